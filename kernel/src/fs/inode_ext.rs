@@ -1,4 +1,5 @@
 use super::*;
+use rcore_fs::vfs::FileType;
 use alloc::str::from_utf8;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
@@ -11,6 +12,8 @@ pub trait INodeExt {
     fn read_as_vec(&self) -> Result<Vec<u8>, usize>;
     /// 读文件到字符串
     fn read_as_string(&self) -> Result<String, usize>;
+    /// 写入文件 文件不存在时创建
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<(), usize>;
 }
 
 impl INodeExt for dyn INode {
@@ -40,6 +43,19 @@ impl INodeExt for dyn INode {
         }
     }
 
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<(), usize> {
+        let file = match path.rfind('/') {
+            Some(i) => {
+                let dir = self.lookup(&path[..i]).map_err(|_| 0usize)?;
+                find_or_create(&*dir, &path[i + 1..])?
+            }
+            None => find_or_create(self, path)?,
+        };
+        file.resize(data.len()).map_err(|_| 0usize)?;
+        file.write_at(0, data).map_err(|_| 0usize)?;
+        Ok(())
+    }
+
     fn ls_as_vec(&self) -> Result<Vec<String>, usize> {
         let mut res = Vec::new();
         let mut id = 0;
@@ -50,3 +66,11 @@ impl INodeExt for dyn INode {
         Ok(res)
     }
 }
+
+// 在目录中查找文件 不存在时创建
+fn find_or_create(dir: &dyn INode, name: &str) -> Result<Arc<dyn INode>, usize> {
+    match dir.find(name) {
+        Ok(f) => Ok(f),
+        Err(_) => dir.create(name, FileType::File, 0o666).map_err(|_| 0usize),
+    }
+}
@@ -5,6 +5,7 @@
 #![feature(untagged_unions)]
 #![feature(abi_x86_interrupt)]
 #![feature(box_syntax)]
+#![feature(asm)]
 #![feature(wake_trait)]
 
 extern crate alloc;
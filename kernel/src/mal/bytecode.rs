@@ -0,0 +1,393 @@
+// jmal 的字节码
+// 每个函数(包括文件里的每个顶层语句)编译成一个 Proto
+// 局部变量在编译时就确定了位置 运行时用 (外层函数的层数, 槽位) 直接访问 不再查找 Env
+use crate::format;
+use crate::mal::reader::read_str;
+use crate::mal::types::MalVal;
+use crate::mal::types::MalVal::List;
+use crate::mal::seq::Seq;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Const(u32),       // 压入常量
+    Nil,              // 压入 nil
+    Local(u16, u16),  // 压入局部变量 (外层函数的层数, 槽位)
+    SetLocal(u16),    // 弹出栈顶 保存到当前函数的槽位
+    Global(u32),      // 在全局环境中查找常量中的符号
+    Def(u32),         // 把栈顶绑定到全局环境中的符号 栈顶保留
+    Pop,              // 丢弃栈顶
+    Jump(u32),        // 跳转
+    JumpIfNot(u32),   // 弹出栈顶 为 nil 或 false 时跳转
+    Closure(u32),     // 用 protos 中的函数和当前的帧生成闭包
+    Call(u16, u32),   // 调用 (参数个数, 调用处在 sites 中的下标)
+    TailCall(u16, u32), // 尾调用 复用当前的调用帧
+    Return,           // 返回栈顶
+    Try(u32, u16, u16), // 进入 try* (catch* 的位置, 异常的槽位, *trace* 的槽位)
+    EndTry,           // 离开 try*
+    Eval,             // 在最外层环境中求值栈顶的代码
+    Interp(u32),      // 编译器不支持的代码 交给解释器在全局环境中求值
+    Vector(u32),      // 把栈顶的 n 个值组成向量
+    Hash(u32),        // 把栈顶的 2n 个值组成 hash map
+    Meta(u32),        // 把常量中的 map 合并到栈顶的值的元数据中
+    Set(u32),         // 把栈顶的 n 个值组成集合
+}
+
+#[derive(Debug)]
+pub struct Proto {
+    pub name: Option<String>,
+    pub arity: u16,   // 固定参数的个数
+    pub rest: bool,   // 是否有 & 参数 它保存在槽位 arity
+    pub nslots: u16,  // 参数和 let* 等局部变量一共需要的槽位
+    pub code: Vec<Op>,
+    pub consts: Vec<MalVal>,
+    pub protos: Vec<Rc<Proto>>,
+    pub sites: Vec<MalVal>, // 调用处 只保留函数名和位置 用于报错和调用栈
+}
+
+// 缓存文件的格式版本 字节码有变化时修改
+const MAGIC: &[u8] = b"JBC5";
+
+// 源代码的哈希 (FNV-1a) 用来判断缓存是否过期
+pub fn source_hash(src: &str) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in src.bytes() {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+// 模块的哈希 由源代码的哈希和 require 的模块的哈希组成
+// 被 require 的模块的哈希又包含了它 require 的模块 所以任何一个间接依赖改变时都会改变
+pub fn module_hash(hash: u64, deps: &[(String, u64)]) -> u64 {
+    let mut h = hash;
+    for (_, d) in deps.iter() {
+        for b in d.to_le_bytes().iter() {
+            h ^= *b as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+    }
+    h
+}
+
+// 把一个文件编译得到的所有顶层 Proto 写成字节 常量中有不能读回的值(比如函数)时返回 None
+// deps 是编译时 require 的模块和它们的哈希 core 是核心库的哈希 展开的宏可能来自它们
+pub fn encode(hash: u64, core: u64, deps: &[(String, u64)], protos: &[Rc<Proto>]) -> Option<Vec<u8>> {
+    let mut w = Writer(Vec::new());
+    w.bytes(MAGIC);
+    w.u64(hash);
+    w.u64(core);
+    w.u32(deps.len() as u32);
+    for (name, h) in deps.iter() {
+        w.str(name);
+        w.u64(*h);
+    }
+    w.u32(protos.len() as u32);
+    for p in protos.iter() {
+        w.proto(p)?;
+    }
+    Some(w.0)
+}
+
+// 读取缓存 格式不对或者源代码 核心库已经改变时返回 None 依赖的模块是否改变由调用者检查
+pub fn decode(hash: u64, core: u64, data: &[u8]) -> Option<(Vec<(String, u64)>, Vec<Rc<Proto>>)> {
+    let mut r = Reader { data, pos: 0 };
+    if r.take(MAGIC.len())? != MAGIC || r.u64()? != hash || r.u64()? != core {
+        return None;
+    }
+    let mut deps = Vec::new();
+    for _ in 0..r.u32()? {
+        let name = r.str()?;
+        deps.push((name, r.u64()?));
+    }
+    let n = r.u32()?;
+    let mut protos = Vec::new();
+    for _ in 0..n {
+        protos.push(r.proto()?);
+    }
+    Some((deps, protos))
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn bytes(&mut self, b: &[u8]) {
+        self.0.extend_from_slice(b);
+    }
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+    fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+    fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+    fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.bytes(s.as_bytes());
+    }
+
+    // 常量用 reader 的语法保存 读回来不相等的值不能缓存
+    fn val(&mut self, v: &MalVal) -> Option<()> {
+        let s = v.pr_str(true);
+        match read_str(s.clone()) {
+            Ok(ref back) if back == v => {
+                self.str(&s);
+                Some(())
+            }
+            _ => None,
+        }
+    }
+
+    fn op(&mut self, op: &Op) {
+        match *op {
+            Op::Const(i) => {
+                self.u8(0);
+                self.u32(i)
+            }
+            Op::Nil => self.u8(1),
+            Op::Local(d, s) => {
+                self.u8(2);
+                self.u16(d);
+                self.u16(s)
+            }
+            Op::SetLocal(s) => {
+                self.u8(3);
+                self.u16(s)
+            }
+            Op::Global(i) => {
+                self.u8(4);
+                self.u32(i)
+            }
+            Op::Def(i) => {
+                self.u8(5);
+                self.u32(i)
+            }
+            Op::Pop => self.u8(6),
+            Op::Jump(i) => {
+                self.u8(7);
+                self.u32(i)
+            }
+            Op::JumpIfNot(i) => {
+                self.u8(8);
+                self.u32(i)
+            }
+            Op::Closure(i) => {
+                self.u8(9);
+                self.u32(i)
+            }
+            Op::Call(n, s) => {
+                self.u8(10);
+                self.u16(n);
+                self.u32(s)
+            }
+            Op::TailCall(n, s) => {
+                self.u8(11);
+                self.u16(n);
+                self.u32(s)
+            }
+            Op::Return => self.u8(12),
+            Op::Try(i, e, t) => {
+                self.u8(13);
+                self.u32(i);
+                self.u16(e);
+                self.u16(t)
+            }
+            Op::EndTry => self.u8(14),
+            Op::Eval => self.u8(15),
+            Op::Interp(i) => {
+                self.u8(16);
+                self.u32(i)
+            }
+            Op::Vector(n) => {
+                self.u8(17);
+                self.u32(n)
+            }
+            Op::Hash(n) => {
+                self.u8(18);
+                self.u32(n)
+            }
+            Op::Meta(i) => {
+                self.u8(19);
+                self.u32(i)
+            }
+            Op::Set(n) => {
+                self.u8(20);
+                self.u32(n)
+            }
+        }
+    }
+
+    fn proto(&mut self, p: &Proto) -> Option<()> {
+        match &p.name {
+            Some(n) => {
+                self.u8(1);
+                self.str(n)
+            }
+            None => self.u8(0),
+        }
+        self.u16(p.arity);
+        self.u8(p.rest as u8);
+        self.u16(p.nslots);
+        self.u32(p.code.len() as u32);
+        for op in p.code.iter() {
+            self.op(op);
+        }
+        self.u32(p.consts.len() as u32);
+        for c in p.consts.iter() {
+            self.val(c)?;
+        }
+        self.u32(p.protos.len() as u32);
+        for sub in p.protos.iter() {
+            self.proto(sub)?;
+        }
+        // 调用处保存函数名和记录位置的元数据
+        self.u32(p.sites.len() as u32);
+        for site in p.sites.iter() {
+            match site {
+                List(l, meta) => {
+                    self.val(&l[0])?;
+                    self.val(meta)?;
+                }
+                _ => return None,
+            }
+        }
+        Some(())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let b = self.data.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(b)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.take(2)?;
+        Some(u16::from_le_bytes([b[0], b[1]]))
+    }
+    fn u32(&mut self) -> Option<u32> {
+        let b = self.take(4)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        let b = self.take(8)?;
+        let mut a = [0u8; 8];
+        a.copy_from_slice(b);
+        Some(u64::from_le_bytes(a))
+    }
+    fn str(&mut self) -> Option<String> {
+        let n = self.u32()? as usize;
+        let b = self.take(n)?;
+        core::str::from_utf8(b).ok().map(|s| s.to_string())
+    }
+    fn val(&mut self) -> Option<MalVal> {
+        read_str(self.str()?).ok()
+    }
+
+    fn op(&mut self) -> Option<Op> {
+        Some(match self.u8()? {
+            0 => Op::Const(self.u32()?),
+            1 => Op::Nil,
+            2 => Op::Local(self.u16()?, self.u16()?),
+            3 => Op::SetLocal(self.u16()?),
+            4 => Op::Global(self.u32()?),
+            5 => Op::Def(self.u32()?),
+            6 => Op::Pop,
+            7 => Op::Jump(self.u32()?),
+            8 => Op::JumpIfNot(self.u32()?),
+            9 => Op::Closure(self.u32()?),
+            10 => Op::Call(self.u16()?, self.u32()?),
+            11 => Op::TailCall(self.u16()?, self.u32()?),
+            12 => Op::Return,
+            13 => Op::Try(self.u32()?, self.u16()?, self.u16()?),
+            14 => Op::EndTry,
+            15 => Op::Eval,
+            16 => Op::Interp(self.u32()?),
+            17 => Op::Vector(self.u32()?),
+            18 => Op::Hash(self.u32()?),
+            19 => Op::Meta(self.u32()?),
+            20 => Op::Set(self.u32()?),
+            _ => return None,
+        })
+    }
+
+    fn proto(&mut self) -> Option<Rc<Proto>> {
+        let name = match self.u8()? {
+            0 => None,
+            _ => Some(self.str()?),
+        };
+        let arity = self.u16()?;
+        let rest = self.u8()? != 0;
+        let nslots = self.u16()?;
+        let mut code = Vec::new();
+        for _ in 0..self.u32()? {
+            code.push(self.op()?);
+        }
+        let mut consts = Vec::new();
+        for _ in 0..self.u32()? {
+            consts.push(self.val()?);
+        }
+        let mut protos = Vec::new();
+        for _ in 0..self.u32()? {
+            protos.push(self.proto()?);
+        }
+        let mut sites = Vec::new();
+        for _ in 0..self.u32()? {
+            let head = self.val()?;
+            let meta = self.val()?;
+            sites.push(List(Seq::list(crate::vec![head]), Rc::new(meta)));
+        }
+        Some(Rc::new(Proto {
+            name,
+            arity,
+            rest,
+            nslots,
+            code,
+            consts,
+            protos,
+            sites,
+        }))
+    }
+}
+
+impl Proto {
+    // 反汇编 调试用
+    pub fn disassemble(&self) -> String {
+        let mut s = format!(
+            "{} arity:{}{} slots:{}\n",
+            self.name.clone().unwrap_or("<lambda>".to_string()),
+            self.arity,
+            if self.rest { "+" } else { "" },
+            self.nslots
+        );
+        for (i, op) in self.code.iter().enumerate() {
+            let note = match *op {
+                Op::Const(c) | Op::Global(c) | Op::Def(c) | Op::Interp(c) | Op::Meta(c) => {
+                    self.consts[c as usize].pr_str(true)
+                }
+                _ => String::new(),
+            };
+            s.push_str(&format!("{:4} {:?} {}\n", i, op, note));
+        }
+        for p in self.protos.iter() {
+            s.push_str(&p.disassemble());
+        }
+        s
+    }
+}
@@ -0,0 +1,322 @@
+// 序列函数库
+// 参数中的集合可以是列表 向量 hash map 集合 惰性序列或 nil
+// hash map 的元素是 [key value] 向量 nil 相当于空列表 返回序列的函数都返回列表
+use crate::format;
+use crate::list;
+use crate::mal::lazy;
+use crate::mal::number;
+use crate::mal::pmap::PMap;
+use crate::mal::seq::Seq;
+use crate::mal::convert::expected;
+use crate::mal::types::MalErr::ErrString;
+use crate::mal::types::MalVal::{Big, Bool, Float, Hash, Int, Keyword, Lazy, List, Nil, Ratio, Set, Str, Sym, Vector};
+use crate::mal::types::{error, MalErr, MalRet, MalVal};
+use crate::vec;
+use crate::vector;
+use alloc::rc::Rc;
+use alloc::vec::{self, Vec};
+use core::cmp::Ordering::{self, Equal, Greater, Less};
+
+// 集合中的所有元素
+pub fn items(coll: &MalVal, name: &str) -> Result<Vec<MalVal>, MalErr> {
+    match coll {
+        List(v, _) | Vector(v, _) => Ok(v.to_vec()),
+        Hash(hm, _) => Ok(hm.iter().map(|(k, v)| vector![k.clone(), v.clone()]).collect()),
+        Set(s, _) => Ok(s.iter().cloned().collect()),
+        Lazy(_) => lazy::to_vec(coll),
+        Nil => Ok(Vec::new()),
+        _ => Err(expected(name, "collection", coll)),
+    }
+}
+
+// 依次取出集合的元素 惰性序列每次只求出一个 提前停止时不会求出后面的元素
+pub enum Elems {
+    Items(vec::IntoIter<MalVal>),
+    Lazy(MalVal),
+}
+
+impl Iterator for Elems {
+    type Item = Result<MalVal, MalErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Elems::Items(it) => it.next().map(Ok),
+            Elems::Lazy(cur) => match lazy::uncons(cur) {
+                Ok(Some((x, rest))) => {
+                    *cur = rest;
+                    Some(Ok(x))
+                }
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            },
+        }
+    }
+}
+
+pub fn elems(coll: &MalVal, name: &str) -> Result<Elems, MalErr> {
+    match coll {
+        Lazy(_) => Ok(Elems::Lazy(coll.clone())),
+        _ => Ok(Elems::Items(items(coll, name)?.into_iter())),
+    }
+}
+
+fn truthy(v: &MalVal) -> bool {
+    match v {
+        Nil | Bool(false) => false,
+        _ => true,
+    }
+}
+
+// (filter pred coll) 惰性序列上的 filter 也是惰性的
+pub fn filter(pred: &MalVal, coll: &MalVal) -> MalRet {
+    if let Lazy(_) = coll {
+        return Ok(lazy::filter(pred, coll));
+    }
+    let mut res = Vec::new();
+    for x in items(coll, "filter")? {
+        if truthy(&pred.apply(vec![x.clone()])?) {
+            res.push(x);
+        }
+    }
+    Ok(list!(res))
+}
+
+// (reduce f coll) (reduce f init coll) 没有初始值时用第一个元素 空集合时返回 (f)
+pub fn reduce(f: &MalVal, x: MalVal, coll: Option<MalVal>) -> MalRet {
+    let (init, xs) = match coll {
+        None => {
+            let mut xs = items(&x, "reduce")?.into_iter();
+            match xs.next() {
+                Some(x) => (x, xs),
+                None => return f.apply(vec![]),
+            }
+        }
+        Some(coll) => (x, items(&coll, "reduce")?.into_iter()),
+    };
+    xs.fold(Ok(init), |acc, x| f.apply(vec![acc?, x]))
+}
+
+pub fn is_number(v: &MalVal) -> bool {
+    match v {
+        Int(_) | Big(_) | Ratio(_) | Float(_) => true,
+        _ => false,
+    }
+}
+
+// (take n coll) 前 n 个元素 惰性序列只求出前 n 个 n 是负数时相当于 0
+pub fn take(n: i64, coll: &MalVal) -> MalRet {
+    let n = n.max(0) as usize;
+    match coll {
+        List(v, _) | Vector(v, _) => Ok(list!(v.iter().take(n).cloned().collect())),
+        Lazy(_) => {
+            let mut res = Vec::new();
+            let mut cur = coll.clone();
+            while res.len() < n {
+                match lazy::uncons(&cur)? {
+                    Some((x, rest)) => {
+                        res.push(x);
+                        cur = rest;
+                    }
+                    None => break,
+                }
+            }
+            Ok(list!(res))
+        }
+        _ => Ok(list!(items(coll, "take")?.into_iter().take(n).collect())),
+    }
+}
+
+// (drop n coll) 去掉前 n 个元素 列表和向量与原来的集合共享剩下的部分
+pub fn drop(n: i64, coll: &MalVal) -> MalRet {
+    let n = n.max(0) as usize;
+    match coll {
+        List(v, _) | Vector(v, _) => Ok(List(v.skip(n), Rc::new(Nil))),
+        Lazy(_) => lazy::drop(n, coll),
+        _ => Ok(list!(items(coll, "drop")?.into_iter().skip(n).collect())),
+    }
+}
+
+pub fn reverse(coll: &MalVal) -> MalRet {
+    Ok(list!(items(coll, "reverse")?.into_iter().rev().collect()))
+}
+
+pub fn last(coll: &MalVal) -> MalRet {
+    match coll {
+        List(v, _) | Vector(v, _) => Ok(v.last().cloned().unwrap_or(Nil)),
+        _ => Ok(items(coll, "last")?.pop().unwrap_or(Nil)),
+    }
+}
+
+// (some pred coll) 第一个使 pred 为真的结果 没有时返回 nil
+pub fn some(pred: &MalVal, coll: &MalVal) -> MalRet {
+    for x in elems(coll, "some")? {
+        let r = pred.apply(vec![x?])?;
+        if truthy(&r) {
+            return Ok(r);
+        }
+    }
+    Ok(Nil)
+}
+
+pub fn every_q(pred: &MalVal, coll: &MalVal) -> MalRet {
+    for x in elems(coll, "every?")? {
+        if !truthy(&pred.apply(vec![x?])?) {
+            return Ok(Bool(false));
+        }
+    }
+    Ok(Bool(true))
+}
+
+// 默认的比较 nil 最小 数字 字符串 符号 关键字 布尔值各自比较 列表和向量按元素依次比较
+pub fn compare(x: &MalVal, y: &MalVal) -> Result<Ordering, MalErr> {
+    match (x, y) {
+        (Nil, Nil) => Ok(Equal),
+        (Nil, _) => Ok(Less),
+        (_, Nil) => Ok(Greater),
+        (x, y) if is_number(x) && is_number(y) => Ok(number::cmp(x, y)?.unwrap_or(Equal)),
+        (Str(a), Str(b)) | (Keyword(a), Keyword(b)) => Ok(a.cmp(b)),
+        (Sym(a), Sym(b)) => Ok(a.cmp(b)),
+        (Bool(a), Bool(b)) => Ok(a.cmp(b)),
+        (List(a, _), List(b, _)) | (Vector(a, _), Vector(b, _)) | (List(a, _), Vector(b, _)) | (Vector(a, _), List(b, _)) => {
+            for (p, q) in a.iter().zip(b.iter()) {
+                match compare(p, q)? {
+                    Equal => (),
+                    o => return Ok(o),
+                }
+            }
+            Ok(a.len().cmp(&b.len()))
+        }
+        _ => Err(ErrString(format!("cannot compare {} and {}", x.pr_str(true), y.pr_str(true)))),
+    }
+}
+
+// 用户给的比较函数可以返回整数 (负数 0 正数) 也可以像 < 一样返回真假
+fn compare_with(f: &MalVal, x: &MalVal, y: &MalVal) -> Result<Ordering, MalErr> {
+    match f.apply(vec![x.clone(), y.clone()])? {
+        Int(n) => Ok(n.cmp(&0)),
+        r if truthy(&r) => Ok(Less),
+        _ if truthy(&f.apply(vec![y.clone(), x.clone()])?) => Ok(Greater),
+        _ => Ok(Equal),
+    }
+}
+
+// 稳定排序 比较时出错就返回第一个错误
+fn sort_items(xs: &mut Vec<(MalVal, MalVal)>, cmp: Option<&MalVal>) -> Result<(), MalErr> {
+    let mut err = None;
+    xs.sort_by(|(p, _), (q, _)| {
+        if err.is_some() {
+            return Equal;
+        }
+        let r = match cmp {
+            Some(f) => compare_with(f, p, q),
+            None => compare(p, q),
+        };
+        r.unwrap_or_else(|e| {
+            err = Some(e);
+            Equal
+        })
+    });
+    match err {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// (sort coll) (sort cmp coll)
+pub fn sort(x: &MalVal, coll: Option<&MalVal>) -> MalRet {
+    let (cmp, coll) = match coll {
+        None => (None, x),
+        Some(coll) => (Some(x), coll),
+    };
+    let mut xs: Vec<(MalVal, MalVal)> = items(coll, "sort")?.into_iter().map(|x| (x, Nil)).collect();
+    sort_items(&mut xs, cmp)?;
+    Ok(list!(xs.into_iter().map(|(x, _)| x).collect()))
+}
+
+// (sort-by keyfn coll) (sort-by keyfn cmp coll) 每个元素只计算一次 keyfn
+pub fn sort_by(keyfn: &MalVal, x: &MalVal, coll: Option<&MalVal>) -> MalRet {
+    let (cmp, coll) = match coll {
+        None => (None, x),
+        Some(coll) => (Some(x), coll),
+    };
+    let mut xs = Vec::new();
+    for x in items(coll, "sort-by")? {
+        xs.push((keyfn.apply(vec![x.clone()])?, x));
+    }
+    sort_items(&mut xs, cmp)?;
+    Ok(list!(xs.into_iter().map(|(_, x)| x).collect()))
+}
+
+// (zipmap keys vals) 以较短的一个为准 另一个可以是无限的惰性序列
+pub fn zipmap(ks: &MalVal, vs: &MalVal) -> MalRet {
+    let kvs = elems(ks, "zipmap")?
+        .zip(elems(vs, "zipmap")?)
+        .map(|(k, v)| Ok((k?, v?)))
+        .collect::<Result<PMap, MalErr>>()?;
+    Ok(Hash(kvs, Rc::new(Nil)))
+}
+
+// (group-by f coll) 按 (f x) 分组 每组是保持原来顺序的向量
+pub fn group_by(f: &MalVal, coll: &MalVal) -> MalRet {
+    let mut groups = PMap::new();
+    for x in items(coll, "group-by")? {
+        let k = f.apply(vec![x.clone()])?;
+        let group = match groups.get(&k) {
+            Some(Vector(v, _)) => v.push(x),
+            _ => Seq::vector(vec![x]),
+        };
+        groups = groups.insert(k, Vector(group, Rc::new(Nil)));
+    }
+    Ok(Hash(groups, Rc::new(Nil)))
+}
+
+// (frequencies coll) 每个元素出现的次数
+pub fn frequencies(coll: &MalVal) -> MalRet {
+    let mut counts = PMap::new();
+    for x in items(coll, "frequencies")? {
+        let n = match counts.get(&x) {
+            Some(Int(n)) => n + 1,
+            _ => 1,
+        };
+        counts = counts.insert(x, Int(n));
+    }
+    Ok(Hash(counts, Rc::new(Nil)))
+}
+
+// (conj coll x ...) 列表在开头添加 向量在末尾添加
+// hash map 添加 [key value] 或者合并另一个 map
+pub fn conj(coll: &MalVal, xs: &[MalVal]) -> MalRet {
+    match coll {
+        List(v, _) => Ok(List(
+            xs.iter().fold(v.clone(), |acc, x| acc.cons(x.clone())),
+            Rc::new(Nil),
+        )),
+        Vector(v, _) => Ok(Vector(
+            xs.iter().fold(v.clone(), |acc, x| acc.push(x.clone())),
+            Rc::new(Nil),
+        )),
+        Set(s, _) => Ok(Set(
+            xs.iter().fold(s.clone(), |acc, x| acc.insert(x.clone())),
+            Rc::new(Nil),
+        )),
+        Hash(hm, _) => {
+            let mut hm = hm.clone();
+            for x in xs.iter() {
+                hm = match x {
+                    Vector(kv, _) if kv.len() == 2 => hm.insert(kv[0].clone(), kv[1].clone()),
+                    Hash(other, _) => other.iter().fold(hm, |m, (k, v)| m.insert(k.clone(), v.clone())),
+                    Nil => hm,
+                    _ => return error("conj on a map requires [key value] vectors or maps"),
+                };
+            }
+            Ok(Hash(hm, Rc::new(Nil)))
+        }
+        Nil => Ok(list!(xs.iter().rev().cloned().collect())),
+        _ => Err(expected("conj", "list, vector, map or set", coll)),
+    }
+}
+
+// (into to from) 把 from 的元素逐个 conj 到 to 中
+pub fn into(to: &MalVal, from: &MalVal) -> MalRet {
+    conj(to, &items(from, "into")?)
+}
@@ -0,0 +1,435 @@
+// 把 MalVal 形式的代码编译成字节码
+// 宏在编译时展开 局部变量(参数 let* catch*)编译成槽位 其余的符号在运行时到全局环境中查找
+// 编译器不支持的顶层语句会整个交给解释器执行
+use crate::mal::bytecode::{Op, Proto};
+use crate::mal::env::Env;
+use crate::mal::types::MalVal::{Hash, List, Nil, Set, Str, Sym, Vector};
+use crate::mal::types::{MalErr, MalVal};
+use crate::mal::seq::Seq;
+use crate::mal::symbol;
+use crate::mal::{def_meta, is_macro_call, is_sym, keep_location, macroexpand, quasiquote};
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+// 编译失败的原因
+enum Fail {
+    Unsupported,  // 编译器不支持 交给解释器
+    Err(MalErr),  // 宏展开时出错
+}
+
+impl From<MalErr> for Fail {
+    fn from(e: MalErr) -> Fail {
+        Fail::Err(e)
+    }
+}
+
+type CResult = Result<(), Fail>;
+
+// 正在编译的函数
+struct FnState {
+    name: Option<String>,
+    arity: u16,
+    rest: bool,
+    nslots: u16,
+    code: Vec<Op>,
+    consts: Vec<MalVal>,
+    protos: Vec<Rc<Proto>>,
+    sites: Vec<MalVal>,
+    scope: Vec<(String, u16)>, // 当前可见的局部变量 后面的遮盖前面的
+}
+
+impl FnState {
+    fn new(name: Option<String>) -> FnState {
+        FnState {
+            name,
+            arity: 0,
+            rest: false,
+            nslots: 0,
+            code: Vec::new(),
+            consts: Vec::new(),
+            protos: Vec::new(),
+            sites: Vec::new(),
+            scope: Vec::new(),
+        }
+    }
+
+    fn finish(self) -> Rc<Proto> {
+        Rc::new(Proto {
+            name: self.name,
+            arity: self.arity,
+            rest: self.rest,
+            nslots: self.nslots,
+            code: self.code,
+            consts: self.consts,
+            protos: self.protos,
+            sites: self.sites,
+        })
+    }
+}
+
+struct Compiler<'a> {
+    env: &'a Env,
+    fns: Vec<FnState>,
+}
+
+fn is_str(v: &MalVal) -> bool {
+    match v {
+        Str(_) => true,
+        _ => false,
+    }
+}
+
+// 编译一个顶层语句 编译器不支持时生成交给解释器执行的代码
+pub fn compile_top(form: &MalVal, env: &Env) -> Result<Rc<Proto>, MalErr> {
+    let mut c = Compiler {
+        env,
+        fns: crate::vec![FnState::new(None)],
+    };
+    match c.compile(form, true) {
+        Ok(()) => {
+            c.emit(Op::Return);
+            Ok(c.fns.pop().unwrap().finish())
+        }
+        Err(Fail::Err(e)) => Err(e),
+        Err(Fail::Unsupported) => {
+            let mut f = FnState::new(None);
+            f.consts.push(form.clone());
+            f.code.push(Op::Interp(0));
+            f.code.push(Op::Return);
+            Ok(f.finish())
+        }
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn cur(&mut self) -> &mut FnState {
+        self.fns.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let f = self.cur();
+        f.code.push(op);
+        f.code.len() - 1
+    }
+
+    fn here(&mut self) -> u32 {
+        self.cur().code.len() as u32
+    }
+
+    fn patch(&mut self, at: usize, to: u32) {
+        let op = &mut self.cur().code[at];
+        *op = match *op {
+            Op::Jump(_) => Op::Jump(to),
+            Op::JumpIfNot(_) => Op::JumpIfNot(to),
+            Op::Try(_, e, t) => Op::Try(to, e, t),
+            o => o,
+        };
+    }
+
+    fn constant(&mut self, v: MalVal) -> u32 {
+        let f = self.cur();
+        f.consts.push(v);
+        (f.consts.len() - 1) as u32
+    }
+
+    fn site(&mut self, form: &MalVal) -> u32 {
+        let site = match form {
+            List(l, meta) => List(Seq::list(crate::vec![l[0].clone()]), meta.clone()),
+            _ => form.clone(),
+        };
+        let f = self.cur();
+        f.sites.push(site);
+        (f.sites.len() - 1) as u32
+    }
+
+    // 分配一个新的局部变量
+    fn declare(&mut self, name: &str) -> u16 {
+        let f = self.cur();
+        let slot = f.nslots;
+        f.nslots += 1;
+        f.scope.push((name.to_string(), slot));
+        slot
+    }
+
+    // 查找局部变量 返回 (外层函数的层数, 槽位)
+    fn resolve(&self, name: &str) -> Option<(u16, u16)> {
+        for (depth, f) in self.fns.iter().rev().enumerate() {
+            if let Some((_, slot)) = f.scope.iter().rev().find(|(n, _)| n == name) {
+                return Some((depth as u16, *slot));
+            }
+        }
+        None
+    }
+
+    // 是否在顶层 顶层的 def! 等语句才能在编译后执行
+    fn at_top(&self) -> bool {
+        self.fns.len() == 1 && self.fns[0].scope.is_empty()
+    }
+
+    fn compile(&mut self, form: &MalVal, tail: bool) -> CResult {
+        match form {
+            Sym(s) => {
+                match self.resolve(s) {
+                    Some((d, slot)) => self.emit(Op::Local(d, slot)),
+                    None => {
+                        let c = self.constant(form.clone());
+                        self.emit(Op::Global(c))
+                    }
+                };
+                Ok(())
+            }
+            List(l, _) if l.len() > 0 => self.compile_list(form, &l.to_vec(), tail),
+            Vector(v, _) => {
+                for e in v.iter() {
+                    self.compile(e, false)?;
+                }
+                self.emit(Op::Vector(v.len() as u32));
+                Ok(())
+            }
+            Hash(hm, _) => {
+                for (k, v) in hm.iter() {
+                    self.compile(k, false)?;
+                    self.compile(v, false)?;
+                }
+                self.emit(Op::Hash(hm.len() as u32));
+                Ok(())
+            }
+            Set(s, _) => {
+                for e in s.iter() {
+                    self.compile(e, false)?;
+                }
+                self.emit(Op::Set(s.len() as u32));
+                Ok(())
+            }
+            Nil => {
+                self.emit(Op::Nil);
+                Ok(())
+            }
+            _ => {
+                let c = self.constant(form.clone());
+                self.emit(Op::Const(c));
+                Ok(())
+            }
+        }
+    }
+
+    fn compile_list(&mut self, form: &MalVal, l: &[MalVal], tail: bool) -> CResult {
+        // 局部变量遮盖了同名的宏和特殊形式
+        let head = match &l[0] {
+            Sym(s) if self.resolve(s).is_none() => s.as_str(),
+            _ => "",
+        };
+        if head != "" && is_macro_call(form, self.env).is_some() {
+            let expanded = match macroexpand(form.clone(), self.env) {
+                (_, Ok(e)) => e,
+                (_, Err(e)) => return Err(Fail::Err(e)),
+            };
+            return self.compile(&keep_location(expanded, form), tail);
+        }
+        match head {
+            // (def! name "doc" value) 带文档的 def!
+            "def!" if (l.len() == 3 || l.len() == 4 && is_str(&l[2])) && self.at_top() => match &l[1] {
+                Sym(name) => {
+                    let doc = if l.len() == 4 { Some(&l[2]) } else { None };
+                    let value = &l[l.len() - 1];
+                    self.compile_named(value, name)?;
+                    if let Some(m) = def_meta(&l[1], doc, value) {
+                        let c = self.constant(m);
+                        self.emit(Op::Meta(c));
+                    }
+                    let c = self.constant(l[1].clone());
+                    self.emit(Op::Def(c));
+                    Ok(())
+                }
+                _ => Err(Fail::Unsupported),
+            },
+            "let*" if l.len() == 3 => self.compile_let(&l[1], &l[2], tail),
+            "lambda" if l.len() == 3 => self.compile_lambda(&l[1], &l[2], None),
+            "if" if l.len() == 3 || l.len() == 4 => {
+                self.compile(&l[1], false)?;
+                let to_else = self.emit(Op::JumpIfNot(0));
+                self.compile(&l[2], tail)?;
+                let to_end = self.emit(Op::Jump(0));
+                let else_pc = self.here();
+                self.patch(to_else, else_pc);
+                match l.get(3) {
+                    Some(e) => self.compile(e, tail)?,
+                    None => {
+                        self.emit(Op::Nil);
+                    }
+                }
+                let end_pc = self.here();
+                self.patch(to_end, end_pc);
+                Ok(())
+            }
+            "do" => {
+                if l.len() == 1 {
+                    self.emit(Op::Nil);
+                }
+                for (i, e) in l[1..].iter().enumerate() {
+                    let last = i == l.len() - 2;
+                    self.compile(e, tail && last)?;
+                    if !last {
+                        self.emit(Op::Pop);
+                    }
+                }
+                Ok(())
+            }
+            "quote" if l.len() == 2 => {
+                let c = self.constant(l[1].clone());
+                self.emit(Op::Const(c));
+                Ok(())
+            }
+            "quasiquote" if l.len() == 2 => self.compile(&quasiquote(&l[1]), tail),
+            "eval" if l.len() == 2 => {
+                self.compile(&l[1], false)?;
+                self.emit(Op::Eval);
+                Ok(())
+            }
+            "try*" if l.len() == 2 => self.compile(&l[1], tail),
+            "try*" if l.len() == 3 => self.compile_try(&l[1], &l[2]),
+            // 这些语句需要访问当前的环境 只在顶层交给解释器执行
+            "def!" | "def-dynamic" | "defmacro!" | "macroexpand" | "ns" | "require" | "import" | "reload" => {
+                if self.at_top() {
+                    let c = self.constant(form.clone());
+                    self.emit(Op::Interp(c));
+                    Ok(())
+                } else {
+                    Err(Fail::Unsupported)
+                }
+            }
+            // 解构的 let* lambda match 和 binding 交给解释器
+            "let*" | "lambda" | "if" | "quote" | "quasiquote" | "eval" | "try*" | "match" | "binding" => {
+                Err(Fail::Unsupported)
+            }
+            _ => {
+                for e in l.iter() {
+                    self.compile(e, false)?;
+                }
+                let site = self.site(form);
+                let argc = (l.len() - 1) as u16;
+                self.emit(if tail {
+                    Op::TailCall(argc, site)
+                } else {
+                    Op::Call(argc, site)
+                });
+                Ok(())
+            }
+        }
+    }
+
+    // def! 的值是 lambda 时记录函数名
+    fn compile_named(&mut self, value: &MalVal, name: &str) -> CResult {
+        match value {
+            List(l, _) if l.len() == 3 && is_sym(&l[0], symbol::LAMBDA) => {
+                self.compile_lambda(&l[1], &l[2], Some(name.to_string()))
+            }
+            _ => self.compile(value, false),
+        }
+    }
+
+    fn compile_let(&mut self, binds: &MalVal, body: &MalVal, tail: bool) -> CResult {
+        let binds = match binds {
+            List(b, _) | Vector(b, _) if b.len() % 2 == 0 => b.to_vec(),
+            _ => return Err(Fail::Unsupported),
+        };
+        let scope_len = self.cur().scope.len();
+        for kv in binds.chunks(2) {
+            let name = match &kv[0] {
+                Sym(s) => s.to_string(),
+                _ => return Err(Fail::Unsupported),
+            };
+            // lambda 可以引用自己 其他的值里同名的符号指向外面的绑定
+            let slot = match &kv[1] {
+                List(l, _) if l.len() > 0 && is_sym(&l[0], symbol::LAMBDA) => {
+                    let slot = self.declare(&name);
+                    self.compile_named(&kv[1], &name)?;
+                    slot
+                }
+                v => {
+                    self.compile(v, false)?;
+                    self.declare(&name)
+                }
+            };
+            self.emit(Op::SetLocal(slot));
+        }
+        self.compile(body, tail)?;
+        self.cur().scope.truncate(scope_len);
+        Ok(())
+    }
+
+    fn compile_lambda(&mut self, params: &MalVal, body: &MalVal, name: Option<String>) -> CResult {
+        let params = match params {
+            List(p, _) | Vector(p, _) => p.clone(),
+            _ => return Err(Fail::Unsupported),
+        };
+        self.fns.push(FnState::new(name));
+        let mut i = 0;
+        while i < params.len() {
+            match &params[i] {
+                Sym(s) if s.id() == symbol::AMP => match params.get(i + 1) {
+                    Some(Sym(r)) if i + 2 == params.len() => {
+                        self.declare(r);
+                        self.cur().rest = true;
+                        break;
+                    }
+                    _ => {
+                        self.fns.pop();
+                        return Err(Fail::Unsupported);
+                    }
+                },
+                // &optional 等参数交给解释器
+                Sym(s) if !s.starts_with('&') => {
+                    self.declare(s);
+                    self.cur().arity += 1;
+                }
+                _ => {
+                    self.fns.pop();
+                    return Err(Fail::Unsupported);
+                }
+            }
+            i += 1;
+        }
+        if let Err(e) = self.compile(body, true) {
+            self.fns.pop();
+            return Err(e);
+        }
+        self.emit(Op::Return);
+        let proto = self.fns.pop().unwrap().finish();
+        let f = self.cur();
+        f.protos.push(proto);
+        let idx = (f.protos.len() - 1) as u32;
+        self.emit(Op::Closure(idx));
+        Ok(())
+    }
+
+    // (try* body (catch* e handler))
+    fn compile_try(&mut self, body: &MalVal, catch: &MalVal) -> CResult {
+        let (name, handler) = match catch {
+            List(c, _) if c.len() == 3 && is_sym(&c[0], symbol::CATCH) => match &c[1] {
+                Sym(s) => (s.to_string(), c[2].clone()),
+                _ => return Err(Fail::Unsupported),
+            },
+            _ => return Err(Fail::Unsupported),
+        };
+        let scope_len = self.cur().scope.len();
+        let exc = self.declare(&name);
+        let trace = self.declare("*trace*");
+        self.cur().scope.truncate(scope_len);
+        let enter = self.emit(Op::Try(0, exc, trace));
+        self.compile(body, false)?;
+        self.emit(Op::EndTry);
+        let to_end = self.emit(Op::Jump(0));
+        let handler_pc = self.here();
+        self.patch(enter, handler_pc);
+        let f = self.cur();
+        f.scope.push((name, exc));
+        f.scope.push(("*trace*".to_string(), trace));
+        self.compile(&handler, false)?;
+        self.cur().scope.truncate(scope_len);
+        let end_pc = self.here();
+        self.patch(to_end, end_pc);
+        Ok(())
+    }
+}
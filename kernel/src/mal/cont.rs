@@ -0,0 +1,48 @@
+// call/cc 和 dynamic-wind
+// 这里的 continuation 只能向外跳出 (escaping continuation)
+// 调用 continuation 时返回 MalErr::ErrCont 沿着调用链一层层返回到对应的 call/cc
+// 途中 dynamic-wind 的 after 都会被执行 try* 也不会拦截它
+// 对应的 call/cc 返回以后再调用这个 continuation 会报错
+use crate::mal::types::MalErr::ErrCont;
+use crate::mal::types::MalVal::{Cont, Nil};
+use crate::mal::types::{error, MalArgs, MalRet, MalVal};
+use crate::mal::global::Global;
+use crate::vec;
+use alloc::vec::Vec;
+
+// 已经分配的最大编号 和 还没有返回的 call/cc 的编号
+static LIVE: Global<(usize, Vec<usize>)> = Global::new((0, Vec::new()));
+
+// (call/cc f) 用当前的 continuation 调用 f
+pub fn call_cc(f: &MalVal) -> MalRet {
+    let id = {
+        let mut live = LIVE.borrow_mut();
+        live.0 += 1;
+        let id = live.0;
+        live.1.push(id);
+        id
+    };
+    let ret = f.apply(vec![Cont(id)]);
+    LIVE.borrow_mut().1.retain(|i| *i != id);
+    match ret {
+        Err(ErrCont(i, v)) if i == id => Ok(v),
+        r => r,
+    }
+}
+
+// 调用编号为 id 的 continuation 跳回到它的 call/cc 并且让 call/cc 返回 a[0]
+pub fn throw(id: usize, a: MalArgs) -> MalRet {
+    if LIVE.borrow().1.contains(&id) {
+        Err(ErrCont(id, a.get(0).cloned().unwrap_or(Nil)))
+    } else {
+        error("continuation called outside of its call/cc, re-entry is not supported")
+    }
+}
+
+// (dynamic-wind before thunk after) 不论 thunk 是正常返回 出错 还是被 continuation 跳出 都会执行 after
+pub fn dynamic_wind(before: &MalVal, thunk: &MalVal, after: &MalVal) -> MalRet {
+    before.apply(vec![])?;
+    let ret = thunk.apply(vec![]);
+    after.apply(vec![])?;
+    ret
+}
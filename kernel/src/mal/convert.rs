@@ -0,0 +1,290 @@
+// 内置函数的参数转换
+// builtin! 声明内置函数的名字和参数类型 调用时先检查参数个数 再把参数转换成声明的类型
+// 个数不对是 :arity 错误 类型不对是 :type 错误 例如 nth: expected int, got string
+// 内置函数只通过声明的参数取值 不会因为参数不对而越界访问
+use crate::format;
+use crate::mal::exception;
+use crate::mal::pmap::{PMap, PSet};
+use crate::mal::types::MalVal::{
+    Atom, Big, Bool, Closure, Cont, Error, Float, Func, Hash, Int, Keyword, Lazy, List, MalFunc, Native, Nil,
+    Ratio, Set, Str, Sym, Vector,
+};
+use crate::mal::types::{arity_error, MalErr, MalRet, MalVal};
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+// 错误信息中值的类型名
+pub fn type_name(v: &MalVal) -> &'static str {
+    match v {
+        Nil => "nil",
+        Bool(_) => "boolean",
+        Int(_) => "int",
+        Float(_) => "float",
+        Big(_) => "bigint",
+        Ratio(_) => "ratio",
+        Str(_) => "string",
+        Sym(_) => "symbol",
+        Keyword(_) => "keyword",
+        List(..) => "list",
+        Vector(..) => "vector",
+        Hash(..) => "map",
+        Set(..) => "set",
+        Lazy(_) => "lazy-seq",
+        MalFunc { is_macro: true, .. } => "macro",
+        Func(..) | Native(..) | MalFunc { .. } | Closure(..) => "function",
+        Atom(_) => "atom",
+        Cont(_) => "continuation",
+        Error(_) => "error",
+    }
+}
+
+// 参数类型不对的错误
+pub fn expected(name: &str, what: &str, got: &MalVal) -> MalErr {
+    exception::raise("type", format!("{}: expected {}, got {}", name, what, type_name(got)))
+}
+
+// 从参数转换成 Rust 的值
+pub trait FromMal: Sized {
+    // 错误信息中期望的类型
+    const EXPECTED: &'static str;
+    // 可以省略的参数 只能放在最后
+    const OPTIONAL: bool = false;
+
+    // 类型不对时还回原来的值 用于生成错误信息
+    fn from_mal(v: MalVal) -> Result<Self, MalVal>;
+
+    fn from_arg(name: &str, v: Option<MalVal>) -> Result<Self, MalErr> {
+        match v {
+            Some(v) => Self::from_mal(v).map_err(|v| expected(name, Self::EXPECTED, &v)),
+            // 已经检查过参数个数 不会走到这里
+            None => Err(exception::raise("arity", format!("{}: missing argument", name))),
+        }
+    }
+}
+
+// 函数的返回值转换成 MalRet
+pub trait IntoMal {
+    fn into_mal(self) -> MalRet;
+}
+
+// 任意的数字
+pub struct Num(pub MalVal);
+
+// 可以调用的值 函数 continuation 和编译过的函数
+pub struct Callable(pub MalVal);
+
+impl FromMal for MalVal {
+    const EXPECTED: &'static str = "any value";
+    fn from_mal(v: MalVal) -> Result<Self, MalVal> {
+        Ok(v)
+    }
+}
+
+impl<T: FromMal> FromMal for Option<T> {
+    const EXPECTED: &'static str = T::EXPECTED;
+    const OPTIONAL: bool = true;
+    fn from_mal(v: MalVal) -> Result<Self, MalVal> {
+        T::from_mal(v).map(Some)
+    }
+    fn from_arg(name: &str, v: Option<MalVal>) -> Result<Self, MalErr> {
+        match v {
+            Some(v) => T::from_arg(name, Some(v)).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl FromMal for i64 {
+    const EXPECTED: &'static str = "int";
+    fn from_mal(v: MalVal) -> Result<Self, MalVal> {
+        match v {
+            Int(i) => Ok(i),
+            v => Err(v),
+        }
+    }
+}
+
+impl FromMal for usize {
+    const EXPECTED: &'static str = "non-negative int";
+    fn from_mal(v: MalVal) -> Result<Self, MalVal> {
+        match v {
+            Int(i) if i >= 0 => Ok(i as usize),
+            v => Err(v),
+        }
+    }
+    // 负数的类型也是 int 错误信息中给出它的值
+    fn from_arg(name: &str, v: Option<MalVal>) -> Result<Self, MalErr> {
+        match v {
+            Some(Int(i)) if i < 0 => Err(exception::raise(
+                "type",
+                format!("{}: expected {}, got {}", name, Self::EXPECTED, i),
+            )),
+            v => i64::from_arg(name, v).map(|i| i as usize),
+        }
+    }
+}
+
+impl FromMal for String {
+    const EXPECTED: &'static str = "string";
+    fn from_mal(v: MalVal) -> Result<Self, MalVal> {
+        match v {
+            Str(s) => Ok(s),
+            v => Err(v),
+        }
+    }
+}
+
+impl FromMal for PMap {
+    const EXPECTED: &'static str = "map";
+    fn from_mal(v: MalVal) -> Result<Self, MalVal> {
+        match v {
+            Hash(hm, _) => Ok(hm),
+            v => Err(v),
+        }
+    }
+}
+
+impl FromMal for PSet {
+    const EXPECTED: &'static str = "set";
+    fn from_mal(v: MalVal) -> Result<Self, MalVal> {
+        match v {
+            Set(s, _) => Ok(s),
+            v => Err(v),
+        }
+    }
+}
+
+impl FromMal for Rc<RefCell<MalVal>> {
+    const EXPECTED: &'static str = "atom";
+    fn from_mal(v: MalVal) -> Result<Self, MalVal> {
+        match v {
+            Atom(a) => Ok(a),
+            v => Err(v),
+        }
+    }
+}
+
+impl FromMal for Num {
+    const EXPECTED: &'static str = "number";
+    fn from_mal(v: MalVal) -> Result<Self, MalVal> {
+        match v {
+            Int(_) | Big(_) | Ratio(_) | Float(_) => Ok(Num(v)),
+            v => Err(v),
+        }
+    }
+}
+
+impl FromMal for Callable {
+    const EXPECTED: &'static str = "function";
+    fn from_mal(v: MalVal) -> Result<Self, MalVal> {
+        match v {
+            Func(..) | Native(..) | MalFunc { .. } | Cont(_) | Closure(..) => Ok(Callable(v)),
+            v => Err(v),
+        }
+    }
+}
+
+impl IntoMal for MalVal {
+    fn into_mal(self) -> MalRet {
+        Ok(self)
+    }
+}
+
+impl IntoMal for bool {
+    fn into_mal(self) -> MalRet {
+        Ok(Bool(self))
+    }
+}
+
+impl IntoMal for i64 {
+    fn into_mal(self) -> MalRet {
+        Ok(Int(self))
+    }
+}
+
+impl IntoMal for String {
+    fn into_mal(self) -> MalRet {
+        Ok(Str(self))
+    }
+}
+
+impl IntoMal for () {
+    fn into_mal(self) -> MalRet {
+        Ok(Nil)
+    }
+}
+
+impl<T: IntoMal> IntoMal for Result<T, MalErr> {
+    fn into_mal(self) -> MalRet {
+        self.and_then(IntoMal::into_mal)
+    }
+}
+
+// 参数个数的范围由每个参数是否可以省略和有没有 & 参数决定
+pub fn check_arity(name: &str, optional: &[bool], rest: bool, got: usize) -> Result<(), MalErr> {
+    let min = optional.iter().filter(|o| !**o).count();
+    let max = match rest {
+        true => None,
+        false => Some(optional.len()),
+    };
+    if got < min || max.map_or(false, |max| got > max) {
+        return Err(arity_error(name, min, max, got));
+    }
+    Ok(())
+}
+
+// & 后面剩下的参数 每个都转换成 T
+pub fn rest<T: FromMal>(name: &str, args: impl Iterator<Item = MalVal>) -> Result<Vec<T>, MalErr> {
+    args.map(|v| T::from_arg(name, Some(v))).collect()
+}
+
+// 声明一个内置函数 得到 (名字, 函数) 放进 core::ns() 中
+// builtin!("nth", |coll: MalVal, i: i64| nth(coll, i))
+// builtin!("subs", |s: String, start: usize, end: Option<usize>| ...) Option 参数可以省略
+// builtin!("+", |& xs: Vec<Num>| ...) & 后面的参数接收剩下的所有参数
+// 函数体可以返回 MalVal bool i64 String () 或者它们的 Result
+#[macro_export]
+macro_rules! builtin {
+    ($name:literal, || $body:expr) => {
+        $crate::builtin!($name, | | $body)
+    };
+    ($name:literal, |$($p:ident : $t:ty),*| $body:expr) => {
+        (
+            $name,
+            $crate::mal::types::func(|a: $crate::mal::types::MalArgs| -> $crate::mal::types::MalRet {
+                $crate::mal::convert::check_arity(
+                    $name,
+                    &[$(<$t as $crate::mal::convert::FromMal>::OPTIONAL),*],
+                    false,
+                    a.len(),
+                )?;
+                #[allow(unused_mut, unused_variables)]
+                let mut args = a.into_iter();
+                $(let $p: $t = $crate::mal::convert::FromMal::from_arg($name, args.next())?;)*
+                let ret = $body;
+                $crate::mal::convert::IntoMal::into_mal(ret)
+            }),
+        )
+    };
+    ($name:literal, |$($p:ident : $t:ty ,)* & $rest:ident : $rt:ty| $body:expr) => {
+        (
+            $name,
+            $crate::mal::types::func(|a: $crate::mal::types::MalArgs| -> $crate::mal::types::MalRet {
+                $crate::mal::convert::check_arity(
+                    $name,
+                    &[$(<$t as $crate::mal::convert::FromMal>::OPTIONAL),*],
+                    true,
+                    a.len(),
+                )?;
+                #[allow(unused_mut)]
+                let mut args = a.into_iter();
+                $(let $p: $t = $crate::mal::convert::FromMal::from_arg($name, args.next())?;)*
+                let $rest: $rt = $crate::mal::convert::rest($name, args)?;
+                let ret = $body;
+                $crate::mal::convert::IntoMal::into_mal(ret)
+            }),
+        )
+    };
+}
@@ -1,15 +1,32 @@
 // mal 语言核心库
+use crate::builtin;
+use crate::format;
 use crate::list;
 use crate::mal::env::Env;
+use crate::mal::cont;
+use crate::mal::vm;
+use crate::mal::bytecode::source_hash;
+use crate::mal::jit;
+use crate::mal::gc;
+use crate::mal::seq::Seq;
+use crate::mal::symbol::{self, Symbol};
+use crate::mal::pmap::{PMap, PSet};
+use crate::mal::convert::{expected, Callable, Num};
+use crate::mal::string;
+use crate::mal::coll;
+use crate::mal::lazy;
+use crate::mal::number;
 use crate::mal::env::{env_set, env_sets};
 use crate::mal::printer::pr_seq;
-use crate::mal::reader::read_str;
-use crate::mal::rep;
+use crate::mal::reader::{read_str, read_str_in};
+use crate::mal::{dynamic, rep};
+use crate::mal::exception;
 use crate::mal::types::MalErr::{ErrMalVal, ErrString};
 use crate::mal::types::MalVal::{
-    Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector,
+    Atom, Big, Bool, Closure, Cont, Float, Func, Hash, Int, Keyword, Lazy, List, MalFunc, Nil, Ratio, Set, Str,
+    Sym, Vector, Error, Native,
 };
-use crate::mal::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
+use crate::mal::types::{MalErr, MalRet, MalVal, _assoc, _dissoc, atom, error, hash_map};
 use crate::vec;
 use crate::vector;
 
@@ -17,303 +34,542 @@ use crate::fs::{inode_ext::INodeExt, ROOT_INODE};
 use alloc::rc::Rc;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::cmp::Ordering::{Greater, Less};
 use log::*;
 
-// 处理两个值入参
-macro_rules! fn_t_int_int {
-    ($ret:ident, $fn:expr) => {{
-        |a: MalArgs| match (a[0].clone(), a[1].clone()) {
-            (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-            _ => error("expecting (int,int) args"),
-        }
-    }};
+// 多个数字依次比较 例如 (< a b c) 相当于 a < b 并且 b < c
+macro_rules! fn_num_cmp {
+    ($name:literal, $test:expr) => {
+        builtin!($name, |x: Num, & more: Vec<Num>| {
+            let mut prev = x.0;
+            for Num(y) in more {
+                match number::cmp(&prev, &y)? {
+                    Some(o) if $test(o) => prev = y,
+                    _ => return Ok(Bool(false)),
+                }
+            }
+            Ok(Bool(true))
+        })
+    };
 }
 
-macro_rules! fn_str {
-    ($fn:expr) => {{
-        |a: MalArgs| match a[0].clone() {
-            Str(a0) => $fn(a0),
-            _ => error("expecting (str) arg"),
-        }
-    }};
+macro_rules! fn_is_type {
+    ($name:literal, $($ps:pat),*) => {
+        builtin!($name, |x: MalVal| match x { $($ps => true,)* _ => false })
+    };
+    ($name:literal, $p:pat if $e:expr) => {
+        builtin!($name, |x: MalVal| match x { $p if $e => true, _ => false })
+    };
+    ($name:literal, $p:pat if $e:expr, $($ps:pat),*) => {
+        builtin!($name, |x: MalVal| match x { $p if $e => true, $($ps => true,)* _ => false })
+    };
 }
 
-macro_rules! fn_is_type {
-    ($($ps:pat),*) => {{
-        |a:MalArgs| {Ok(Bool(match a[0] {$($ps => true,)* _=>false}))}
-    }};
-    ($p:pat if $e:expr) => {{
-        |a:MalArgs| { Ok(Bool(match a[0] { $p if $e => true, _ => false})) }
-    }};
-    ($p:pat if $e:expr,$($ps:pat),*) => {{
-        |a:MalArgs| { Ok(Bool(match a[0] { $p if $e => true, $($ps => true,)* _ => false})) }
-    }};
-}
-
-fn cons(a: MalArgs) -> MalRet {
-    match a[1].clone() {
-        List(v, _) | Vector(v, _) => {
-            let mut new_v = vec![a[0].clone()];
-            new_v.extend_from_slice(&v);
-            Ok(list!(new_v.to_vec()))
-        }
-        _ => error("cons expects seq as second arg"),
+// 列表和向量都是持久化的 cons 和 rest 不复制原来的元素
+// cons 到惰性序列上时不求值原来的序列
+fn cons(x: MalVal, coll: &MalVal) -> MalRet {
+    match coll {
+        List(v, _) | Vector(v, _) => Ok(List(v.cons(x), Rc::new(Nil))),
+        Lazy(_) => Ok(lazy::cell(x, coll.clone())),
+        Nil => Ok(list!(vec![x])),
+        _ => Err(expected("cons", "seq", coll)),
     }
 }
 
-fn concat(a: MalArgs) -> MalRet {
-    let mut new_v = vec![];
-    for seq in a.iter() {
-        match seq {
-            List(v, _) | Vector(v, _) => new_v.extend_from_slice(v),
-            _ => return error("non-seq passed to concat"),
+// 最后一个序列直接共享 只复制前面的序列 有惰性序列时结果也是惰性的
+fn concat(colls: Vec<MalVal>) -> MalRet {
+    let any_lazy = colls.iter().any(|c| match c {
+        Lazy(_) => true,
+        _ => false,
+    });
+    if any_lazy {
+        for c in colls.iter() {
+            match c {
+                List(..) | Vector(..) | Lazy(_) => (),
+                _ => return Err(expected("concat", "seq", c)),
+            }
         }
+        return Ok(lazy::concat(colls));
     }
-    Ok(list!(new_v.to_vec()))
+    let mut res: Option<Seq> = None;
+    for seq in colls.iter().rev() {
+        match (seq, res) {
+            (List(v, _), None) | (Vector(v, _), None) => res = Some(v.clone()),
+            (List(v, _), Some(tail)) | (Vector(v, _), Some(tail)) => {
+                res = Some(v.to_vec().into_iter().rev().fold(tail, |acc, x| acc.cons(x)))
+            }
+            (seq, _) => return Err(expected("concat", "seq", seq)),
+        }
+    }
+    Ok(List(res.unwrap_or(Seq::list(vec![])), Rc::new(Nil)))
 }
 
-fn nth(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (List(seq, _), Int(idx)) | (Vector(seq, _), Int(idx)) => {
-            if seq.len() <= idx as usize {
+fn nth(coll: &MalVal, idx: i64) -> MalRet {
+    match coll {
+        List(seq, _) | Vector(seq, _) => {
+            if idx < 0 || seq.len() <= idx as usize {
                 return error("nth:index out of range");
             }
             Ok(seq[idx as usize].clone())
         }
-        _ => error("invalid args to nth"),
+        Lazy(_) if idx >= 0 => match lazy::uncons(&lazy::drop(idx as usize, coll)?)? {
+            Some((x, _)) => Ok(x),
+            None => error("nth:index out of range"),
+        },
+        Lazy(_) => error("nth:index out of range"),
+        _ => Err(expected("nth", "list, vector or lazy-seq", coll)),
     }
 }
 
-fn first(a: MalArgs) -> MalRet {
-    match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) if seq.len() == 0 => Ok(Nil),
-        List(ref seq, _) | Vector(ref seq, _) => Ok(seq[0].clone()),
+fn first(coll: &MalVal) -> MalRet {
+    match coll {
+        List(seq, _) | Vector(seq, _) if seq.len() == 0 => Ok(Nil),
+        List(seq, _) | Vector(seq, _) => Ok(seq[0].clone()),
         Nil => Ok(Nil),
-        _ => error("invalid args to first"),
+        Lazy(l) => Ok(lazy::force(l)?.map(|(x, _)| x).unwrap_or(Nil)),
+        _ => Err(expected("first", "seq", coll)),
     }
 }
 
-fn rest(a: MalArgs) -> MalRet {
-    match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) => {
-            if seq.len() > 1 {
-                Ok(list![seq[1..].to_vec()])
-            } else {
-                Ok(list![])
-            }
-        }
+fn rest(coll: &MalVal) -> MalRet {
+    match coll {
+        List(seq, _) | Vector(seq, _) => Ok(List(seq.rest(), Rc::new(Nil))),
         Nil => Ok(list![]),
-        _ => error("invalid args to list"),
+        Lazy(l) => Ok(lazy::force(l)?.map(|(_, rest)| rest).unwrap_or(list![])),
+        _ => Err(expected("rest", "seq", coll)),
     }
 }
 
-fn apply(a: MalArgs) -> MalRet {
-    match a[a.len() - 1] {
-        List(ref v, _) | Vector(ref v, _) => {
-            let f = &a[0];
-            let mut fargs = a[1..a.len() - 1].to_vec();
-            // Q: 这个的extend_from_slice的方法的文档
-            fargs.extend_from_slice(&v);
-            f.apply(fargs)
-        }
-        _ => error("apply called with no-seq"),
+// (apply f x ... coll) 最后一个参数是序列 它的元素接在前面的参数后面
+fn apply(f: &MalVal, x: MalVal, more: Vec<MalVal>) -> MalRet {
+    let mut fargs = vec![x];
+    fargs.extend(more);
+    let coll = fargs.pop().unwrap_or(Nil);
+    match coll {
+        List(ref v, _) | Vector(ref v, _) => fargs.extend(v.iter().cloned()),
+        Lazy(_) => fargs.extend(lazy::to_vec(&coll)?),
+        _ => return Err(expected("apply", "seq", &coll)),
     }
+    f.apply(fargs)
 }
 
-// 生成一个符号
-fn symbol(a: MalArgs) -> MalRet {
-    match a[0] {
-        Str(ref s) => Ok(Sym(s.to_string())),
-        _ => error("illegal symbol call"),
+// 把字符串读成 mal 对象 第二个参数是代码所在的文件名 用于报错时的定位
+fn read_string(s: String, file: Option<String>) -> MalRet {
+    match file {
+        None => read_str(s),
+        file => read_str_in(s, file),
     }
 }
 
-fn map(a: MalArgs) -> MalRet {
-    match a[1] {
-        List(ref v, _) | Vector(ref v, _) => {
-            let mut res = vec![];
-            for mv in v.iter() {
-                res.push(a[0].apply(vec![mv.clone()])?);
-            }
-            Ok(list!(res))
-        }
-        _ => error("map called with no-seq"),
+// 惰性序列上的 map 也是惰性的 其他集合直接求出结果
+fn map(f: &MalVal, coll: &MalVal) -> MalRet {
+    if let Lazy(_) = coll {
+        return Ok(lazy::map(f, coll));
+    }
+    let mut res = vec![];
+    for mv in coll::items(coll, "map")? {
+        res.push(f.apply(vec![mv])?);
     }
+    Ok(list!(res))
 }
 
 // 向hash map 中添加新的 key-value不改变原理的值 返回新的hashmap
-fn assoc(a: MalArgs) -> MalRet {
-    match a[0] {
-        Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("assoc on non-Hash Map"),
+// (assoc v i x) 替换向量的第 i 个元素 i 等于长度时添加到末尾
+fn assoc(coll: &MalVal, kvs: Vec<MalVal>) -> MalRet {
+    match coll {
+        Hash(hm, _) => _assoc(hm.clone(), kvs),
+        Vector(v, _) => {
+            let mut v = v.clone();
+            for kv in kvs.chunks(2) {
+                v = match kv {
+                    [Int(i), x] if *i >= 0 => match v.set(*i as usize, x.clone()) {
+                        Some(v) => v,
+                        None => return error("assoc: index out of range"),
+                    },
+                    _ => return error("assoc on vector requires index and value pairs"),
+                };
+            }
+            Ok(Vector(v, Rc::new(Nil)))
+        }
+        _ => Err(expected("assoc", "map or vector", coll)),
     }
 }
 
-fn dissoc(a: MalArgs) -> MalRet {
-    match a[0] {
-        Hash(ref hm, _) => _dissoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("dissoc on non-Hash Map"),
+// 通过 key 获取 vlaue的值
+fn get(coll: &MalVal, k: &MalVal) -> MalRet {
+    match coll {
+        Nil => Ok(Nil),
+        Hash(hm, _) => Ok(hm.get(k).cloned().unwrap_or(Nil)),
+        _ => Err(expected("get", "map", coll)),
     }
 }
 
-// 通过关键字获取 vlaue的值
-fn get(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Nil, _) => Ok(Nil),
-        (Hash(ref hm, _), Str(ref s)) => match hm.get(s) {
-            Some(mv) => Ok(mv.clone()),
-            None => Ok(Nil),
-        },
-        _ => error("illegal get args"),
+// hash map 是否包含某个key
+fn contains_q(coll: &MalVal, k: &MalVal) -> MalRet {
+    match coll {
+        Hash(hm, _) => Ok(Bool(hm.contains_key(k))),
+        Set(s, _) => Ok(Bool(s.contains(k))),
+        _ => Err(expected("contains?", "map or set", coll)),
     }
 }
 
-// hash map 是否包含某个key
-fn contains_q(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Hash(ref hm, _), Str(ref s)) => Ok(Bool(hm.contains_key(s))),
-        _ => error("illefal contains args"),
+// (set coll) 用列表 向量或集合中的元素创建集合
+fn set(coll: &MalVal) -> MalRet {
+    match coll {
+        List(v, _) | Vector(v, _) => Ok(Set(v.iter().cloned().collect(), Rc::new(Nil))),
+        Set(s, _) => Ok(Set(s.clone(), Rc::new(Nil))),
+        Nil => Ok(Set(PSet::new(), Rc::new(Nil))),
+        _ => Err(expected("set", "list, vector or set", coll)),
     }
 }
 
-fn keys(a: MalArgs) -> MalRet {
-    match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| { Str(k.to_string()) }).collect())),
-        _ => error("keys requires Hash Map"),
+// 从集合中删除元素 不改变原来的集合
+fn disj(coll: &MalVal, xs: Vec<MalVal>) -> MalRet {
+    match coll {
+        Set(s, _) => Ok(Set(xs.iter().fold(s.clone(), |acc, x| acc.remove(x)), Rc::new(Nil))),
+        Nil => Ok(Nil),
+        _ => Err(expected("disj", "set", coll)),
     }
 }
 
-fn vals(a: MalArgs) -> MalRet {
-    match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.values().map(|v| { v.clone() }).collect())),
-        _ => error("vals requires Hash Map"),
-    }
+// 集合运算的参数都必须是集合
+fn union(ss: Vec<PSet>) -> MalVal {
+    let res = ss.iter().fold(PSet::new(), |acc, s| s.iter().fold(acc, |acc, x| acc.insert(x.clone())));
+    Set(res, Rc::new(Nil))
+}
+
+fn intersection(first: PSet, others: Vec<PSet>) -> MalVal {
+    Set(
+        first.iter().filter(|x| others.iter().all(|s| s.contains(x))).cloned().collect(),
+        Rc::new(Nil),
+    )
+}
+
+fn difference(first: PSet, others: Vec<PSet>) -> MalVal {
+    Set(
+        others.iter().fold(first, |acc, s| s.iter().fold(acc, |acc, x| acc.remove(x))),
+        Rc::new(Nil),
+    )
+}
+
+// (vary-meta obj f & args) 用 (f (meta obj) args...) 的结果作为新的元数据
+fn vary_meta(x: &MalVal, f: &MalVal, args: Vec<MalVal>) -> MalRet {
+    let mut fargs = vec![x.meta()];
+    fargs.extend(args);
+    x.with_meta(&f.apply(fargs)?)
+}
+
+// (swap! a f & args) 用 (f @a args...) 的结果更新原子
+fn swap(a: &RefCell<MalVal>, f: &MalVal, args: Vec<MalVal>) -> MalRet {
+    let mut fargs = vec![a.borrow().clone()];
+    fargs.extend(args);
+    let v = f.apply(fargs)?;
+    *a.borrow_mut() = v.clone();
+    Ok(v)
 }
 
-fn read_file(a: MalArgs) -> MalRet {
-    match &a[0] {
-        Str(path) => {
-            let rs = ROOT_INODE
-                .lookup(path.clone().as_str())
-                .unwrap()
-                .read_as_string()
-                .unwrap();
-            Ok(Str(rs))
+// (doc f) 打印 def! 记录的名字 参数列表和文档
+fn doc(f: &MalVal) -> MalRet {
+    let meta = f.meta();
+    let get = |k: &str| match meta {
+        Hash(ref hm, _) => hm.get(&Keyword(k.to_string())).cloned(),
+        _ => None,
+    };
+    let mut s = String::new();
+    if let Some(name) = get("name") {
+        s.push_str(&format!("{}\n", name.pr_str(true)));
+    }
+    match (get("arglists"), f) {
+        (Some(List(l, _)), _) => l.iter().for_each(|p| s.push_str(&format!("{}\n", p.pr_str(true)))),
+        // 有多个参数个数的函数
+        (_, MalFunc { params, ast, .. }) if **params == Nil => {
+            if let List(clauses, _) = &**ast {
+                for c in clauses.iter() {
+                    if let List(c, _) = c {
+                        s.push_str(&format!("{}\n", c[0].pr_str(true)));
+                    }
+                }
+            }
         }
-        _ => error("read_file requires path String"),
+        (_, MalFunc { params, .. }) => s.push_str(&format!("{}\n", params.pr_str(true))),
+        _ => (),
+    }
+    if let MalFunc { is_macro: true, .. } = f {
+        s.push_str("Macro\n");
+    }
+    match get("doc") {
+        Some(Str(d)) => s.push_str(&format!("  {}\n", d)),
+        _ => s.push_str("  no documentation\n"),
     }
+    if !to_out(&s)? {
+        print!("{}", s);
+    }
+    Ok(Nil)
 }
 
-fn ls_dir(a: MalArgs) -> MalRet {
-    if a.len() > 0 {
-        match &a[0] {
-            Str(name) => {
-                // FIXME 处理这段函数
-                let list = ROOT_INODE.lookup(name).unwrap().ls_as_vec().unwrap();
-                let rs: Vec<MalVal> = list.iter().map(|v| Str(v.to_string()) as MalVal).collect();
-                Ok(list!(rs.to_vec()))
+// *out* 是 atom 时把输出追加到 atom 中的字符串上 返回 false 时由调用者打印到屏幕
+fn to_out(s: &str) -> Result<bool, MalErr> {
+    match dynamic(symbol::OUT) {
+        Some(Atom(a)) => {
+            let cur = a.borrow().clone();
+            match cur {
+                Str(c) => *a.borrow_mut() = Str(c + s),
+                _ => return Err(ErrString("*out* must hold a string".to_string())),
             }
-            _ => error("ls requires a path string!"),
+            Ok(true)
+        }
+        Some(Nil) | None => Ok(false),
+        Some(_) => Err(ErrString("*out* must be nil or an atom".to_string())),
+    }
+}
+
+// 相对路径从 *current-dir* 开始
+fn path(p: &str) -> String {
+    match dynamic(symbol::CURRENT_DIR) {
+        Some(Str(d)) if !p.starts_with('/') && d.trim_end_matches('/') != "" => {
+            format!("{}/{}", d.trim_end_matches('/'), p)
         }
+        _ => p.to_string(),
+    }
+}
+
+// 找不到文件是 :io 错误 读取失败是 :device 错误
+fn read_file(p: &str) -> MalRet {
+    match ROOT_INODE.lookup(path(p).as_str()) {
+        Ok(inode) => match inode.read_as_string() {
+            Ok(rs) => Ok(Str(rs)),
+            Err(_) => Err(exception::raise("device", format!("cannot read '{}'", path(p)))),
+        },
+        Err(_) => Err(exception::raise("io", format!("file '{}' not found", path(p)))),
+    }
+}
+
+// 没有参数时列出 *current-dir*
+fn ls_dir(p: Option<String>) -> MalRet {
+    let name = match (p, dynamic(symbol::CURRENT_DIR)) {
+        (Some(name), _) => path(&name),
+        (None, Some(Str(d))) => d,
+        (None, _) => "/".to_string(),
+    };
+    let list = if name.trim_end_matches('/') == "" {
+        ROOT_INODE.ls_as_vec().unwrap()
     } else {
-        let tmp = ROOT_INODE.ls_as_vec().unwrap();
-        let tmp_rs: Vec<MalVal> = tmp.iter().map(|v| Str(v.to_string()) as MalVal).collect();
-        Ok(list!(tmp_rs.to_vec()))
+        match ROOT_INODE.lookup(name.as_str()).map(|d| d.ls_as_vec()) {
+            Ok(Ok(list)) => list,
+            Ok(Err(_)) => return Err(exception::raise("device", format!("cannot read '{}'", name))),
+            Err(_) => return Err(exception::raise("io", format!("directory '{}' not found", name))),
+        }
+    };
+    let rs: Vec<MalVal> = list.iter().map(|v| Str(v.to_string()) as MalVal).collect();
+    Ok(list!(rs))
+}
+
+fn add(xs: Vec<Num>) -> MalRet {
+    xs.iter().try_fold(Int(0), |acc, Num(x)| number::add(&acc, x))
+}
+
+fn mul(xs: Vec<Num>) -> MalRet {
+    xs.iter().try_fold(Int(1), |acc, Num(x)| number::mul(&acc, x))
+}
+
+// (- x) 取负数 (- x y z) 依次相减
+fn sub(Num(x): Num, more: Vec<Num>) -> MalRet {
+    match more.len() {
+        0 => number::sub(&Int(0), &x),
+        _ => more.iter().try_fold(x, |acc, Num(y)| number::sub(&acc, y)),
+    }
+}
+
+// (/ x) 取倒数 (/ x y z) 依次相除 整数不能整除时得到分数
+fn div(Num(x): Num, more: Vec<Num>) -> MalRet {
+    match more.len() {
+        0 => number::div(&Int(1), &x),
+        _ => more.iter().try_fold(x, |acc, Num(y)| number::div(&acc, y)),
     }
 }
 
+// 每个内置函数都用 builtin! 声明参数的个数和类型
 pub fn ns() -> Vec<(&'static str, MalVal)> {
     vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("read-string", func(fn_str!(|s| { read_str(s) }))),
-        ("list", func(|a| Ok(list!(a)))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
-        (
-            "prn",
-            func(|a| {
-                print!(93; "{}",pr_seq(&a, true, "", "", ""));
-                Ok(Nil)
-            }),
-        ),
-        ("cons", func(cons)),
-        ("concat", func(concat)),
-        ("nth", func(nth)),
-        ("first", func(first)),
-        ("rest", func(rest)),
-        ("count", func(|x| x[0].count())), // 获取列表 或者向量的长度
-        ("empty?", func(|a| a[0].empty_q())), // 判断一个符号是否为空
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))), // 主动的抛出异常
-        ("apply", func(apply)),
-        ("map", func(map)),
-        ("nil?", func(fn_is_type!(Nil))),
-        ("ture?", func(fn_is_type!(Bool(true)))),
-        ("false?", func(fn_is_type!(Bool(false)))),
-        ("symbol?", func(fn_is_type!(Sym(_)))),
+        builtin!("=", |x: MalVal, y: MalVal| x == y),
+        builtin!("read-string", |s: String, file: Option<String>| read_string(s, file)),
+        builtin!("list", |& xs: Vec<MalVal>| list!(xs)),
+        fn_num_cmp!("<", |o| o == Less),
+        fn_num_cmp!("<=", |o| o != Greater),
+        fn_num_cmp!(">", |o| o == Greater),
+        fn_num_cmp!(">=", |o| o != Less),
+        builtin!("+", |& xs: Vec<Num>| add(xs)),
+        builtin!("-", |x: Num, & more: Vec<Num>| sub(x, more)),
+        builtin!("*", |& xs: Vec<Num>| mul(xs)),
+        builtin!("/", |x: Num, & more: Vec<Num>| div(x, more)),
+        builtin!("quot", |x: MalVal, y: MalVal| number::quot(&x, &y)),
+        builtin!("rem", |x: MalVal, y: MalVal| number::rem(&x, &y)),
+        builtin!("mod", |x: MalVal, y: MalVal| number::modulo(&x, &y)),
+        builtin!("prn", |& xs: Vec<MalVal>| {
+            let s = pr_seq(&xs, true, "", "", "");
+            if !to_out(&s)? {
+                print!(93; "{}", s);
+            }
+            Ok(Nil)
+        }),
+        builtin!("cons", |x: MalVal, coll: MalVal| cons(x, &coll)),
+        builtin!("concat", |& colls: Vec<MalVal>| concat(colls)),
+        builtin!("conj", |coll: MalVal, & xs: Vec<MalVal>| coll::conj(&coll, &xs)),
+        builtin!("nth", |coll: MalVal, i: i64| nth(&coll, i)),
+        builtin!("first", |coll: MalVal| first(&coll)),
+        builtin!("rest", |coll: MalVal| rest(&coll)),
+        builtin!("count", |x: MalVal| x.count()), // 获取列表 或者向量的长度
+        builtin!("empty?", |x: MalVal| x.empty_q()), // 判断一个符号是否为空
+        builtin!("throw", |x: MalVal| Err::<MalVal, _>(ErrMalVal(x))), // 主动的抛出异常
+        builtin!("ex-info", |msg: String, data: Option<MalVal>, cause: Option<MalVal>| {
+            exception::ex_info(msg, data, cause)
+        }),
+        builtin!("ex-message", |e: MalVal| exception::ex_message(&e)),
+        builtin!("ex-data", |e: MalVal| exception::ex_data(&e)),
+        builtin!("ex-kind", |e: MalVal| exception::ex_kind(&e)),
+        builtin!("ex-cause", |e: MalVal| exception::ex_cause(&e)),
+        builtin!("ex-trace", |e: MalVal| exception::ex_trace(&e)),
+        builtin!("apply", |f: Callable, x: MalVal, & more: Vec<MalVal>| apply(&f.0, x, more)),
+        builtin!("map", |f: Callable, coll: MalVal| map(&f.0, &coll)),
+        builtin!("filter", |pred: Callable, coll: MalVal| coll::filter(&pred.0, &coll)),
+        builtin!("reduce", |f: Callable, x: MalVal, coll: Option<MalVal>| coll::reduce(&f.0, x, coll)),
+        builtin!("range", |x: Option<Num>, y: Option<Num>, step: Option<Num>| lazy::range(x, y, step)),
+        builtin!("iterate", |f: Callable, x: MalVal| lazy::iterate(&f.0, &x)),
+        builtin!("lazy-seq*", |f: Callable| lazy::lazy_seq(&f.0)),
+        builtin!("take", |n: i64, coll: MalVal| coll::take(n, &coll)),
+        builtin!("drop", |n: i64, coll: MalVal| coll::drop(n, &coll)),
+        builtin!("reverse", |coll: MalVal| coll::reverse(&coll)),
+        builtin!("last", |coll: MalVal| coll::last(&coll)),
+        builtin!("sort", |x: MalVal, coll: Option<MalVal>| coll::sort(&x, coll.as_ref())),
+        builtin!("sort-by", |keyfn: Callable, x: MalVal, coll: Option<MalVal>| {
+            coll::sort_by(&keyfn.0, &x, coll.as_ref())
+        }),
+        builtin!("compare", |x: MalVal, y: MalVal| coll::compare(&x, &y).map(|o| o as i64)),
+        builtin!("some", |pred: Callable, coll: MalVal| coll::some(&pred.0, &coll)),
+        builtin!("every?", |pred: Callable, coll: MalVal| coll::every_q(&pred.0, &coll)),
+        builtin!("zipmap", |ks: MalVal, vs: MalVal| coll::zipmap(&ks, &vs)),
+        builtin!("group-by", |f: Callable, coll: MalVal| coll::group_by(&f.0, &coll)),
+        builtin!("frequencies", |coll: MalVal| coll::frequencies(&coll)),
+        builtin!("into", |to: MalVal, from: MalVal| coll::into(&to, &from)),
+        fn_is_type!("nil?", Nil),
+        fn_is_type!("ture?", Bool(true)),
+        fn_is_type!("false?", Bool(false)),
+        fn_is_type!("symbol?", Sym(_)),
         // 原子操作
-        ("atom", func(|a| Ok(atom(&a[0])))),
-        ("atom?", func(fn_is_type!(Atom(_)))),
-        ("reset!", func(|a| a[0].reset_bang(&a[1]))),
-        ("deref", func(|a| a[0].deref())),
-        ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
+        builtin!("atom", |x: MalVal| atom(&x)),
+        fn_is_type!("atom?", Atom(_)),
+        fn_is_type!("error?", Error(_)),
+        builtin!("reset!", |a: Rc<RefCell<MalVal>>, x: MalVal| {
+            *a.borrow_mut() = x.clone();
+            x
+        }),
+        builtin!("deref", |a: Rc<RefCell<MalVal>>| a.borrow().clone()),
+        builtin!("swap!", |a: Rc<RefCell<MalVal>>, f: Callable, & args: Vec<MalVal>| swap(&a, &f.0, args)),
         // 生成一个符号
-        ("symbol", func(symbol)),
+        builtin!("symbol", |s: String| Sym(Symbol::new(&s))),
         // 生成一个关键字 一个关键字是一个:开头的字符串!
-        ("keyword", func(|a| a[0].keyword())),
-        (
-            "keyword?",
-            func(fn_is_type!(Str(ref s) if s.starts_with("\u{29e}"))),
-        ),
-        // 判断是否是整形数字
-        ("number?", func(fn_is_type!(Int(_)))),
-        (
+        builtin!("keyword", |x: MalVal| x.keyword()),
+        fn_is_type!("keyword?", Keyword(_)),
+        // 判断是否是数字
+        fn_is_type!("number?", Int(_), Big(_), Ratio(_), Float(_)),
+        fn_is_type!("integer?", Int(_), Big(_)),
+        fn_is_type!("ratio?", Ratio(_)),
+        fn_is_type!("float?", Float(_)),
+        // 数字类型之间的转换
+        builtin!("int", |x: MalVal| number::to_integer(&x)),
+        builtin!("float", |x: Num| Float(number::to_f64(&x.0))),
+        builtin!("numerator", |x: MalVal| number::numerator(&x)),
+        builtin!("denominator", |x: MalVal| number::denominator(&x)),
+        fn_is_type!(
             "lambda?",
-            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
-        ),
-        (
-            "macro?",
-            func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
+            MalFunc { is_macro, .. } if !is_macro,
+            Func(_, _),
+            Native(_, _),
+            Cont(_),
+            Closure(_, _)
         ),
+        fn_is_type!("macro?", MalFunc { is_macro, .. } if is_macro),
         // 生成字符串并且打印
-        ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
+        builtin!("pr-str", |& xs: Vec<MalVal>| pr_seq(&xs, true, "", "", " ")),
         // 生成字符串不进行打印
-        ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
+        builtin!("str", |& xs: Vec<MalVal>| pr_seq(&xs, false, "", "", "")),
+        // 字符串库
+        builtin!("subs", |s: String, start: usize, end: Option<usize>| string::subs(&s, start, end)),
+        builtin!("split", |s: String, sep: String| string::split(&s, &sep)),
+        builtin!("join", |x: MalVal, coll: Option<MalVal>| string::join(&x, coll.as_ref())),
+        builtin!("index-of", |s: String, sub: String, from: Option<usize>| string::index_of(&s, &sub, from)),
+        builtin!("char-at", |s: String, i: usize| string::char_at(&s, i)),
+        builtin!("replace", |s: String, from: String, to: String| string::replace(&s, &from, &to)),
+        builtin!("starts-with?", |s: String, p: String| s.starts_with(p.as_str())),
+        builtin!("ends-with?", |s: String, p: String| s.ends_with(p.as_str())),
+        builtin!("upper-case", |s: String| s.to_uppercase()),
+        builtin!("lower-case", |s: String| s.to_lowercase()),
+        builtin!("trim", |s: String| s.trim().to_string()),
+        builtin!("parse-int", |s: String, radix: Option<i64>| string::parse_int(&s, radix)),
+        builtin!("parse-float", |s: String| string::parse_float(&s)),
+        builtin!("int->string", |n: MalVal, radix: Option<i64>| string::int_to_string(&n, radix)),
+        fn_is_type!("string?", Str(_)),
         // 判断一个符号是否是 列表 或者 向量
-        ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
-        ("list", func(|a| Ok(list!(a)))),
-        ("list?", func(fn_is_type!(List(_, _)))),
-        ("vector", func(|a| Ok(vector!(a)))),
-        ("vector?", func(fn_is_type!(Vector(_, _)))),
+        fn_is_type!("sequential?", List(_, _), Vector(_, _), Lazy(_)),
+        fn_is_type!("lazy-seq?", Lazy(_)),
+        builtin!("list", |& xs: Vec<MalVal>| list!(xs)),
+        fn_is_type!("list?", List(_, _)),
+        builtin!("vector", |& xs: Vec<MalVal>| vector!(xs)),
+        fn_is_type!("vector?", Vector(_, _)),
         // 哈希表支持的方法
-        ("hash-map", func(|a| hash_map(a))),
-        ("map?", func(fn_is_type!(Hash(_, _)))),
-        ("assoc", func(assoc)),
-        ("dissoc", func(dissoc)),
-        ("get", func(get)),
-        ("contains?", func(contains_q)),
-        ("keys", func(keys)),
-        ("vals", func(vals)),
+        builtin!("hash-map", |& kvs: Vec<MalVal>| hash_map(kvs)),
+        fn_is_type!("map?", Hash(_, _)),
+        builtin!("set", |coll: MalVal| set(&coll)),
+        fn_is_type!("set?", Set(_, _)),
+        builtin!("disj", |s: MalVal, & xs: Vec<MalVal>| disj(&s, xs)),
+        builtin!("union", |& ss: Vec<PSet>| union(ss)),
+        builtin!("intersection", |s: PSet, & more: Vec<PSet>| intersection(s, more)),
+        builtin!("difference", |s: PSet, & more: Vec<PSet>| difference(s, more)),
+        builtin!("assoc", |coll: MalVal, & kvs: Vec<MalVal>| assoc(&coll, kvs)),
+        builtin!("dissoc", |hm: PMap, & ks: Vec<MalVal>| _dissoc(hm, ks)),
+        builtin!("get", |coll: MalVal, k: MalVal| get(&coll, &k)),
+        builtin!("contains?", |coll: MalVal, k: MalVal| contains_q(&coll, &k)),
+        builtin!("keys", |hm: PMap| list!(hm.keys().cloned().collect())),
+        builtin!("vals", |hm: PMap| list!(hm.values().cloned().collect())),
+        builtin!("meta", |x: MalVal| x.meta()),
+        builtin!("with-meta", |x: MalVal, m: MalVal| x.with_meta(&m)),
+        builtin!("vary-meta", |x: MalVal, f: Callable, & args: Vec<MalVal>| vary_meta(&x, &f.0, args)),
+        builtin!("doc", |f: MalVal| doc(&f)),
         // 添加文件操作
-        ("read-file", func(read_file)),
-        ("ls", func(ls_dir)),
+        // continuation
+        builtin!("call/cc", |f: Callable| cont::call_cc(&f.0)),
+        builtin!("call-with-current-continuation", |f: Callable| cont::call_cc(&f.0)),
+        builtin!("dynamic-wind", |before: Callable, thunk: Callable, after: Callable| {
+            cont::dynamic_wind(&before.0, &thunk.0, &after.0)
+        }),
+        builtin!("read-file", |p: String| read_file(&p)),
+        builtin!("disassemble", |f: MalVal| vm::disassemble(&f)),
+        builtin!("jit?", |f: MalVal| jit::is_compiled(&f)),
+        builtin!("gc", || gc::gc()),
+        builtin!("ls", |p: Option<String>| ls_dir(p)),
     ]
 }
 
+// 核心库的哈希 核心库中的宏会展开到编译后的代码中 换了核心库时字节码缓存也要失效
+pub fn core_lib_hash() -> u64 {
+    source_hash(&mal().join("\n"))
+}
+
 fn mal() -> Vec<&'static str> {
     vec![
         "(prn \"load core lisp Lib!\")",
         "(def! *gensym-counter* (atom 0))",
+        "(def-dynamic *out* nil)",
+        "(def-dynamic *print-length* nil)",
+        "(def-dynamic *current-dir* \"/\")",
         "(def! gensym (lambda [] (symbol (str \"G__\"(swap! *gensym-counter* (lambda [x] (+ 1 x)))))))",
         "(defmacro! or (v (& xs) (if (empty? xs) nil (if (= 1 (count xs)) (first xs) (let* (condvar (gensym)) `(let* (~condvar ~(first xs)) (if ~condvar ~condvar (or ~@(rest xs)))))))))",
-        "(def! not (lambda (a) (if a false true)))",
+        "(def! not \"Returns true if a is false or nil.\" (lambda (a) (if a false true)))",
         "(defmacro! cond (lambda (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))",
-        "(def! load-file (lambda (f) (eval (read-string (str \"(do \" (read-file f) \"nil)\" )))))",
+        "(defmacro! lazy-seq (lambda (& body) `(lazy-seq* (lambda [] ~@body))))",
+        "(defmacro! with-out-str (lambda (& body) (let* [out (gensym)] `(let* [~out (atom \"\")] (do (binding [*out* ~out] ~@body) (deref ~out))))))",
+        "(def! load-file \"Reads and evaluates every form in the file f.\" (lambda (f) (eval (read-string (str \"(do \" (read-file f) \"\nnil)\") f))))",
         // 初始化时添加进入系统入口
         "(load-file \"entry.jmal\")",
     ]
@@ -321,7 +577,9 @@ fn mal() -> Vec<&'static str> {
 
 // 加载核心函数 使用rust进行定义
 pub fn load_core(env: &Env) {
-    for (k, v) in ns() {
+    let ns = ns();
+    jit::set_builtins(&ns);
+    for (k, v) in ns {
         env_sets(&env, k, v);
     }
     load_core_lib(&env);
@@ -1,89 +1,293 @@
-use alloc::rc::Rc;
-use alloc::string::{String,ToString};
+use alloc::boxed::Box;
+use alloc::rc::{Rc, Weak};
+use alloc::string::ToString;
 use core::cell::RefCell;
 use hashbrown::HashMap;
 use alloc::vec::Vec;
 
 use crate::mal::types::MalErr::ErrString;
 use crate::mal::types::MalVal::{List, Nil, Sym, Vector,Func,Int};
-use crate::mal::types::{error, MalErr, MalRet, MalVal};
+use crate::mal::types::{error, native, MalArgs, MalErr, MalRet, MalVal};
+use crate::mal::module;
+use crate::mal::exception;
+use crate::mal::pattern;
+use crate::mal::eval;
+use crate::mal::gc::{self, Tracked};
+use crate::mal::symbol::{self, Symbol};
+use crate::mal::global::Global;
 
 use crate::format;
 use crate::list;
 
+// 环境中的绑定 全局和模块的环境绑定很多 使用 HashMap
+// 函数参数和 let* 的环境只有几个绑定 放在数组的槽位里 不需要哈希
+// 代码中的符号第一次在数组中找到时记下槽位 之后按槽位直接取值 对不上时才重新查找
+#[derive(Debug)]
+pub enum Slots {
+    Map(HashMap<Symbol, MalVal>),
+    Array(Vec<(Symbol, MalVal)>),
+}
+
+impl Default for Slots {
+    fn default() -> Slots {
+        Slots::Array(Vec::new())
+    }
+}
+
+impl Slots {
+    pub fn get(&self, key: &Symbol) -> Option<&MalVal> {
+        match self {
+            Slots::Map(m) => m.get(key),
+            Slots::Array(a) => {
+                if let Some((k, v)) = key.slot().and_then(|i| a.get(i)) {
+                    if k == key {
+                        return Some(v);
+                    }
+                }
+                let i = a.iter().position(|(k, _)| k == key)?;
+                key.set_slot(i);
+                Some(&a[i].1)
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: Symbol, val: MalVal) {
+        match self {
+            Slots::Map(m) => {
+                m.insert(key, val);
+            }
+            Slots::Array(a) => match a.iter_mut().find(|(k, _)| *k == key) {
+                Some(slot) => slot.1 = val,
+                None => a.push((key, val)),
+            },
+        }
+    }
+
+    pub fn values<'a>(&'a self) -> Box<dyn Iterator<Item = &'a MalVal> + 'a> {
+        match self {
+            Slots::Map(m) => Box::new(m.values()),
+            Slots::Array(a) => Box::new(a.iter().map(|(_, v)| v)),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct EnvSturct {
-    data: RefCell<HashMap<String,MalVal>>,
+    pub data: RefCell<Slots>,
     pub outer: Option<Env>,
 }
 
 pub type Env = Rc<EnvSturct>;
 
-pub fn env_new(outer: Option<Env>)->Env{
-    Rc::new(EnvSturct{
-        data: RefCell::new(HashMap::default()),
+fn env_with(data: Slots, outer: Option<Env>) -> Env {
+    let env = Rc::new(EnvSturct{
+        data: RefCell::new(data),
         outer: outer,
-    })
+    });
+    gc::track(Tracked::Env(Rc::downgrade(&env)));
+    env
+}
+
+// 全局和模块的环境
+pub fn env_new(outer: Option<Env>)->Env{
+    env_with(Slots::Map(HashMap::default()), outer)
+}
+
+// 函数调用 let* 和 match 的局部环境
+pub fn env_local(outer: Env) -> Env {
+    env_with(Slots::Array(Vec::new()), Some(outer))
+}
+
+// 动态变量 按定义它的环境和符号区分 不同模块中同名的动态变量互不影响
+// binding 的新值压在变量自己的栈上 不修改环境中的值 env_get 找到变量时先看栈顶
+struct Dynamic {
+    env: Weak<EnvSturct>,
+    stack: Vec<MalVal>,
+}
+
+static DYNAMICS: Global<Option<HashMap<Symbol, Vec<Dynamic>>>> = Global::new(None);
+// 所有栈上的值的个数 为 0 时查找变量不用再看 DYNAMICS
+static BOUND: Global<usize> = Global::new(0);
+
+fn defined_in(d: &Dynamic, env: &Env) -> bool {
+    d.env.upgrade().map_or(false, |e| Rc::ptr_eq(&e, env))
+}
+
+// 把 env 中的 key 登记为动态变量 重复登记不会清掉正在生效的 binding
+pub fn env_def_dynamic(env: &Env, key: &Symbol) {
+    let mut dynamics = DYNAMICS.borrow_mut();
+    let vars = dynamics.get_or_insert_with(HashMap::new).entry(key.clone()).or_insert_with(Vec::new);
+    // 顺便去掉环境已经释放的变量 binding 期间会一直持有环境 所以它们的栈都是空的
+    vars.retain(|d| d.env.strong_count() > 0);
+    if !vars.iter().any(|d| defined_in(d, env)) {
+        vars.push(Dynamic { env: Rc::downgrade(env), stack: Vec::new() });
+    }
+}
+
+pub fn env_is_dynamic(env: &Env, key: &Symbol) -> bool {
+    match DYNAMICS.borrow().as_ref().and_then(|d| d.get(key)) {
+        Some(vars) => vars.iter().any(|d| defined_in(d, env)),
+        None => false,
+    }
+}
+
+// 内核读取 *out* 这样的变量时使用最先定义的那个 也就是 core 中定义的
+pub fn env_dynamic_env(key: &Symbol) -> Option<Env> {
+    DYNAMICS.borrow().as_ref()?.get(key)?.iter().find_map(|d| d.env.upgrade())
+}
+
+// binding 开始时压入新的值
+pub fn env_push_binding(env: &Env, key: &Symbol, val: MalVal) {
+    let mut dynamics = DYNAMICS.borrow_mut();
+    let var = dynamics.as_mut().and_then(|d| d.get_mut(key)).and_then(|vars| vars.iter_mut().find(|d| defined_in(d, env)));
+    if let Some(var) = var {
+        var.stack.push(val);
+        *BOUND.borrow_mut() += 1;
+    }
+}
+
+// binding 结束时弹出 出错退出时也一样
+pub fn env_pop_binding(env: &Env, key: &Symbol) {
+    let mut dynamics = DYNAMICS.borrow_mut();
+    let var = dynamics.as_mut().and_then(|d| d.get_mut(key)).and_then(|vars| vars.iter_mut().find(|d| defined_in(d, env)));
+    if let Some(Some(_)) = var.map(|var| var.stack.pop()) {
+        *BOUND.borrow_mut() -= 1;
+    }
+}
+
+// env 中的动态变量 key 当前被 binding 绑定的值
+fn bound(env: &Env, key: &Symbol) -> Option<MalVal> {
+    if *BOUND.borrow() == 0 {
+        return None;
+    }
+    DYNAMICS.borrow().as_ref()?.get(key)?.iter().find(|d| defined_in(d, env))?.stack.last().cloned()
 }
 
 // 查找符号所在环境
-pub fn env_find(env: &Env, key: &str) -> Option<Env> {
-    match (env.data.borrow().contains_key(key), env.outer.clone()) {
-        (true, _) => Some(env.clone()),
-        (false, Some(o)) => env_find(&o, key),
-        _ => None,
+pub fn env_find(env: &Env, key: &Symbol) -> Option<Env> {
+    let mut cur = env;
+    loop {
+        if cur.data.borrow().get(key).is_some() {
+            return Some(cur.clone());
+        }
+        match cur.outer {
+            Some(ref o) => cur = o,
+            None => return None,
+        }
     }
 }
 
-// 再环境中查找符号
+// 再环境中查找符号 一层一层向外查找 找到就返回
 pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
     match key {
-        Sym(ref s) => match env_find(env, s) {
-            Some(e) => Ok(e
-                .data
-                .borrow()
-                .get(s)
-                .ok_or(ErrString(format!("'{}' not found", s)))?
-                .clone()),
-            _ => error(&format!("'{}' not found", s)),
-        },
+        Sym(ref s) => {
+            let mut cur = env;
+            loop {
+                if let Some(v) = cur.data.borrow().get(s) {
+                    return Ok(bound(cur, s).unwrap_or_else(|| v.clone()));
+                }
+                match cur.outer {
+                    Some(ref o) => cur = o,
+                    None => break,
+                }
+            }
+            // alias/sym 形式的符号到模块里查找
+            match module::resolve(env, s) {
+                Some(r) => r,
+                None => Err(exception::raise("unbound-symbol", format!("'{}' not found", s))),
+            }
+        }
         _ => error("Env.get called with non-Str"),
     }
 }
 
+// 只在这一层环境中查找 不查找外层
+pub fn env_get_local(env: &Env, key: &Symbol) -> Option<MalVal> {
+    env.data.borrow().get(key).cloned()
+}
+
 // 在环境中绑定符号 MalVal是move进来的 适合使用Rust直接定义函数
 pub fn env_sets(env:&Env,key:&str,val:MalVal){
-    env.data.borrow_mut().insert(key.to_string(),val);
+    env.data.borrow_mut().insert(Symbol::new(key),val);
 }
 
 
+// 注册一个可以捕获状态的 Rust 函数 内核的子系统可以在运行时用它绑定设备 inode 或回调
+pub fn env_native(env: &Env, name: &str, min: usize, max: Option<usize>, f: impl Fn(MalArgs) -> MalRet + 'static) {
+    env_sets(env, name, native(name, min, max, f));
+}
+
 // 给环境中的符号绑定 抽象语法树！
 pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
     match key {
-        Sym(ref s) => {
-            env.data.borrow_mut().insert(s.to_string(), val.clone());
+        Sym(s) => {
+            env.data.borrow_mut().insert(s, val.clone());
             Ok(val)
         }
         _ => error("Env.set called with non-Str"),
     }
 }
 
+// 参数表接受的参数个数 (最少, 最多) 有 & 时最多的个数为 None
+pub fn arity(params: &MalVal) -> (usize, Option<usize>) {
+    let (mut min, mut optional) = (0, None);
+    if let List(binds, _) | Vector(binds, _) = params {
+        for b in binds.iter() {
+            match (b, optional) {
+                (Sym(s), _) if s.id() == symbol::AMP => return (min, None),
+                (Sym(s), None) if s.id() == symbol::AMP_OPTIONAL => optional = Some(0),
+                (_, Some(n)) => optional = Some(n + 1),
+                (_, None) => min += 1,
+            }
+        }
+    }
+    (min, Some(min + optional.unwrap_or(0)))
+}
+
+// 参数表是否接受 n 个参数
+pub fn accepts(params: &MalVal, n: usize) -> bool {
+    match arity(params) {
+        (min, _) if n < min => false,
+        (_, Some(max)) => n <= max,
+        (_, None) => true,
+    }
+}
+
 // 给符号绑定 对应的抽象语法树 并且返回一个待使用的Env子环境， 类似与形参绑定实参 
+// &optional 后面的参数可以省略 写成 (name default) 时省略的参数使用默认值 否则为 nil
+// & {:keys [a b]} 把剩下的参数当作 key value 绑定
 pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Result<Env, MalErr> {
-    let env = env_new(outer);
+    let env = match outer {
+        Some(o) => env_local(o),
+        None => env_new(None),
+    };
     match mbinds {
         List(binds, _) | Vector(binds, _) => {
-            for (i, b) in binds.iter().enumerate() {
-                match b {
+            let mut args = exprs.into_iter();
+            let mut optional = false;
+            let mut i = 0;
+            while i < binds.len() {
+                match &binds[i] {
                     // 这个特性保证 & 后面的其他参数都进入到一个list里面
-                    Sym(s) if s == "&" => {
-                        env_set(&env, binds[i + 1].clone(), list!(exprs[i..].to_vec()))?;
+                    Sym(s) if s.id() == symbol::AMP => {
+                        match binds.get(i + 1) {
+                            Some(p) => pattern::bind(&env, p, list!(args.collect()))?,
+                            None => return Err(ErrString("& must be followed by a parameter".to_string())),
+                        }
                         break;
                     }
-                    _ => {
-                        env_set(&env, b.clone(), exprs[i].clone())?;
+                    Sym(s) if s.id() == symbol::AMP_OPTIONAL => optional = true,
+                    List(l, _) if optional && l.len() == 2 => {
+                        let v = match args.next() {
+                            Some(v) => v,
+                            None => eval(l[1].clone(), env.clone())?,
+                        };
+                        pattern::bind(&env, &l[0], v)?;
                     }
+                    // 参数可以是解构的模式
+                    b => pattern::bind(&env, b, args.next().unwrap_or(Nil))?,
                 }
+                i += 1;
             }
             Ok(env)
         }
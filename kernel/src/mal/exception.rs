@@ -0,0 +1,245 @@
+// 结构化的错误
+// 错误值带有类别 kind 消息 message 附加数据 data 和引起它的错误 cause
+// 内部错误的类别有 type arity unbound-symbol io device 其他错误的类别是 error
+// (try* body (catch* :io e ...) (catch* e ...) (finally* ...)) 按类别选择 catch* 最后总是执行 finally*
+use crate::format;
+use crate::list;
+use crate::mal::convert::expected;
+use crate::mal::env::{env_bind, env_set, Env};
+use crate::mal::symbol::{self, Symbol};
+use crate::mal::types::MalErr::{ErrCont, ErrMalVal, ErrString};
+use crate::mal::types::MalVal::{Error, Hash, Keyword, List, Nil, Str, Sym, Vector};
+use crate::mal::types::{error, MalErr, MalRet, MalVal};
+use crate::vec;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+#[derive(Debug)]
+pub struct ErrorInfo {
+    pub kind: String, // 关键字的名字 例如 io
+    pub message: String,
+    pub data: MalVal,  // map 或 nil
+    pub cause: MalVal, // 引起这个错误的错误 没有时为 nil
+    pub trace: RefCell<Vec<String>>, // 第一次被捕获时的调用栈 最里面的在前
+}
+
+pub fn new(kind: &str, message: String, data: MalVal, cause: MalVal) -> MalVal {
+    Error(Rc::new(ErrorInfo {
+        kind: kind.to_string(),
+        message,
+        data,
+        cause,
+        trace: RefCell::new(Vec::new()),
+    }))
+}
+
+// 带类别的内部错误 例如 raise("io", format!("file '{}' not found", path))
+pub fn raise(kind: &str, message: String) -> MalErr {
+    ErrMalVal(new(kind, message, Nil, Nil))
+}
+
+// catch* 得到的值 throw 抛出的值保持不变 其他错误转成 :error 类别的错误值
+// 错误值还没有调用栈时记下出错时的调用栈 再次抛出时保留原来的
+pub fn caught(e: &MalErr) -> MalVal {
+    let exc = match e.inner() {
+        ErrMalVal(v) => v.clone(),
+        ErrString(s) => new("error", s.to_string(), Nil, Nil),
+        _ => unreachable!(),
+    };
+    if let Error(info) = &exc {
+        let mut trace = info.trace.borrow_mut();
+        if trace.is_empty() {
+            *trace = e.trace();
+        }
+    }
+    exc
+}
+
+// 打印 readably 时是 #error {:kind :io :message "..."} 否则只有消息
+pub fn pr(e: &ErrorInfo, print_readably: bool) -> String {
+    if !print_readably {
+        return e.message.clone();
+    }
+    let mut s = format!("#error {{:kind :{} :message {}", e.kind, Str(e.message.clone()).pr_str(true));
+    if e.data != Nil {
+        s.push_str(&format!(" :data {}", e.data.pr_str(true)));
+    }
+    if e.cause != Nil {
+        s.push_str(&format!(" :cause {}", e.cause.pr_str(true)));
+    }
+    s.push('}');
+    s
+}
+
+// 没有被捕获时显示的内容 内部错误只显示消息
+pub fn describe(e: &ErrorInfo) -> String {
+    match e.data {
+        Nil => e.message.clone(),
+        ref d => format!("{} {}", e.message, d.pr_str(true)),
+    }
+}
+
+// (ex-info msg data) (ex-info msg data cause) data 中的 :kind 是错误的类别 默认为 :error
+pub fn ex_info(msg: String, data: Option<MalVal>, cause: Option<MalVal>) -> MalRet {
+    let data = data.unwrap_or(Nil);
+    let kind = match &data {
+        Hash(m, _) => match m.get(&Keyword("kind".to_string())) {
+            Some(Keyword(k)) => k.clone(),
+            Some(_) => return error("ex-info: :kind must be a keyword"),
+            None => "error".to_string(),
+        },
+        Nil => "error".to_string(),
+        _ => return Err(expected("ex-info", "map", &data)),
+    };
+    Ok(new(&kind, msg, data, cause.unwrap_or(Nil)))
+}
+
+// 下面几个函数的参数不是错误值时返回 nil
+pub fn ex_message(e: &MalVal) -> MalVal {
+    match e {
+        Error(e) => Str(e.message.clone()),
+        _ => Nil,
+    }
+}
+
+pub fn ex_data(e: &MalVal) -> MalVal {
+    match e {
+        Error(e) => e.data.clone(),
+        _ => Nil,
+    }
+}
+
+pub fn ex_kind(e: &MalVal) -> MalVal {
+    match e {
+        Error(e) => Keyword(e.kind.clone()),
+        _ => Nil,
+    }
+}
+
+pub fn ex_cause(e: &MalVal) -> MalVal {
+    match e {
+        Error(e) => e.cause.clone(),
+        _ => Nil,
+    }
+}
+
+pub fn ex_trace(e: &MalVal) -> MalVal {
+    match e {
+        Error(e) => list!(e.trace.borrow().iter().map(|f| Str(f.clone())).collect()),
+        _ => Nil,
+    }
+}
+
+enum Clause {
+    // 能处理的类别 空的表示任何错误 绑定的符号 处理的代码
+    Catch(Vec<String>, MalVal, Vec<MalVal>),
+    Finally(Vec<MalVal>),
+}
+
+fn is_head(l: &[MalVal], name: &str) -> bool {
+    match l.first() {
+        Some(Sym(s)) => s == name,
+        _ => false,
+    }
+}
+
+fn parse_clause(c: &MalVal) -> Result<Clause, MalErr> {
+    let invalid = || Err(ErrString(format!("invalid try* clause {}", c.pr_str(true))));
+    let l = match c {
+        List(l, _) => l.to_vec(),
+        _ => return invalid(),
+    };
+    if is_head(&l, "finally*") {
+        return Ok(Clause::Finally(l[1..].to_vec()));
+    }
+    if !is_head(&l, "catch*") {
+        return invalid();
+    }
+    match l.get(1) {
+        Some(Sym(_)) if l.len() >= 3 => Ok(Clause::Catch(vec![], l[1].clone(), l[2..].to_vec())),
+        Some(k) if l.len() >= 4 => {
+            let kinds = match k {
+                Keyword(k) => vec![k.clone()],
+                Vector(ks, _) => {
+                    let mut kinds = vec![];
+                    for k in ks.iter() {
+                        match k {
+                            Keyword(k) => kinds.push(k.clone()),
+                            _ => return invalid(),
+                        }
+                    }
+                    kinds
+                }
+                _ => return invalid(),
+            };
+            match l[2] {
+                Sym(_) => Ok(Clause::Catch(kinds, l[2].clone(), l[3..].to_vec())),
+                _ => invalid(),
+            }
+        }
+        _ => invalid(),
+    }
+}
+
+fn handles(kinds: &[String], exc: &MalVal) -> bool {
+    match exc {
+        _ if kinds.is_empty() => true,
+        Error(e) => kinds.iter().any(|k| *k == e.kind),
+        _ => false,
+    }
+}
+
+fn eval_body(body: &[MalVal], env: &Env) -> MalRet {
+    let mut res = Nil;
+    for form in body.iter() {
+        res = super::eval(form.clone(), env.clone())?;
+    }
+    Ok(res)
+}
+
+// (try* body 子句 ...) 第一个类别匹配的 catch* 处理错误
+// finally* 在正常返回 出错和 continuation 跳出时都会执行 它的值被丢弃
+pub fn try_form(args: &[MalVal], env: &Env) -> MalRet {
+    let body = match args.first() {
+        Some(b) => b,
+        None => return error("try* requires a body"),
+    };
+    let mut catches = vec![];
+    let mut finally = None;
+    for c in args[1..].iter() {
+        match parse_clause(c)? {
+            Clause::Finally(_) if finally.is_some() => return error("try* allows only one finally*"),
+            Clause::Finally(f) => finally = Some(f),
+            catch => catches.push(catch),
+        }
+    }
+    let res = match super::eval(body.clone(), env.clone()) {
+        // continuation 的跳出不会被 catch* 拦截
+        Err(ErrCont(id, v)) => Err(ErrCont(id, v)),
+        Err(e) => {
+            let exc = caught(&e);
+            let handler = catches.iter().find_map(|c| match c {
+                Clause::Catch(kinds, name, code) if handles(kinds, &exc) => Some((name, code)),
+                _ => None,
+            });
+            match handler {
+                Some((name, code)) => {
+                    let catch_env = env_bind(Some(env.clone()), list![vec![name.clone()]], vec![exc])?;
+                    // catch* 中可以通过 *trace* 读取出错时的调用栈
+                    let frames = e.trace().into_iter().map(|f| Str(f)).collect();
+                    env_set(&catch_env, Sym(Symbol::known(symbol::TRACE)), list!(frames))?;
+                    eval_body(code, &catch_env)
+                }
+                None => Err(e),
+            }
+        }
+        res => res,
+    };
+    if let Some(f) = finally {
+        // finally* 出错时它的错误代替原来的结果
+        eval_body(&f, env)?;
+    }
+    res
+}
@@ -0,0 +1,351 @@
+// 回收循环引用的垃圾
+// 闭包保存着创建它的 Env 而 def! 又把闭包保存到这个 Env 中 这样的 Rc 循环永远不会被释放
+// 这里用试探删除的方法找出这些循环 所有的 Env Atom 和虚拟机的 Frame Closure 创建时都会被登记下来
+// 1. 从每个对象的强引用计数中减去被其他登记对象引用的次数
+// 2. 剩下的计数大于 0 的对象还被外面(rust 的栈 全局变量)引用着 从它们出发能到达的对象都是活的
+// 3. 其余的对象只被垃圾引用 清空它们的内容打破循环 Rc 就会释放它们
+use crate::mal::bytecode::Proto;
+use crate::mal::env::{Env, EnvSturct};
+use crate::mal::lazy;
+use crate::mal::types::MalVal::{Atom, Closure as ClosureVal, Func, Hash, Keyword, Lazy, List, MalFunc, Nil, Set, Vector, Error, Native};
+use crate::mal::types::{hash_map, MalRet, MalVal};
+use crate::mal::vm::{Closure, Frame};
+use crate::mal::global::Global;
+use crate::vec;
+use alloc::rc::{Rc, Weak};
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use hashbrown::{HashMap, HashSet};
+
+// 登记的对象数量超过这个值时自动回收
+const MIN_THRESHOLD: usize = 10000;
+
+pub enum Tracked {
+    Env(Weak<EnvSturct>),
+    Atom(Weak<RefCell<MalVal>>),
+    Frame(Weak<Frame>),
+    Closure(Weak<Closure>),
+}
+
+enum Node {
+    Env(Env),
+    Atom(Rc<RefCell<MalVal>>),
+    Frame(Rc<Frame>),
+    Closure(Rc<Closure>),
+}
+
+struct State {
+    tracked: Vec<Tracked>,
+    threshold: usize,
+}
+
+static GC: Global<State> = Global::new(State {
+    tracked: Vec::new(),
+    threshold: MIN_THRESHOLD,
+});
+
+// 每一类回收了多少个对象 以及回收后还活着的对象
+#[derive(Default)]
+pub struct Stats {
+    envs: usize,
+    atoms: usize,
+    frames: usize,
+    closures: usize,
+    live: usize,
+}
+
+// 登记一个新创建的对象
+pub fn track(t: Tracked) {
+    GC.borrow_mut().tracked.push(t);
+}
+
+// 登记的对象足够多时回收一次
+pub fn maybe_collect() {
+    let full = {
+        let gc = GC.borrow();
+        gc.tracked.len() >= gc.threshold
+    };
+    if full {
+        collect();
+    }
+}
+
+// (gc) 立即回收 返回回收的统计
+pub fn gc() -> MalRet {
+    let s = collect().unwrap_or_default();
+    let stat = |k: &str, n: usize| vec![Keyword(k.to_string()), MalVal::Int(n as i64)];
+    let mut kvs = Vec::new();
+    kvs.extend(stat("envs", s.envs));
+    kvs.extend(stat("atoms", s.atoms));
+    kvs.extend(stat("frames", s.frames));
+    kvs.extend(stat("closures", s.closures));
+    kvs.extend(stat("live", s.live));
+    hash_map(kvs)
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Node> {
+        match self {
+            Tracked::Env(w) => w.upgrade().map(Node::Env),
+            Tracked::Atom(w) => w.upgrade().map(Node::Atom),
+            Tracked::Frame(w) => w.upgrade().map(Node::Frame),
+            Tracked::Closure(w) => w.upgrade().map(Node::Closure),
+        }
+    }
+}
+
+impl Node {
+    fn addr(&self) -> usize {
+        match self {
+            Node::Env(e) => Rc::as_ptr(e) as *const u8 as usize,
+            Node::Atom(a) => Rc::as_ptr(a) as *const u8 as usize,
+            Node::Frame(f) => Rc::as_ptr(f) as *const u8 as usize,
+            Node::Closure(c) => Rc::as_ptr(c) as *const u8 as usize,
+        }
+    }
+
+    fn strong(&self) -> usize {
+        match self {
+            Node::Env(e) => Rc::strong_count(e),
+            Node::Atom(a) => Rc::strong_count(a),
+            Node::Frame(f) => Rc::strong_count(f),
+            Node::Closure(c) => Rc::strong_count(c),
+        }
+    }
+
+    fn downgrade(&self) -> Tracked {
+        match self {
+            Node::Env(e) => Tracked::Env(Rc::downgrade(e)),
+            Node::Atom(a) => Tracked::Atom(Rc::downgrade(a)),
+            Node::Frame(f) => Tracked::Frame(Rc::downgrade(f)),
+            Node::Closure(c) => Tracked::Closure(Rc::downgrade(c)),
+        }
+    }
+
+    // 把直接引用的登记对象放进 w.found 有 RefCell 正在被修改时返回 false
+    fn children(&self, w: &mut Walk) -> bool {
+        match self {
+            Node::Env(e) => {
+                if let Some(ref o) = e.outer {
+                    w.found.push(addr(o));
+                }
+                match e.data.try_borrow() {
+                    Ok(data) => data.values().for_each(|v| w.visit(v)),
+                    Err(_) => return false,
+                }
+            }
+            Node::Atom(a) => match a.try_borrow() {
+                Ok(v) => w.visit(&v),
+                Err(_) => return false,
+            },
+            Node::Frame(fr) => {
+                if let Some(ref o) = fr.outer {
+                    w.found.push(addr(o));
+                }
+                match fr.slots.try_borrow() {
+                    Ok(slots) => slots.iter().for_each(|v| w.visit(v)),
+                    Err(_) => return false,
+                }
+            }
+            Node::Closure(c) => {
+                if let Some(ref fr) = c.frame {
+                    w.found.push(addr(fr));
+                }
+                w.found.push(addr(&c.env));
+                w.proto(&c.proto);
+            }
+        }
+        true
+    }
+
+    // 打破循环 释放的工作交给 Rc
+    fn clear(&self) {
+        match self {
+            Node::Env(e) => {
+                let data = core::mem::take(&mut *e.data.borrow_mut());
+                drop(data);
+            }
+            Node::Atom(a) => {
+                let v = a.replace(Nil);
+                drop(v);
+            }
+            Node::Frame(fr) => {
+                let slots = core::mem::take(&mut *fr.slots.borrow_mut());
+                drop(slots);
+            }
+            Node::Closure(_) => (),
+        }
+    }
+}
+
+fn addr<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const u8 as usize
+}
+
+// 被共享的中间值只穿过一次
+fn enter(shared: bool, seen: &mut HashSet<usize>, addr: usize, count: usize) -> bool {
+    if shared {
+        seen.insert(addr)
+    } else {
+        count == 1
+    }
+}
+
+// 遍历对象引用的值 收集其中登记对象的地址
+struct Walk {
+    shared: bool, // 是否穿过被共享的列表等中间值 它们可能还被外面引用着
+    seen: HashSet<usize>,
+    found: Vec<usize>,
+}
+
+impl Walk {
+    fn new(shared: bool) -> Walk {
+        Walk {
+            shared,
+            seen: HashSet::new(),
+            found: Vec::new(),
+        }
+    }
+
+    fn enter<T>(&mut self, rc: &Rc<T>) -> bool {
+        enter(self.shared, &mut self.seen, addr(rc), Rc::strong_count(rc))
+    }
+
+    // 持久化的集合内部的节点也可能被共享 先收集元素再逐个访问
+    fn visit(&mut self, v: &MalVal) {
+        let mut items = Vec::new();
+        let (shared, seen) = (self.shared, &mut self.seen);
+        match v {
+            List(l, meta) | Vector(l, meta) => {
+                l.walk(&mut |a, n| enter(shared, seen, a, n), &mut |x| items.push(x));
+                items.iter().for_each(|x| self.visit(x));
+                self.meta(meta);
+            }
+            Hash(h, meta) => {
+                h.walk(&mut |a, n| enter(shared, seen, a, n), &mut |x| items.push(x));
+                items.iter().for_each(|x| self.visit(x));
+                self.meta(meta);
+            }
+            Set(s, meta) => {
+                s.walk(&mut |a, n| enter(shared, seen, a, n), &mut |x| items.push(x));
+                items.iter().for_each(|x| self.visit(x));
+                self.meta(meta);
+            }
+            Lazy(l) => {
+                lazy::walk(l, &mut |a, n| enter(shared, seen, a, n), &mut |x| items.push(x));
+                items.iter().for_each(|x| self.visit(x));
+            }
+            Func(_, meta) => self.meta(meta),
+            // 闭包捕获的值看不到 只有在闭包之外也被引用时才会被回收
+            Native(_, meta) => self.meta(meta),
+            MalFunc {
+                env,
+                ast,
+                params,
+                meta,
+                ..
+            } => {
+                self.found.push(addr(env));
+                self.meta(ast);
+                self.meta(params);
+                self.meta(meta);
+            }
+            Atom(a) => self.found.push(addr(a)),
+            Error(e) => {
+                if self.enter(e) {
+                    self.visit(&e.data);
+                    self.visit(&e.cause);
+                }
+            }
+            ClosureVal(c, meta) => {
+                self.found.push(addr(c));
+                self.meta(meta);
+            }
+            _ => (),
+        }
+    }
+
+    fn meta(&mut self, v: &Rc<MalVal>) {
+        if self.enter(v) {
+            self.visit(v);
+        }
+    }
+
+    // 宏展开的结果可能把函数等值放进编译后的常量中
+    fn proto(&mut self, p: &Rc<Proto>) {
+        if self.enter(p) {
+            p.consts.iter().for_each(|v| self.visit(v));
+            p.protos.iter().for_each(|sub| self.proto(sub));
+        }
+    }
+}
+
+// 回收一次 有对象正在被修改时放弃这次回收
+pub fn collect() -> Option<Stats> {
+    let tracked = core::mem::take(&mut GC.borrow_mut().tracked);
+    let nodes: Vec<Node> = tracked.iter().filter_map(|t| t.upgrade()).collect();
+    drop(tracked);
+    let index: HashMap<usize, usize> = nodes.iter().enumerate().map(|(i, n)| (n.addr(), i)).collect();
+    // nodes 中的 Rc 也占了一个引用
+    let mut refs: Vec<isize> = nodes.iter().map(|n| n.strong() as isize - 1).collect();
+    let mut ok = true;
+    let mut w = Walk::new(false);
+    for n in nodes.iter() {
+        ok &= n.children(&mut w);
+        for a in w.found.drain(..) {
+            if let Some(&i) = index.get(&a) {
+                refs[i] -= 1;
+            }
+        }
+    }
+    let mut live = alloc::vec![false; nodes.len()];
+    if ok {
+        let mut work: Vec<usize> = (0..nodes.len()).filter(|&i| refs[i] > 0).collect();
+        for &i in work.iter() {
+            live[i] = true;
+        }
+        let mut w = Walk::new(true);
+        while let Some(i) = work.pop() {
+            ok &= nodes[i].children(&mut w);
+            for a in w.found.drain(..) {
+                if let Some(&j) = index.get(&a) {
+                    if !live[j] {
+                        live[j] = true;
+                        work.push(j);
+                    }
+                }
+            }
+        }
+    }
+    if !ok {
+        // 放弃回收 所有对象保持登记 等登记的对象再多一些时重试
+        let mut gc = GC.borrow_mut();
+        gc.tracked.extend(nodes.iter().map(|n| n.downgrade()));
+        gc.threshold = MIN_THRESHOLD.max(2 * gc.tracked.len());
+        return None;
+    }
+    let mut stats = Stats::default();
+    {
+        let mut gc = GC.borrow_mut();
+        for (i, n) in nodes.iter().enumerate() {
+            if live[i] {
+                gc.tracked.push(n.downgrade());
+            }
+        }
+        stats.live = gc.tracked.len();
+        gc.threshold = MIN_THRESHOLD.max(2 * stats.live);
+    }
+    for (i, n) in nodes.iter().enumerate() {
+        if !live[i] {
+            match n {
+                Node::Env(_) => stats.envs += 1,
+                Node::Atom(_) => stats.atoms += 1,
+                Node::Frame(_) => stats.frames += 1,
+                Node::Closure(_) => stats.closures += 1,
+            }
+            n.clear();
+        }
+    }
+    Some(stats)
+}
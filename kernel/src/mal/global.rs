@@ -0,0 +1,23 @@
+// 解释器的全局状态
+// mal 解释器只在一个核上的 shell 中运行 中断处理不会求值 所以全局状态不需要加锁
+// Global 让 RefCell 可以放在 static 中 同时借用两次和 RefCell 一样会 panic
+use core::cell::{Ref, RefCell, RefMut};
+
+pub struct Global<T>(RefCell<T>);
+
+// 同一时刻只有求值的那个核会访问 见上面的说明
+unsafe impl<T> Sync for Global<T> {}
+
+impl<T> Global<T> {
+    pub const fn new(v: T) -> Global<T> {
+        Global(RefCell::new(v))
+    }
+
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.0.borrow()
+    }
+
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        self.0.borrow_mut()
+    }
+}
@@ -0,0 +1,671 @@
+// x86_64 JIT
+// 一个 MalFunc 被调用足够多次以后 尝试把它编译成机器码
+// 只支持整数运算 比较 if let* 和调用自己 这些代码没有副作用
+// 所以机器码中遇到溢出 除以 0 递归太深等情况时 可以直接放弃(deopt) 用解释器把这次调用重新执行一遍
+// 参数不是整数 或者函数名和用到的内置函数被重新绑定时也交给解释器执行
+use crate::mal::env::{env_get, Env};
+use crate::mal::macroexpand;
+use crate::mal::symbol::Symbol;
+use crate::mal::types::MalVal;
+use crate::mal::types::MalVal::{Bool, Func, Int, List, MalFunc, Sym, Vector};
+use crate::mal::global::Global;
+use crate::memory::{alloc_frame_contiguous, phys_to_virt, PAGE_SIZE};
+use alloc::rc::{Rc, Weak};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use hashbrown::HashMap;
+
+// 调用多少次以后编译
+const THRESHOLD: u32 = 50;
+// deopt 多少次以后不再使用机器码
+const MAX_DEOPTS: u32 = 8;
+// 机器码中自己调用自己的最大深度 内核栈很小
+const MAX_DEPTH: i32 = 1000;
+// 机器码最多占用的内存
+const MAX_CODE_BYTES: usize = 1024 * 1024;
+// 记录少于这个数目时不清理
+const PRUNE_MIN: usize = 256;
+// 机器码中直接计算的内置函数
+const OPS: [&str; 11] = ["+", "-", "*", "quot", "rem", "mod", "<", "<=", ">", ">=", "="];
+
+struct Code {
+    entry: usize,
+    nparams: usize,
+    self_name: Option<Symbol>, // 函数体中调用自己时使用的名字
+    builtins: Vec<(Symbol, usize)>, // 用到的内置函数和编译时它们的地址
+}
+
+struct Entry {
+    ast: Weak<MalVal>, // 函数体 用来确认地址没有被别的函数复用
+    calls: u32,
+    deopts: u32,
+    code: Option<Rc<Code>>,
+    failed: bool, // 不能编译 或者 deopt 太多次
+}
+
+impl Entry {
+    fn new(ast: &Rc<MalVal>) -> Entry {
+        Entry {
+            ast: Rc::downgrade(ast),
+            calls: 0,
+            deopts: 0,
+            code: None,
+            failed: false,
+        }
+    }
+}
+
+// 可执行内存中还没有使用的部分 [next, end) 和已经使用的字节数
+struct Arena {
+    next: usize,
+    end: usize,
+    used: usize,
+}
+
+struct State {
+    entries: Option<HashMap<usize, Entry>>,
+    live: usize, // 上次清理后剩下的记录数
+    builtins: Vec<(String, usize)>, // OPS 中的函数在 core 中的地址
+    arena: Arena,
+}
+
+static JIT: Global<State> = Global::new(State {
+    entries: None,
+    live: 0,
+    builtins: Vec::new(),
+    arena: Arena {
+        next: 0,
+        end: 0,
+        used: 0,
+    },
+});
+
+// 加载 core 时记下内置运算的地址 编译时只有指向这些函数的符号才当成内置运算
+pub fn set_builtins(ns: &[(&'static str, MalVal)]) {
+    JIT.borrow_mut().builtins = ns
+        .iter()
+        .filter_map(|(name, v)| match v {
+            Func(f, _) if OPS.contains(name) => Some((name.to_string(), *f as usize)),
+            _ => None,
+        })
+        .collect();
+}
+
+fn builtin_addr(name: &str) -> Option<usize> {
+    JIT.borrow().builtins.iter().find(|(n, _)| n == name).map(|(_, a)| *a)
+}
+
+// 符号在 env 中是不是指向地址为 addr 的内置函数
+fn points_to(env: &Env, name: &Symbol, addr: usize) -> bool {
+    match env_get(env, &Sym(name.clone())) {
+        Ok(Func(f, _)) => f as usize == addr,
+        _ => false,
+    }
+}
+
+// 取出函数对应的记录 函数体的地址被新的函数复用时重新开始计数
+// 记录数比上次清理后翻倍时 删除函数已经被释放的记录
+fn with_entry<R>(ast: &Rc<MalVal>, f: impl FnOnce(&mut Entry, &mut Arena) -> R) -> R {
+    let mut state = JIT.borrow_mut();
+    let state = &mut *state;
+    let entries = state.entries.get_or_insert_with(HashMap::new);
+    if entries.len() >= core::cmp::max(PRUNE_MIN, state.live * 2) {
+        entries.retain(|_, e| e.ast.strong_count() > 0);
+        state.live = entries.len();
+    }
+    let entry = entries
+        .entry(Rc::as_ptr(ast) as usize)
+        .or_insert_with(|| Entry::new(ast));
+    if !entry.ast.upgrade().map_or(false, |a| Rc::ptr_eq(&a, ast)) {
+        *entry = Entry::new(ast);
+    }
+    f(entry, &mut state.arena)
+}
+
+// 调用 MalFunc 之前调用 已经编译过并且执行成功时返回结果 否则返回 None 由解释器执行
+pub fn call(f: &MalVal, args: &[MalVal]) -> Option<MalVal> {
+    let (ast, env, params) = match f {
+        MalFunc {
+            ast,
+            env,
+            params,
+            is_macro: false,
+            ..
+        } => (ast, env, params),
+        _ => return None,
+    };
+    let code = with_entry(ast, |entry, _| {
+        if entry.failed || entry.code.is_some() {
+            return Err(entry.code.clone());
+        }
+        entry.calls += 1;
+        // 编译期间展开宏时可能再次调用这个函数 先标记为失败
+        entry.failed = entry.calls >= THRESHOLD;
+        Ok(entry.failed)
+    });
+    let code = match code {
+        Err(Some(code)) => code,
+        Err(None) | Ok(false) => return None,
+        Ok(true) => {
+            let compiled = compile(ast, env, params);
+            let code = with_entry(ast, |entry, arena| {
+                let (self_name, nparams, builtins, bytes) = compiled?;
+                let code = Rc::new(Code {
+                    entry: install(arena, &bytes)?,
+                    nparams,
+                    self_name: self_name.map(|n| Symbol::new(&n)),
+                    builtins,
+                });
+                entry.code = Some(code.clone());
+                entry.failed = false;
+                Some(code)
+            });
+            match code {
+                Some(code) => code,
+                None => return None,
+            }
+        }
+    };
+    // 守卫 参数都是整数 并且函数名和用到的内置函数的名字都没有被重新绑定
+    if args.len() != code.nparams {
+        return None;
+    }
+    let mut ints = Vec::with_capacity(args.len());
+    for a in args.iter() {
+        match a {
+            Int(i) => ints.push(*i),
+            _ => return None,
+        }
+    }
+    if let Some(name) = &code.self_name {
+        match env_get(env, &Sym(name.clone())) {
+            Ok(MalFunc { ast: a, .. }) if Rc::ptr_eq(&a, ast) => (),
+            _ => return None,
+        }
+    }
+    if !code.builtins.iter().all(|(name, addr)| points_to(env, name, *addr)) {
+        return None;
+    }
+    let native: extern "sysv64" fn(*const i64, *mut i64) -> u64 =
+        unsafe { core::mem::transmute(code.entry) };
+    let mut out: i64 = 0;
+    if native(ints.as_ptr(), &mut out) == 0 {
+        return Some(Int(out));
+    }
+    with_entry(ast, |entry, _| {
+        entry.deopts += 1;
+        if entry.deopts >= MAX_DEOPTS {
+            entry.failed = true;
+            entry.code = None;
+        }
+    });
+    None
+}
+
+// (jit? f) 函数是否已经被编译成机器码
+pub fn is_compiled(f: &MalVal) -> bool {
+    match f {
+        MalFunc { ast, .. } => with_entry(ast, |entry, _| entry.code.is_some()),
+        _ => false,
+    }
+}
+
+// 把机器码复制到可执行的内存中
+// 物理内存被映射到 PHYSICAL_MEMORY_OFFSET 这段映射是可以执行的
+fn install(arena: &mut Arena, bytes: &[u8]) -> Option<usize> {
+    if arena.used + bytes.len() > MAX_CODE_BYTES {
+        return None;
+    }
+    if arena.end - arena.next < bytes.len() {
+        let pages = (bytes.len().max(4 * PAGE_SIZE) + PAGE_SIZE - 1) / PAGE_SIZE;
+        let vaddr = phys_to_virt(alloc_frame_contiguous(pages, 0)?);
+        arena.next = vaddr;
+        arena.end = vaddr + pages * PAGE_SIZE;
+    }
+    let addr = arena.next;
+    unsafe {
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), addr as *mut u8, bytes.len());
+    }
+    // 16 字节对齐
+    let len = (bytes.len() + 15) & !15;
+    arena.next += len;
+    arena.used += len;
+    Some(addr)
+}
+
+// 一个很小的汇编器 标签都是在这段代码内部的相对跳转
+struct Asm {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, usize)>,
+}
+
+// 条件跳转的第二个字节
+const JO: u8 = 0x80;
+const JE: u8 = 0x84;
+const JNE: u8 = 0x85;
+const JA: u8 = 0x87;
+const JNS: u8 = 0x89;
+const JL: u8 = 0x8C;
+const JGE: u8 = 0x8D;
+const JLE: u8 = 0x8E;
+const JG: u8 = 0x8F;
+
+impl Asm {
+    fn emit(&mut self, b: &[u8]) {
+        self.code.extend_from_slice(b);
+    }
+    fn emit_i32(&mut self, v: i32) {
+        self.emit(&v.to_le_bytes());
+    }
+    fn label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+    fn bind(&mut self, l: usize) {
+        self.labels[l] = Some(self.code.len());
+    }
+    fn rel32(&mut self, l: usize) {
+        self.fixups.push((self.code.len(), l));
+        self.emit_i32(0);
+    }
+    fn jmp(&mut self, l: usize) {
+        self.emit(&[0xE9]);
+        self.rel32(l);
+    }
+    fn jcc(&mut self, cc: u8, l: usize) {
+        self.emit(&[0x0F, cc]);
+        self.rel32(l);
+    }
+    fn call(&mut self, l: usize) {
+        self.emit(&[0xE8]);
+        self.rel32(l);
+    }
+    fn finish(mut self) -> Vec<u8> {
+        for (pos, l) in self.fixups.iter() {
+            let target = self.labels[*l].unwrap() as i32;
+            let rel = target - (*pos as i32 + 4);
+            self.code[*pos..*pos + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        self.code
+    }
+
+    // mov rax, imm64
+    fn mov_rax_imm(&mut self, v: i64) {
+        self.emit(&[0x48, 0xB8]);
+        self.emit(&v.to_le_bytes());
+    }
+    // mov rax, [rbp + disp]
+    fn load(&mut self, disp: i32) {
+        self.emit(&[0x48, 0x8B, 0x85]);
+        self.emit_i32(disp);
+    }
+    // mov [rbp + disp], rax
+    fn store(&mut self, disp: i32) {
+        self.emit(&[0x48, 0x89, 0x85]);
+        self.emit_i32(disp);
+    }
+    fn push_rax(&mut self) {
+        self.emit(&[0x50]);
+    }
+    fn pop_rax(&mut self) {
+        self.emit(&[0x58]);
+    }
+    // pop rcx 后 rax 是左边的操作数 rcx 是右边的操作数
+    fn pop_operands(&mut self) {
+        // mov rcx, rax ; pop rax
+        self.emit(&[0x48, 0x89, 0xC1, 0x58]);
+    }
+}
+
+// 编译一个函数体 生成的代码开头是供 Rust 调用的入口
+// 入口: extern "sysv64" fn(args: *const i64, out: *mut i64) -> u64 返回 0 表示成功 1 表示 deopt
+struct Compiler<'a> {
+    asm: Asm,
+    env: &'a Env,
+    ast: &'a Rc<MalVal>,
+    params: Vec<String>,
+    locals: Vec<(String, usize)>,
+    nlocals: usize,
+    self_name: Option<String>,
+    builtins: Vec<(Symbol, usize)>,
+    deopt: usize,
+    func: usize,
+    body: usize,
+}
+
+fn compile(
+    ast: &Rc<MalVal>,
+    env: &Env,
+    params: &MalVal,
+) -> Option<(Option<String>, usize, Vec<(Symbol, usize)>, Vec<u8>)> {
+    let params: Vec<String> = match params {
+        List(p, _) | Vector(p, _) => p
+            .iter()
+            .map(|p| match p {
+                Sym(s) if !s.starts_with('&') => Some(s.to_string()),
+                _ => None,
+            })
+            .collect::<Option<Vec<String>>>()?,
+        _ => return None,
+    };
+    let mut asm = Asm {
+        code: Vec::new(),
+        labels: Vec::new(),
+        fixups: Vec::new(),
+    };
+    let deopt = asm.label();
+    let func = asm.label();
+    let body = asm.label();
+    let mut c = Compiler {
+        asm,
+        env,
+        ast,
+        params,
+        locals: Vec::new(),
+        nlocals: 0,
+        self_name: None,
+        builtins: Vec::new(),
+        deopt,
+        func,
+        body,
+    };
+    c.entry();
+    c.function(ast)?;
+    let n = c.params.len();
+    Some((c.self_name, n, c.builtins, c.asm.finish()))
+}
+
+impl<'a> Compiler<'a> {
+    // Rust 调用的入口 保存寄存器 把参数压栈后调用函数体
+    // r12 保存入口时的栈 deopt 时直接恢复 r13 是递归深度 r14 保存结果的地址
+    fn entry(&mut self) {
+        let a = &mut self.asm;
+        // push rbp rbx r12 r13 r14 r15
+        a.emit(&[0x55, 0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+        // mov r12, rsp ; xor r13d, r13d ; mov r14, rsi
+        a.emit(&[0x49, 0x89, 0xE4, 0x45, 0x31, 0xED, 0x49, 0x89, 0xF6]);
+        for i in 0..self.params.len() {
+            // push qword [rdi + 8*i]
+            a.emit(&[0xFF, 0xB7]);
+            a.emit_i32(8 * i as i32);
+        }
+        a.call(self.func);
+        // mov [r14], rax ; mov rsp, r12 ; xor eax, eax
+        a.emit(&[0x49, 0x89, 0x06, 0x4C, 0x89, 0xE4, 0x31, 0xC0]);
+        let done = a.label();
+        a.jmp(done);
+        a.bind(self.deopt);
+        // mov rsp, r12 ; mov eax, 1
+        a.emit(&[0x4C, 0x89, 0xE4, 0xB8, 0x01, 0x00, 0x00, 0x00]);
+        a.bind(done);
+        // pop r15 r14 r13 r12 rbx rbp ; ret
+        a.emit(&[0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B, 0x5D, 0xC3]);
+    }
+
+    // 函数体 参数由调用者压栈 第 i 个参数在 [rbp + 16 + 8*(n-1-i)] 局部变量在 [rbp - 8*(j+1)]
+    fn function(&mut self, ast: &MalVal) -> Option<()> {
+        self.asm.bind(self.func);
+        // push rbp ; mov rbp, rsp ; sub rsp, imm32
+        self.asm.emit(&[0x55, 0x48, 0x89, 0xE5, 0x48, 0x81, 0xEC]);
+        let frame_size = self.asm.code.len();
+        self.asm.emit_i32(0);
+        self.asm.bind(self.body);
+        self.expr(ast, true)?;
+        // mov rsp, rbp ; pop rbp ; ret
+        self.asm.emit(&[0x48, 0x89, 0xEC, 0x5D, 0xC3]);
+        let size = (8 * self.nlocals) as i32;
+        self.asm.code[frame_size..frame_size + 4].copy_from_slice(&size.to_le_bytes());
+        Some(())
+    }
+
+    fn param_disp(&self, i: usize) -> i32 {
+        16 + 8 * (self.params.len() - 1 - i) as i32
+    }
+
+    fn var(&self, name: &str) -> Option<i32> {
+        if let Some((_, j)) = self.locals.iter().rev().find(|(n, _)| n == name) {
+            return Some(-8 * (*j as i32 + 1));
+        }
+        let i = self.params.iter().rposition(|p| p == name)?;
+        Some(self.param_disp(i))
+    }
+
+    // 符号是不是没有被遮盖的内置函数 记下来供调用前的守卫检查
+    fn is_builtin(&mut self, name: &str) -> bool {
+        if self.var(name).is_some() {
+            return false;
+        }
+        let sym = Symbol::new(name);
+        match builtin_addr(name) {
+            Some(addr) if points_to(self.env, &sym, addr) => {
+                if !self.builtins.iter().any(|(s, _)| *s == sym) {
+                    self.builtins.push((sym, addr));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // 符号是不是指向正在编译的函数
+    fn is_self(&mut self, name: &str) -> bool {
+        if self.var(name).is_some() {
+            return false;
+        }
+        match env_get(self.env, &Sym(Symbol::new(name))) {
+            Ok(MalFunc { ast, .. }) if Rc::ptr_eq(&ast, self.ast) => {
+                self.self_name = Some(name.to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // 计算表达式 结果放在 rax
+    fn expr(&mut self, e: &MalVal, tail: bool) -> Option<()> {
+        match e {
+            Int(i) => {
+                self.asm.mov_rax_imm(*i);
+                Some(())
+            }
+            Sym(s) => {
+                let disp = self.var(s)?;
+                self.asm.load(disp);
+                Some(())
+            }
+            List(l, _) if l.len() > 0 => {
+                let l = &l.to_vec();
+                let head = match &l[0] {
+                    Sym(s) => s.as_str(),
+                    _ => return None,
+                };
+                if self.var(head).is_none() {
+                    if let (true, Ok(expanded)) = macroexpand(e.clone(), self.env) {
+                        return self.expr(&expanded, tail);
+                    }
+                }
+                match head {
+                    "if" if self.var("if").is_none() => self.if_form(l, tail),
+                    "let*" if self.var("let*").is_none() => self.let_form(l, tail),
+                    "+" | "-" | "*" if self.is_builtin(head) => self.arith(head, &l[1..]),
+                    "quot" | "rem" | "mod" if l.len() == 3 && self.is_builtin(head) => {
+                        self.divide(head, &l[1], &l[2])
+                    }
+                    _ if self.is_self(head) && l.len() - 1 == self.params.len() => {
+                        self.self_call(&l[1..], tail)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn operands(&mut self, a: &MalVal, b: &MalVal) -> Option<()> {
+        self.expr(a, false)?;
+        self.asm.push_rax();
+        self.expr(b, false)?;
+        self.asm.pop_operands();
+        Some(())
+    }
+
+    fn arith(&mut self, op: &str, args: &[MalVal]) -> Option<()> {
+        match (op, args.len()) {
+            ("+", 0) => self.asm.mov_rax_imm(0),
+            ("*", 0) => self.asm.mov_rax_imm(1),
+            ("-", 0) => return None,
+            ("-", 1) => {
+                self.expr(&args[0], false)?;
+                // neg rax
+                self.asm.emit(&[0x48, 0xF7, 0xD8]);
+                self.asm.jcc(JO, self.deopt);
+            }
+            _ => {
+                self.expr(&args[0], false)?;
+                for a in args[1..].iter() {
+                    self.asm.push_rax();
+                    self.expr(a, false)?;
+                    self.asm.pop_operands();
+                    match op {
+                        "+" => self.asm.emit(&[0x48, 0x01, 0xC8]),       // add rax, rcx
+                        "-" => self.asm.emit(&[0x48, 0x29, 0xC8]),       // sub rax, rcx
+                        _ => self.asm.emit(&[0x48, 0x0F, 0xAF, 0xC1]),   // imul rax, rcx
+                    }
+                    self.asm.jcc(JO, self.deopt);
+                }
+            }
+        }
+        Some(())
+    }
+
+    // 整数除法 除数为 0 时 deopt 让解释器报错
+    fn divide(&mut self, op: &str, a: &MalVal, b: &MalVal) -> Option<()> {
+        self.operands(a, b)?;
+        let normal = self.asm.label();
+        let done = self.asm.label();
+        // test rcx, rcx
+        self.asm.emit(&[0x48, 0x85, 0xC9]);
+        self.asm.jcc(JE, self.deopt);
+        // cmp rcx, -1  除以 -1 时 idiv 可能溢出 单独处理
+        self.asm.emit(&[0x48, 0x83, 0xF9, 0xFF]);
+        self.asm.jcc(JNE, normal);
+        if op == "quot" {
+            self.asm.emit(&[0x48, 0xF7, 0xD8]); // neg rax
+            self.asm.jcc(JO, self.deopt);
+        } else {
+            self.asm.emit(&[0x31, 0xC0]); // xor eax, eax
+        }
+        self.asm.jmp(done);
+        self.asm.bind(normal);
+        // cqo ; idiv rcx
+        self.asm.emit(&[0x48, 0x99, 0x48, 0xF7, 0xF9]);
+        match op {
+            "quot" => (),
+            "rem" => self.asm.emit(&[0x48, 0x89, 0xD0]), // mov rax, rdx
+            _ => {
+                // 余数不为 0 并且和除数符号不同时加上除数
+                // mov rax, rdx ; test rax, rax
+                self.asm.emit(&[0x48, 0x89, 0xD0, 0x48, 0x85, 0xC0]);
+                self.asm.jcc(JE, done);
+                // mov rdx, rax ; xor rdx, rcx
+                self.asm.emit(&[0x48, 0x89, 0xC2, 0x48, 0x31, 0xCA]);
+                self.asm.jcc(JNS, done);
+                self.asm.emit(&[0x48, 0x01, 0xC8]); // add rax, rcx
+            }
+        }
+        self.asm.bind(done);
+        Some(())
+    }
+
+    // if 的条件只支持两个整数的比较 和 true false
+    fn if_form(&mut self, l: &[MalVal], tail: bool) -> Option<()> {
+        if l.len() != 4 {
+            return None;
+        }
+        match &l[1] {
+            Bool(true) => return self.expr(&l[2], tail),
+            Bool(false) => return self.expr(&l[3], tail),
+            _ => (),
+        }
+        let (head, a, b) = match &l[1] {
+            List(c, _) if c.len() == 3 => match &c[0] {
+                Sym(s) if self.is_builtin(s) => (s.to_string(), &c[1], &c[2]),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        // 条件不成立时跳转
+        let cc = match head.as_str() {
+            "<" => JGE,
+            "<=" => JG,
+            ">" => JLE,
+            ">=" => JL,
+            "=" => JNE,
+            _ => return None,
+        };
+        let other = self.asm.label();
+        let done = self.asm.label();
+        self.operands(a, b)?;
+        // cmp rax, rcx
+        self.asm.emit(&[0x48, 0x39, 0xC8]);
+        self.asm.jcc(cc, other);
+        self.expr(&l[2], tail)?;
+        self.asm.jmp(done);
+        self.asm.bind(other);
+        self.expr(&l[3], tail)?;
+        self.asm.bind(done);
+        Some(())
+    }
+
+    fn let_form(&mut self, l: &[MalVal], tail: bool) -> Option<()> {
+        if l.len() != 3 {
+            return None;
+        }
+        let binds = match &l[1] {
+            List(b, _) | Vector(b, _) if b.len() % 2 == 0 => b.to_vec(),
+            _ => return None,
+        };
+        let scope = self.locals.len();
+        for kv in binds.chunks(2) {
+            let name = match &kv[0] {
+                Sym(s) => s.to_string(),
+                _ => return None,
+            };
+            self.expr(&kv[1], false)?;
+            let j = self.nlocals;
+            self.nlocals += 1;
+            self.asm.store(-8 * (j as i32 + 1));
+            self.locals.push((name, j));
+        }
+        self.expr(&l[2], tail)?;
+        self.locals.truncate(scope);
+        Some(())
+    }
+
+    // 调用自己 尾调用直接覆盖参数后跳回函数体开头
+    fn self_call(&mut self, args: &[MalVal], tail: bool) -> Option<()> {
+        for a in args.iter() {
+            self.expr(a, false)?;
+            self.asm.push_rax();
+        }
+        if tail {
+            for i in (0..args.len()).rev() {
+                self.asm.pop_rax();
+                let disp = self.param_disp(i);
+                self.asm.store(disp);
+            }
+            self.asm.jmp(self.body);
+        } else {
+            // inc r13 ; cmp r13, MAX_DEPTH ; ja deopt
+            self.asm.emit(&[0x49, 0xFF, 0xC5, 0x49, 0x81, 0xFD]);
+            self.asm.emit_i32(MAX_DEPTH);
+            self.asm.jcc(JA, self.deopt);
+            self.asm.call(self.func);
+            // dec r13 ; add rsp, 8*n
+            self.asm.emit(&[0x49, 0xFF, 0xCD, 0x48, 0x81, 0xC4]);
+            self.asm.emit_i32(8 * args.len() as i32);
+        }
+        Some(())
+    }
+}
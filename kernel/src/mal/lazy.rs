@@ -0,0 +1,292 @@
+// 惰性序列
+// 每个惰性序列在第一次使用时才求值 得到第一个元素和剩下的序列 结果保存下来不会再次求值
+// 剩下的序列可以是列表 向量或者另一个惰性序列 所以可以表示无限长的序列
+// 只要不保留序列的开头 已经用过的部分就会被 Rc 释放
+use crate::list;
+use crate::mal::coll;
+use crate::mal::convert::Num;
+use crate::mal::number;
+use crate::mal::types::MalVal::{Bool, Lazy, List, Nil, Vector};
+use crate::mal::types::MalErr::ErrString;
+use crate::mal::types::{error, MalErr, MalRet, MalVal};
+use crate::vec;
+use alloc::rc::Rc;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::cmp::Ordering::{Greater, Less};
+use core::mem;
+
+pub struct LazySeq(RefCell<State>);
+
+enum State {
+    Pending(Gen),
+    Forcing, // 正在求值 再次求值说明序列依赖它自己
+    Done(Option<(MalVal, MalVal)>), // 第一个元素和剩下的序列 空序列为 None
+}
+
+// 求值的方法 内置的惰性函数不需要创建 lisp 函数
+enum Gen {
+    Body(MalVal),                  // (lazy-seq body) 中包装 body 的函数
+    Range(MalVal, Option<MalVal>, MalVal), // 开始 结束 步长 没有结束时是无限的
+    Iterate(MalVal, MalVal),       // (f x) 是下一个元素
+    Map(MalVal, MalVal),           // 函数和原来的序列
+    Filter(MalVal, MalVal),
+    Concat(MalVal, MalVal),        // 正在取元素的序列和后面的序列组成的列表
+}
+
+impl core::fmt::Debug for LazySeq {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "LazySeq")
+    }
+}
+
+fn new(gen: Gen) -> MalVal {
+    Lazy(Rc::new(LazySeq(RefCell::new(State::Pending(gen)))))
+}
+
+// 已经求值的序列 用于在惰性序列前面 cons 元素
+pub fn cell(x: MalVal, rest: MalVal) -> MalVal {
+    Lazy(Rc::new(LazySeq(RefCell::new(State::Done(Some((x, rest)))))))
+}
+
+fn truthy(v: &MalVal) -> bool {
+    match v {
+        Nil | Bool(false) => false,
+        _ => true,
+    }
+}
+
+// 求值一个惰性序列
+pub fn force(l: &Rc<LazySeq>) -> Result<Option<(MalVal, MalVal)>, MalErr> {
+    match *l.0.borrow() {
+        State::Done(ref c) => return Ok(c.clone()),
+        State::Forcing => return Err(ErrString("lazy sequence depends on itself".to_string())),
+        State::Pending(_) => (),
+    }
+    let gen = match mem::replace(&mut *l.0.borrow_mut(), State::Forcing) {
+        State::Pending(gen) => gen,
+        _ => unreachable!(),
+    };
+    match step(&gen) {
+        Ok(c) => {
+            *l.0.borrow_mut() = State::Done(c.clone());
+            Ok(c)
+        }
+        Err(e) => {
+            // 出错时保持未求值 下次使用时重试
+            *l.0.borrow_mut() = State::Pending(gen);
+            Err(e)
+        }
+    }
+}
+
+fn step(gen: &Gen) -> Result<Option<(MalVal, MalVal)>, MalErr> {
+    match gen {
+        Gen::Body(f) => uncons(&f.apply(vec![])?),
+        Gen::Range(start, end, step) => {
+            let going = match number::cmp(step, &MalVal::Int(0))? {
+                Some(Greater) => Less,
+                _ => Greater,
+            };
+            if let Some(end) = end {
+                if number::cmp(start, end)? != Some(going) {
+                    return Ok(None);
+                }
+            }
+            let next = number::add(start, step)?;
+            Ok(Some((start.clone(), new(Gen::Range(next, end.clone(), step.clone())))))
+        }
+        Gen::Iterate(f, x) => {
+            let y = f.apply(vec![x.clone()])?;
+            Ok(Some((y.clone(), new(Gen::Iterate(f.clone(), y)))))
+        }
+        Gen::Map(f, coll) => match uncons(coll)? {
+            Some((x, rest)) => Ok(Some((f.apply(vec![x])?, new(Gen::Map(f.clone(), rest))))),
+            None => Ok(None),
+        },
+        Gen::Filter(pred, coll) => {
+            // 不满足条件的元素用循环跳过 不会递归
+            let mut coll = coll.clone();
+            while let Some((x, rest)) = uncons(&coll)? {
+                if truthy(&pred.apply(vec![x.clone()])?) {
+                    return Ok(Some((x, new(Gen::Filter(pred.clone(), rest)))));
+                }
+                coll = rest;
+            }
+            Ok(None)
+        }
+        Gen::Concat(cur, colls) => {
+            // 当前的序列用完时换下一个 空序列用循环跳过
+            let mut cur = cur.clone();
+            let mut colls = colls.clone();
+            loop {
+                if let Some((x, rest)) = uncons(&cur)? {
+                    return Ok(Some((x, new(Gen::Concat(rest, colls)))));
+                }
+                match uncons(&colls)? {
+                    Some((next, more)) => {
+                        cur = next;
+                        colls = more;
+                    }
+                    None => return Ok(None),
+                }
+            }
+        }
+    }
+}
+
+// 取出任意序列的第一个元素和剩下的部分 空序列返回 None
+pub fn uncons(v: &MalVal) -> Result<Option<(MalVal, MalVal)>, MalErr> {
+    match v {
+        List(s, _) | Vector(s, _) => match s.first() {
+            Some(x) => Ok(Some((x.clone(), List(s.rest(), Rc::new(Nil))))),
+            None => Ok(None),
+        },
+        Lazy(l) => force(l),
+        Nil => Ok(None),
+        _ => uncons(&list!(coll::items(v, "seq")?)),
+    }
+}
+
+// 求出整个序列的元素 无限序列不会返回
+pub fn to_vec(v: &MalVal) -> Result<Vec<MalVal>, MalErr> {
+    let mut out = Vec::new();
+    let mut cur = v.clone();
+    while let Some((x, rest)) = uncons(&cur)? {
+        out.push(x);
+        cur = rest;
+    }
+    Ok(out)
+}
+
+// 求出元素 遇到错误时停止 打印时使用
+// 有 limit 时最多求出 limit + 1 个元素 打印无限序列时够判断是否需要 ...
+pub fn elements(l: &Rc<LazySeq>, limit: Option<usize>) -> Vec<MalVal> {
+    let mut out = Vec::new();
+    let mut cur = Lazy(l.clone());
+    while let Ok(Some((x, rest))) = uncons(&cur) {
+        if Some(out.len()) == limit.map(|n| n + 1) {
+            break;
+        }
+        out.push(x);
+        cur = rest;
+    }
+    out
+}
+
+// 去掉前 n 个元素 剩下的部分保持惰性
+pub fn drop(n: usize, v: &MalVal) -> MalRet {
+    let mut cur = v.clone();
+    for _ in 0..n {
+        match uncons(&cur)? {
+            Some((_, rest)) => cur = rest,
+            None => break,
+        }
+    }
+    Ok(cur)
+}
+
+// (lazy-seq* f) 第一次使用时调用 (f) 宏 lazy-seq 把 body 包装成函数
+pub fn lazy_seq(f: &MalVal) -> MalVal {
+    new(Gen::Body(f.clone()))
+}
+
+// (range) 从 0 开始的无限序列 其他参数和 coll::range 一样
+pub fn range(x: Option<Num>, y: Option<Num>, step: Option<Num>) -> MalRet {
+    let (start, end) = match (x, y) {
+        (None, _) => (MalVal::Int(0), None),
+        (Some(Num(end)), None) => (MalVal::Int(0), Some(end)),
+        (Some(Num(start)), Some(Num(end))) => (start, Some(end)),
+    };
+    let step = step.map(|Num(s)| s).unwrap_or(MalVal::Int(1));
+    match number::cmp(&step, &MalVal::Int(0))? {
+        Some(Greater) | Some(Less) => Ok(new(Gen::Range(start, end, step))),
+        _ => error("range: step must not be zero"),
+    }
+}
+
+// (iterate f x) 得到 x (f x) (f (f x)) ...
+pub fn iterate(f: &MalVal, x: &MalVal) -> MalVal {
+    cell(x.clone(), new(Gen::Iterate(f.clone(), x.clone())))
+}
+
+pub fn map(f: &MalVal, coll: &MalVal) -> MalVal {
+    new(Gen::Map(f.clone(), coll.clone()))
+}
+
+pub fn filter(pred: &MalVal, coll: &MalVal) -> MalVal {
+    new(Gen::Filter(pred.clone(), coll.clone()))
+}
+
+pub fn concat(colls: Vec<MalVal>) -> MalVal {
+    new(Gen::Concat(Nil, list!(colls)))
+}
+
+// 遍历内部的值 已经求值的部分用循环遍历 很长的序列也不会耗尽栈
+pub fn walk<'a>(l: &'a Rc<LazySeq>, enter: &mut dyn FnMut(usize, usize) -> bool, visit: &mut dyn FnMut(&'a MalVal)) {
+    let mut cur = l;
+    loop {
+        if !enter(Rc::as_ptr(cur) as *const u8 as usize, Rc::strong_count(cur)) {
+            return;
+        }
+        // 回收时不会修改惰性序列 没有别的借用
+        let st = match unsafe { cur.0.try_borrow_unguarded() } {
+            Ok(st) => st,
+            Err(_) => return,
+        };
+        match st {
+            State::Pending(Gen::Body(f)) => visit(f),
+            State::Pending(Gen::Range(start, end, step)) => {
+                visit(start);
+                end.iter().for_each(|e| visit(e));
+                visit(step);
+            }
+            State::Pending(Gen::Iterate(f, x))
+            | State::Pending(Gen::Map(f, x))
+            | State::Pending(Gen::Filter(f, x))
+            | State::Pending(Gen::Concat(f, x)) => {
+                visit(f);
+                visit(x);
+            }
+            State::Done(Some((x, rest))) => {
+                visit(x);
+                match rest {
+                    Lazy(next) => {
+                        cur = next;
+                        continue;
+                    }
+                    _ => visit(rest),
+                }
+            }
+            State::Done(None) | State::Forcing => (),
+        }
+        return;
+    }
+}
+
+// 很长的已求值序列递归释放会耗尽栈 改成循环释放
+impl Drop for LazySeq {
+    fn drop(&mut self) {
+        let mut next = take_rest(self.0.get_mut());
+        while let Some(l) = next {
+            match Rc::try_unwrap(l) {
+                Ok(mut l) => next = take_rest(l.0.get_mut()),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+fn take_rest(st: &mut State) -> Option<Rc<LazySeq>> {
+    match st {
+        State::Done(Some((_, rest))) => match mem::replace(rest, Nil) {
+            Lazy(l) => Some(l),
+            other => {
+                *rest = other;
+                None
+            }
+        },
+        _ => None,
+    }
+}
@@ -1,6 +1,6 @@
 use crate::alloc::string::{String,ToString};
 use crate::mal::reader::read_str;
-use hashbrown::HashMap;
+use crate::mal::pmap::{PMap, PSet};
 use alloc::rc::Rc;
 use alloc::vec::Vec;
 
@@ -10,20 +10,53 @@ pub mod reader;
 pub mod env;
 pub mod printer;
 pub mod core;
+pub mod number;
+pub mod trace;
+pub mod cont;
+pub mod module;
+pub mod bytecode;
+pub mod compiler;
+pub mod vm;
+pub mod jit;
+pub mod stack;
+pub mod gc;
+pub mod string;
+pub mod coll;
+pub mod lazy;
+pub mod pattern;
+pub mod seq;
+pub mod pmap;
+pub mod symbol;
+pub mod exception;
+pub mod convert;
+pub mod global;
 
-use crate::mal::types::MalVal::{List,Sym,Str,Vector,Hash,Nil,Int,MalFunc,Bool,Func};
-use crate::mal::types::{error,MalRet,MalArgs,MalVal,MalErr};
-use crate::mal::types::MalErr::{ErrMalVal,ErrString};
+use crate::mal::types::MalVal::{List,Sym,Str,Vector,Hash,Set,Nil,Int,MalFunc,Bool,Func,Native,Cont,Closure,Keyword};
+use crate::mal::types::{error,arity_error,MalRet,MalArgs,MalVal,MalErr};
+use crate::mal::seq::Seq;
+use crate::mal::convert::expected;
 use crate::mal::env::Env;
-use crate::mal::env::{env_get,env_set,env_new,env_bind,env_find};
+use crate::mal::env::{env_get,env_set,env_local,env_find,env_def_dynamic,env_is_dynamic,env_dynamic_env,env_push_binding,env_pop_binding};
+use crate::mal::symbol::Symbol;
 use crate::vec;
 use crate::vector;
 use crate::list;
+use crate::format;
 
 // 输入-求值-打印 不循环
 pub fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
+    if !stack::active() {
+        return stack::run(|| rep(str, env));
+    }
     let ast = read_str(str.to_string())?;
     let exp = eval(ast, env.clone())?;
+    // 先求出惰性序列中要打印的元素 打印时就不会再出错
+    if let MalVal::Lazy(_) = exp {
+        match printer::print_length() {
+            Some(n) => lazy::drop(n + 1, &exp).map(|_| ())?,
+            None => lazy::to_vec(&exp).map(|_| ())?,
+        }
+    }
     Ok(exp.pr_str(true))
 }
 
@@ -33,29 +66,29 @@ fn quasiquote(ast: &MalVal) -> MalVal {
         List(ref v,_) | Vector(ref v, _) if v.len() > 0 => {
             let a0 = &v[0];
             match a0 {
-                Sym(ref s) if s == "unquote" => v[1].clone(),
+                Sym(ref s) if s.id() == symbol::UNQUOTE => v[1].clone(),
                 _ => match a0 {
                     List(ref v0,_) | Vector(ref v0,_) if v0.len() > 0 => match v0[0] {
-                        Sym(ref s) if s == "splice-unquote" => list![
-                            Sym("concat".to_string()),
+                        Sym(ref s) if s.id() == symbol::SPLICE_UNQUOTE => list![
+                            Sym(Symbol::known(symbol::CONCAT)),
                             v0[1].clone(),
-                            quasiquote(&list!(v[1..].to_vec()))
+                            quasiquote(&List(v.rest(), Rc::new(Nil)))
                         ],
                         _ => list![
-                            Sym("cons".to_string()),
+                            Sym(Symbol::known(symbol::CONS)),
                             quasiquote(a0),
-                            quasiquote(&list!(v[1..].to_vec()))
+                            quasiquote(&List(v.rest(), Rc::new(Nil)))
                         ],
                     },
                     _ => list![
-                        Sym("cons".to_string()),
+                        Sym(Symbol::known(symbol::CONS)),
                         quasiquote(a0),
-                        quasiquote(&list!(v[1..].to_vec()))
+                        quasiquote(&List(v.rest(), Rc::new(Nil)))
                     ],
                 },
             }
         }
-        _ => list![Sym("quote".to_string()),ast.clone()]
+        _ => list![Sym(Symbol::known(symbol::QUOTE)),ast.clone()]
     }
 }
 
@@ -63,11 +96,9 @@ fn quasiquote(ast: &MalVal) -> MalVal {
 fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
     match ast {
         List(v, _) => match v[0] {
-            Sym(ref s) => match env_find(env, s) {
-                Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
-                    _ => None,
-                },
+            // 通过 env_get 查找 这样 core/defun! 这样的模块符号也可以是宏
+            Sym(_) => match env_get(env, &v[0]) {
+                Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v.rest().to_vec())),
                 _ => None,
             },
             _ => None,
@@ -90,32 +121,218 @@ fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
 }
 
 
-// 求值
-fn eval(mut ast: MalVal,mut env: Env) -> MalRet {
+// 求值 出错时给错误附上出错代码的位置 和出错时的调用栈
+fn eval(ast: MalVal, env: Env) -> MalRet {
+    // 最外层的求值切换到求值栈上进行
+    if !stack::active() {
+        return stack::run(move || eval(ast, env));
+    }
+    if let Err(e) = stack::enter() {
+        return Err(at_form(e, &ast).with_trace(trace::snapshot));
+    }
+    // 登记的对象足够多时回收循环引用的垃圾
+    gc::maybe_collect();
+    let depth = trace::depth();
+    let mut form = ast;
+    let ret = eval_form(&mut form, env, depth)
+        .map_err(|e| at_form(e, &form).with_trace(trace::snapshot));
+    // 离开时弹出这次求值中压入的调用帧
+    trace::truncate(depth);
+    stack::leave();
+    ret
+}
+
+fn at_form(e: MalErr, form: &MalVal) -> MalErr {
+    match form.location() {
+        Some(loc) => e.at(loc),
+        None => e,
+    }
+}
+
+// (lambda ([x] body) ([x y] body ...)) 有多个参数个数的函数 返回 (([x] body) ([x y] (do body ...)))
+// 不是这种形式时返回 None
+pub fn arities(forms: &[MalVal]) -> Option<MalVal> {
+    let mut clauses = vec![];
+    for c in forms.iter() {
+        match c {
+            List(c, _) if c.len() >= 2 && is_vector(&c[0]) => {
+                let body = match c.len() {
+                    2 => c[1].clone(),
+                    _ => List(c.rest().cons(Sym(Symbol::known(symbol::DO))), Rc::new(Nil)),
+                };
+                clauses.push(list![c[0].clone(), body]);
+            }
+            _ => return None,
+        }
+    }
+    if clauses.is_empty() {
+        return None;
+    }
+    Some(list!(clauses))
+}
+
+// 是不是编号为 id 的符号
+pub fn is_sym(v: &MalVal, id: u32) -> bool {
+    match v {
+        Sym(s) => s.id() == id,
+        _ => false,
+    }
+}
+
+fn is_vector(v: &MalVal) -> bool {
+    match v {
+        Vector(..) => true,
+        _ => false,
+    }
+}
+
+// def! 定义的函数带上的元数据 {:name :arglists :doc} 值不是 lambda 也没有文档时返回 None
+pub fn def_meta(name: &MalVal, doc: Option<&MalVal>, form: &MalVal) -> Option<MalVal> {
+    let mut kvs = vec![];
+    if let List(f, _) = form {
+        if f.len() >= 2 && is_sym(&f[0], symbol::LAMBDA) {
+            kvs.push(Keyword("arglists".to_string()));
+            match arities(&f.rest().to_vec()) {
+                Some(List(clauses, _)) => kvs.push(list!(clauses.iter().filter_map(|c| match c {
+                    List(c, _) => Some(c[0].clone()),
+                    _ => None,
+                }).collect())),
+                _ => kvs.push(list!(vec![f[1].clone()])),
+            }
+        }
+    }
+    if let Some(d) = doc {
+        kvs.push(Keyword("doc".to_string()));
+        kvs.push(d.clone());
+    }
+    if kvs.is_empty() {
+        return None;
+    }
+    kvs.push(Keyword("name".to_string()));
+    kvs.push(name.clone());
+    types::hash_map(kvs).ok()
+}
+
+// 动态变量当前的值 内核中的函数用它读取 *out* *print-length* 这样的变量
+// id 是预先登记的符号的编号 例如 symbol::OUT
+pub fn dynamic(id: u32) -> Option<MalVal> {
+    let name = Symbol::known(id);
+    let env = env_dynamic_env(&name)?;
+    env_get(&env, &Sym(name)).ok()
+}
+
+// (def-dynamic *x* value) 和 def! 一样定义变量 之后可以用 binding 重新绑定
+fn def_dynamic(args: &[MalVal], env: &Env) -> MalRet {
+    let name = match args {
+        [Sym(s), _] => s.clone(),
+        _ => return error("def-dynamic expects a symbol and a value"),
+    };
+    let v = eval(args[1].clone(), env.clone())?;
+    let v = env_set(env, args[0].clone(), v)?;
+    env_def_dynamic(env, &name);
+    Ok(v)
+}
+
+// (binding [*x* 1 *y* 2] body ...) 执行 body 期间动态变量取新的值
+// 新的值压在变量的栈上 环境中的值不变 body 中 def! 修改的值在结束后仍然有效
+// 结束时弹出新的值 出错或者 continuation 跳出时也一样
+fn binding(args: &[MalVal], env: &Env) -> MalRet {
+    let binds = match args.first() {
+        Some(List(b, _)) | Some(Vector(b, _)) if b.len() % 2 == 0 => b.to_vec(),
+        _ => return error("binding requires a vector of symbol value pairs"),
+    };
+    // 先求出所有的值 再一起绑定
+    let mut news = vec![];
+    for pair in binds.chunks(2) {
+        let name = match &pair[0] {
+            Sym(s) => s.clone(),
+            _ => return error("binding requires a vector of symbol value pairs"),
+        };
+        // 绑定的是这里能看到的那个变量
+        let denv = match env_find(env, &name) {
+            Some(e) if env_is_dynamic(&e, &name) => e,
+            _ => return error(&format!("{} is not a dynamic variable", name)),
+        };
+        news.push((denv, name, eval(pair[1].clone(), env.clone())?));
+    }
+    for (denv, name, v) in news.iter() {
+        env_push_binding(denv, name, v.clone());
+    }
+    let mut res = Ok(Nil);
+    for form in args[1..].iter() {
+        res = eval(form.clone(), env.clone());
+        if res.is_err() {
+            break;
+        }
+    }
+    for (denv, name, _) in news.iter().rev() {
+        env_pop_binding(denv, name);
+    }
+    res
+}
+
+// 特殊形式的参数个数不对时报错 而不是在取参数时 panic
+fn check_form(name: &str, l: &Seq, min: usize, max: Option<usize>) -> Result<(), MalErr> {
+    let n = l.len() - 1;
+    if n < min || max.map_or(false, |max| n > max) {
+        return Err(arity_error(name, min, max, n));
+    }
+    Ok(())
+}
+
+// 宏展开后的代码没有位置信息 沿用展开前代码的位置
+fn keep_location(new_ast: MalVal, old: &MalVal) -> MalVal {
+    match (new_ast, old) {
+        (List(v, meta), List(_, old_meta)) if *meta == Nil => List(v, old_meta.clone()),
+        (a, _) => a,
+    }
+}
+
+// 求值的 TCO 循环 ast 始终指向当前正在求值的代码
+// depth 是进入时调用栈的深度 尾调用会替换掉这一层压入的调用帧
+fn eval_form(ast: &mut MalVal, mut env: Env, depth: usize) -> MalRet {
     let ret:MalRet;
     'tco: loop {
         ret = match ast.clone(){
             List(l,_)=>{
                 if l.len() == 0 {
-                    return Ok(ast);
+                    return Ok(ast.clone());
                 }
                 // 展开尝试并且求值
                 match macroexpand(ast.clone(), &env) {
                     (true,Ok(new_ast)) => {
-                        ast = new_ast;
+                        *ast = keep_location(new_ast, ast);
                         continue 'tco;
                     }
                     (_,Err(e)) => return Err(e),
                     _ => (), // 理论上不会到这个分支
                 }
-                let a0 = &l[0];
-                match a0 {
-                    Sym(ref a0sym) if a0sym == "def!" => {
-                        env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
+                // 特殊形式按符号的编号分派
+                let head = match &l[0] {
+                    Sym(s) => Some(s.id()),
+                    _ => None,
+                };
+                match head {
+                    Some(symbol::DEF) => {
+                        check_form("def!", &l, 2, Some(3))?;
+                        // (def! name "doc" value) 中的字符串是文档
+                        let doc = match (l.len(), &l[2]) {
+                            (4, Str(_)) => Some(&l[2]),
+                            _ => None,
+                        };
+                        let form = if doc.is_some() { &l[3] } else { &l[2] };
+                        let mut v = eval(form.clone(), env.clone())?;
+                        if let Some(m) = def_meta(&l[1], doc, form) {
+                            v = v.add_meta(&m)?;
+                        }
+                        env_set(&env, l[1].clone(), v)
                     },
-                    Sym(ref a0sym) if a0sym == "let*" => {
+                    Some(symbol::DEF_DYNAMIC) => def_dynamic(&l.rest().to_vec(), &env),
+                    Some(symbol::BINDING) => binding(&l.rest().to_vec(), &env),
+                    Some(symbol::LET) => {
                         // 对let* 语法进行支持
-                        env = env_new(Some(env.clone()));
+                        check_form("let*", &l, 2, None)?;
+                        env = env_local(env.clone());
                         let (a1,a2) = (l[1].clone(),l[2].clone());
                         match a1 {
                             List(ref binds,_) | Vector(ref binds,_) => {
@@ -125,11 +342,8 @@ fn eval(mut ast: MalVal,mut env: Env) -> MalRet {
                                         Some(b) =>{
                                             match binds_iter.next() {
                                                 Some(e) => {
-                                                    let _ = env_set(
-                                                        &env,
-                                                        b.clone(),
-                                                        eval(e.clone(), env.clone())?
-                                                    );
+                                                    // 绑定的可以是解构的模式
+                                                    pattern::bind(&env, b, eval(e.clone(), env.clone())?)?;
                                                 },
                                                 None => {
                                                     return error("let* with non-Sym binding");
@@ -146,12 +360,21 @@ fn eval(mut ast: MalVal,mut env: Env) -> MalRet {
                                 return error("let* with non-List bindings");
                             },
                         }
-                        ast = a2;
+                        *ast = a2;
                         continue 'tco;
                     }
                     // 定义闭包函数的语法
-                    Sym(a0sym) if a0sym == "lambda" => {
-                        let (a1,a2) = (l[1].clone(),l[2].clone());
+                    Some(symbol::LAMBDA) => {
+                        let (a1,a2) = match arities(&l.rest().to_vec()) {
+                            Some(clauses) => (Nil, clauses),
+                            None => {
+                                check_form("lambda", &l, 2, None)?;
+                                match l[1] {
+                                    List(..) | Vector(..) => (l[1].clone(),l[2].clone()),
+                                    _ => return Err(expected("lambda", "parameter vector", &l[1])),
+                                }
+                            }
+                        };
                         Ok(MalFunc {
                             eval: eval,
                             ast: Rc::new(a2),
@@ -161,65 +384,62 @@ fn eval(mut ast: MalVal,mut env: Env) -> MalRet {
                             meta: Rc::new(Nil),
                         })
                     },
-                    Sym(ref a0sym) if a0sym == "if" => {
+                    Some(symbol::IF) => {
+                        check_form("if", &l, 2, Some(3))?;
                         let cond = eval(l[1].clone(), env.clone())?;
                         match cond {
                             Bool(false) | Nil if l.len() >= 4 => {
-                                ast = l[3].clone();
+                                *ast = l[3].clone();
                                 continue 'tco;
                             },
                             Bool(false) | Nil => Ok(Nil),
                             _ if l.len() >= 3 => {
-                                ast = l[2].clone();
+                                *ast = l[2].clone();
                                 continue 'tco;
                             },
                             _ => Ok(Nil),
                         }
                     },
-                    Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..].to_vec()),&env)?{
-                            List(_,_) => {
-                                ast = l.last().unwrap_or(&Nil).clone();
-                                continue 'tco;
-                            }
-                            _ => error("invalid do form"),
+                    Some(symbol::DO) => {
+                        // 最后一个表达式留给尾调用求值 不能在这里先求值一次
+                        for a in l.iter().skip(1).take(l.len().max(2) - 2) {
+                            eval(a.clone(), env.clone())?;
                         }
+                        *ast = l.last().filter(|_| l.len() > 1).cloned().unwrap_or(Nil);
+                        continue 'tco;
                     }
-                    Sym(ref a0sym) if a0sym == "quote" => Ok(l[1].clone()),
-                    Sym(ref a0sym) if a0sym == "quasiquote" => {
-                        ast = quasiquote(&l[1]);
+                    Some(symbol::QUOTE) => {
+                        check_form("quote", &l, 1, Some(1))?;
+                        Ok(l[1].clone())
+                    },
+                    Some(symbol::QUASIQUOTE) => {
+                        check_form("quasiquote", &l, 1, Some(1))?;
+                        *ast = quasiquote(&l[1]);
                         continue 'tco;
                     },
-                    Sym(ref a0sym) if a0sym == "eval" =>{
-                        ast = eval(l[1].clone(), env.clone())?;
+                    Some(symbol::EVAL) =>{
+                        check_form("eval", &l, 1, Some(1))?;
+                        *ast = eval(l[1].clone(), env.clone())?;
                         while let Some(ref e) = env.clone().outer {
                             env = e.clone();
                         }
                         continue 'tco;
                     },
+                    Some(symbol::MATCH) => {
+                        let (body, menv) = pattern::match_form(&l.rest().to_vec(), &env)?;
+                        env = menv;
+                        *ast = body;
+                        continue 'tco;
+                    }
+                    Some(symbol::NS) => module::ns(&l.rest().to_vec(), &env),
+                    Some(symbol::REQUIRE) => module::require(&l.rest().to_vec(), &env),
+                    Some(symbol::IMPORT) => module::import(&l.rest().to_vec(), &env),
+                    Some(symbol::RELOAD) => module::reload(&l.rest().to_vec(), &env),
                     // todo 这里实现其他的符号逻辑
-                    Sym(ref a0sym) if a0sym == "try*" => match eval(l[1].clone(), env.clone()) {
-                        Err(ref e) if l.len() >= 3 => {
-                            let exc = match e {
-                                ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
-                            };
-                            match l[2].clone() {
-                                List(c,_) => {
-                                    let catch_env = env_bind(
-                                        Some(env.clone()),
-                                        list![vec![c[1].clone()]],
-                                        vec![exc],
-                                    )?;
-                                    eval(c[2].clone(), catch_env)
-                                },
-                                _ => error("invalid catch b,lock"),
-                            }
-                        }
-                        res => res,
-                    },
+                    Some(symbol::TRY) => exception::try_form(&l.rest().to_vec(), &env),
                     // 进行宏定义
-                    Sym(ref a0sym) if a0sym == "defmacro!" => {
+                    Some(symbol::DEFMACRO) => {
+                        check_form("defmacro!", &l, 2, Some(2))?;
                         let (a1,a2) = (l[1].clone(),l[2].clone());
                         let r = eval(a2, env.clone())?;
                         match r {
@@ -228,6 +448,7 @@ fn eval(mut ast: MalVal,mut env: Env) -> MalRet {
                                 ast,
                                 env,
                                 params,
+                                meta,
                                 ..
                             }=> Ok(env_set(&env, a1.clone(), MalFunc {
                                 eval:eval,
@@ -235,36 +456,41 @@ fn eval(mut ast: MalVal,mut env: Env) -> MalRet {
                                 env:env.clone(),
                                 params:params.clone(),
                                 is_macro:true,
-                                meta: Rc::new(Nil),
-                                // mate 的作用是什么？
+                                meta: meta.clone(),
                             })?),
                             _ => error("set_macro on non-function"),
                         }
                     },
                     // 进行宏展开
-                    Sym(ref a0sym) if a0sym == "macroexpand" => {
+                    Some(symbol::MACROEXPAND) => {
+                        check_form("macroexpand", &l, 1, Some(1))?;
                         match macroexpand(l[1].clone(), &env) {
                             (_, Ok(new_ast)) => Ok(new_ast),
                             (_, e) => return e,
                         }
                     },
-                    _ => match eval_ast(&ast, &env)? {
+                    _ => match eval_ast(ast, &env)? {
                         List(ref el, _) => {
                             let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
+                            let args: MalArgs = el.iter().skip(1).cloned().collect();
                             match f {
-                                Func(_,_) => f.apply(args),
-                                MalFunc{
-                                    ast: mast,
-                                    env: menv,
-                                    params,
-                                    ..
-                                } => {
-                                    let a = &**mast;
-                                    let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
-                                    ast = a.clone();
-                                    continue 'tco;
+                                Func(_,_) | Native(_,_) | Cont(_) | Closure(_,_) => {
+                                    trace::push(ast.clone());
+                                    f.apply(args)
+                                },
+                                MalFunc{ .. } => {
+                                    // 尾调用 替换掉这一层之前压入的调用帧
+                                    trace::truncate(depth);
+                                    trace::push(ast.clone());
+                                    // 足够热的函数由 JIT 编译成机器码执行
+                                    if let Some(v) = jit::call(f, &args) {
+                                        Ok(v)
+                                    } else {
+                                        let (body, fn_env) = f.fn_env(args)?;
+                                        env = fn_env;
+                                        *ast = body;
+                                        continue 'tco;
+                                    }
                                 },
                                 _ => error("attempt to call non-function"),
                             }
@@ -273,7 +499,7 @@ fn eval(mut ast: MalVal,mut env: Env) -> MalRet {
                     }
                 }
             },
-            _ => eval_ast(&ast, &env),
+            _ => eval_ast(ast, &env),
             };
             break 'tco;
     }
@@ -287,7 +513,11 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
         List(v,_) => {
             let mut lst:MalArgs = vec![];
             for a in v.iter() {
-                lst.push(eval(a.clone(),env.clone())?)
+                match a {
+                    // 直接用代码中的符号查找 记下的槽位留在代码里 下次求值时可以直接用
+                    Sym(_) => lst.push(env_get(env, a)?),
+                    _ => lst.push(eval(a.clone(),env.clone())?),
+                }
             }
             Ok(list!(lst))
         },
@@ -299,11 +529,18 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         },
         Hash(hm,_) => {
-            let mut new_hm:HashMap<String,MalVal> = HashMap::default();
+            let mut new_hm = PMap::new();
             for (k,v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(),env.clone())?);
+                new_hm = new_hm.insert(eval(k.clone(),env.clone())?, eval(v.clone(),env.clone())?);
+            }
+            Ok(Hash(new_hm,Rc::new(Nil)))
+        },
+        Set(s,_) => {
+            let mut new_set = PSet::new();
+            for x in s.iter() {
+                new_set = new_set.insert(eval(x.clone(),env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm),Rc::new(Nil)))
+            Ok(Set(new_set,Rc::new(Nil)))
         },
         _ => Ok(ast.clone()),
     }
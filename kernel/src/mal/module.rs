@@ -0,0 +1,260 @@
+// jmal 的模块系统
+// 每个 .jmal 文件是一个模块 有自己的环境 这个环境的外层是全局环境
+// (ns name :export [a b]) 声明模块名和导出的符号 只有导出的符号可以被其他模块访问
+// (require name) (require name :as alias) 加载模块 之后可以用 name/a 或者 alias/a 访问导出的符号
+// (import name a b) 加载模块 并把导出的 a b 绑定到当前环境
+// (reload name) 重新加载模块
+// 模块只会加载一次 之后的 require 直接使用缓存
+use crate::format;
+use crate::fs::{inode_ext::INodeExt, ROOT_INODE};
+use crate::mal::env::{env_get_local, env_new, env_sets, Env};
+use crate::mal::vm;
+use crate::mal::reader::read_str_in;
+use crate::mal::exception;
+use crate::mal::types::MalErr::ErrString;
+use crate::mal::types::MalVal::{Hash, Keyword, List, Nil, Str, Sym, Vector};
+use crate::mal::types::{error, MalErr, MalRet, MalVal};
+use crate::mal::pmap::PMap;
+use crate::mal::symbol::{self, Symbol};
+use crate::mal::global::Global;
+use crate::vector;
+use crate::vec;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use hashbrown::HashMap;
+
+// 已经加载的模块 (模块名, 模块的环境和哈希) 为 None 表示正在加载 用来发现循环依赖
+static MODULES: Global<Vec<(String, Option<(Env, u64)>)>> = Global::new(Vec::new());
+
+// 每个正在加载的模块 require 的模块和它们的哈希 写进字节码缓存 用来判断缓存是否过期
+static DEPS: Global<Vec<Vec<(String, u64)>>> = Global::new(Vec::new());
+
+fn cached(name: &str) -> Option<Option<(Env, u64)>> {
+    MODULES.borrow().iter().find(|(n, _)| n == name).map(|(_, e)| e.clone())
+}
+
+fn set_cached(name: &str, module: Option<(Env, u64)>) {
+    let mut modules = MODULES.borrow_mut();
+    modules.retain(|(n, _)| n != name);
+    if let Some(m) = module {
+        modules.push((name.to_string(), Some(m)));
+    }
+}
+
+// 开始和结束记录一个模块 require 的模块
+pub fn push_deps() {
+    DEPS.borrow_mut().push(Vec::new());
+}
+
+pub fn pop_deps() -> Vec<(String, u64)> {
+    DEPS.borrow_mut().pop().unwrap_or_default()
+}
+
+pub fn current_deps() -> Vec<(String, u64)> {
+    DEPS.borrow().last().cloned().unwrap_or_default()
+}
+
+fn record_dep(name: &str, hash: u64) {
+    if let Some(deps) = DEPS.borrow_mut().last_mut() {
+        if !deps.iter().any(|(n, _)| n == name) {
+            deps.push((name.to_string(), hash));
+        }
+    }
+}
+
+// 缓存中记录的模块加载后哈希都没有变时返回 true 加载失败时重新编译 由编译报告错误
+pub fn deps_unchanged(deps: &[(String, u64)], env: &Env) -> bool {
+    push_deps();
+    let same = deps.iter().all(|(name, hash)| match load(name, env) {
+        Ok((_, h)) => h == *hash,
+        Err(_) => false,
+    });
+    pop_deps();
+    same
+}
+
+fn mark_loading(name: &str) {
+    let mut modules = MODULES.borrow_mut();
+    modules.retain(|(n, _)| n != name);
+    modules.push((name.to_string(), None));
+}
+
+// 模块名可以是符号或者字符串 例如 core 或者 "lib/list"
+fn module_name(v: &MalVal) -> Result<String, MalErr> {
+    match v {
+        Sym(s) => Ok(s.to_string()),
+        Str(s) => Ok(s.to_string()),
+        _ => Err(ErrString("module name must be a symbol or string".to_string())),
+    }
+}
+
+// 默认的别名是模块路径的最后一段 lib/list 的别名是 list
+fn default_alias(name: &str) -> String {
+    name.rsplit('/').next().unwrap_or(name).to_string()
+}
+
+fn root_env(env: &Env) -> Env {
+    let mut root = env.clone();
+    while let Some(ref e) = root.clone().outer {
+        root = e.clone();
+    }
+    root
+}
+
+// 在新的环境中执行模块文件
+fn load_fresh(name: &str, env: &Env, use_cache: bool) -> Result<(Env, u64), MalErr> {
+    let path = format!("{}.jmal", name);
+    let content = match ROOT_INODE.lookup(path.as_str()) {
+        Ok(inode) => match inode.read_as_string() {
+            Ok(s) => s,
+            Err(_) => return Err(exception::raise("io", format!("cannot read module '{}'", name))),
+        },
+        Err(_) => return Err(exception::raise("io", format!("module '{}' not found", name))),
+    };
+    let forms = match read_str_in(format!("(do {}\nnil)", content), Some(path.clone()))? {
+        List(l, _) => l.iter().skip(1).take(l.len() - 2).cloned().collect::<Vec<_>>(),
+        _ => unreachable!(),
+    };
+    let menv = env_new(Some(root_env(env)));
+    // 重新加载失败时保留原来的模块
+    let old = cached(name).flatten();
+    mark_loading(name);
+    // 模块用虚拟机执行 编译结果缓存在磁盘上
+    match vm::run_file(&path, &content, &forms, &menv, use_cache) {
+        Ok(hash) => {
+            set_cached(name, Some((menv.clone(), hash)));
+            Ok((menv, hash))
+        }
+        Err(e) => {
+            set_cached(name, old);
+            Err(e)
+        }
+    }
+}
+
+// 加载模块 已经加载过的直接返回缓存 并记录到正在加载的模块的依赖中
+fn load(name: &str, env: &Env) -> Result<(Env, u64), MalErr> {
+    let (menv, hash) = match cached(name) {
+        Some(Some(m)) => m,
+        Some(None) => return Err(ErrString(format!("circular require of module '{}'", name))),
+        None => load_fresh(name, env, true)?,
+    };
+    record_dep(name, hash);
+    Ok((menv, hash))
+}
+
+// 在当前环境的 *aliases* 中记录 alias -> 模块名
+fn add_alias(env: &Env, alias: &str, name: &str) {
+    let mut aliases = match env_get(env, symbol::ALIASES) {
+        Some(Hash(hm, _)) => hm,
+        _ => PMap::new(),
+    };
+    let aliases = aliases.insert(Str(alias.to_string()), Str(name.to_string()));
+    env_sets(env, "*aliases*", Hash(aliases, Rc::new(Nil)));
+}
+
+fn env_get(env: &Env, key: u32) -> Option<MalVal> {
+    crate::mal::env::env_get(env, &Sym(Symbol::known(key))).ok()
+}
+
+// 取出模块导出的符号 没有导出时报错
+fn exported(name: &str, menv: &Env, sym: &str) -> MalRet {
+    let key = Symbol::new(sym);
+    let is_exported = match env_get_local(menv, &Symbol::known(symbol::EXPORTS)) {
+        Some(Vector(v, _)) => v.iter().any(|e| match e {
+            Sym(s) => *s == key,
+            _ => false,
+        }),
+        _ => false,
+    };
+    if !is_exported {
+        return error(&format!("'{}' is not exported by module '{}'", sym, name));
+    }
+    match env_get_local(menv, &key) {
+        Some(v) => Ok(v),
+        None => error(&format!("'{}' is exported but not defined by module '{}'", sym, name)),
+    }
+}
+
+// (ns name :export [a b])
+pub fn ns(a: &[MalVal], env: &Env) -> MalRet {
+    if a.len() < 1 {
+        return error("ns requires a module name");
+    }
+    let name = module_name(&a[0])?;
+    let mut exports = vec![];
+    let mut opts = a[1..].iter();
+    while let Some(k) = opts.next() {
+        match (k, opts.next()) {
+            (Keyword(k), Some(Vector(v, _))) if k == "export" => exports.extend(v.iter().cloned()),
+            _ => return error("ns options must be :export [symbols]"),
+        }
+    }
+    env_sets(env, "*ns*", Str(name));
+    env_sets(env, "*exports*", vector!(exports));
+    Ok(Nil)
+}
+
+// (require name) (require name :as alias)
+pub fn require(a: &[MalVal], env: &Env) -> MalRet {
+    let name = match a {
+        [n] | [n, Keyword(_), Sym(_)] => module_name(n)?,
+        _ => return error("require expects (require name) or (require name :as alias)"),
+    };
+    let alias = match a {
+        [_, Keyword(k), Sym(s)] if k == "as" => s.to_string(),
+        [_] => default_alias(&name),
+        _ => return error("require expects (require name) or (require name :as alias)"),
+    };
+    load(&name, env)?;
+    add_alias(env, &alias, &name);
+    Ok(Sym(Symbol::new(&name)))
+}
+
+// (import name a b)
+pub fn import(a: &[MalVal], env: &Env) -> MalRet {
+    if a.len() < 1 {
+        return error("import requires a module name");
+    }
+    let name = module_name(&a[0])?;
+    let (menv, _) = load(&name, env)?;
+    add_alias(env, &default_alias(&name), &name);
+    for s in a[1..].iter() {
+        match s {
+            Sym(s) => env_sets(env, s, exported(&name, &menv, s)?),
+            _ => return error("import expects symbols"),
+        }
+    }
+    Ok(Sym(Symbol::new(&name)))
+}
+
+// (reload name) 重新编译并执行模块文件 通过 name/a 访问的符号会看到新的定义
+// 之前 import 进来的绑定不会改变
+pub fn reload(a: &[MalVal], env: &Env) -> MalRet {
+    if a.len() != 1 {
+        return error("reload requires a module name");
+    }
+    let name = module_name(&a[0])?;
+    load_fresh(&name, env, false)?;
+    Ok(Sym(Symbol::new(&name)))
+}
+
+// 查找 alias/sym 形式的符号 符号本身不在环境中时才会走到这里
+pub fn resolve(env: &Env, key: &str) -> Option<MalRet> {
+    let (alias, sym) = match key.find('/') {
+        Some(i) if i > 0 && i + 1 < key.len() => (&key[..i], &key[i + 1..]),
+        _ => return None,
+    };
+    let name = match env_get(env, symbol::ALIASES) {
+        Some(Hash(hm, _)) => match hm.get(&Str(alias.to_string())) {
+            Some(Str(n)) => n.to_string(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    match cached(&name).flatten() {
+        Some((menv, _)) => Some(exported(&name, &menv, sym)),
+        None => Some(error(&format!("module '{}' is not loaded", name))),
+    }
+}
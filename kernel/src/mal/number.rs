@@ -0,0 +1,248 @@
+// mal 的数字塔 Int -> Big -> Ratio -> Float
+// 两个数字运算时 提升到两者中较高的一级再计算 结果再尽量降回 Int
+use crate::format;
+use crate::mal::types::MalVal::{Big, Float, Int, Ratio};
+use crate::mal::types::{error, MalErr, MalRet, MalVal};
+use alloc::rc::Rc;
+use alloc::string::ToString;
+use core::cmp::Ordering;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, ToPrimitive, Zero};
+
+use crate::mal::convert::expected;
+use crate::mal::exception;
+
+// 数字所在的层级 越大越“宽”
+#[derive(PartialEq, PartialOrd, Clone, Copy)]
+enum Level {
+    Int,
+    Big,
+    Ratio,
+    Float,
+}
+
+fn level(v: &MalVal) -> Result<Level, MalErr> {
+    match v {
+        Int(_) => Ok(Level::Int),
+        Big(_) => Ok(Level::Big),
+        Ratio(_) => Ok(Level::Ratio),
+        Float(_) => Ok(Level::Float),
+        _ => Err(exception::raise("type", "expecting number args".to_string())),
+    }
+}
+
+// 大整数如果可以放进 i64 就还原成 Int
+pub fn from_bigint(b: BigInt) -> MalVal {
+    match b.to_i64() {
+        Some(i) => Int(i),
+        None => Big(Rc::new(b)),
+    }
+}
+
+// 分母为 1 的分数还原成整数
+pub fn from_ratio(r: BigRational) -> MalVal {
+    if r.is_integer() {
+        from_bigint(r.to_integer())
+    } else {
+        Ratio(Rc::new(r))
+    }
+}
+
+fn to_bigint(v: &MalVal) -> BigInt {
+    match v {
+        Int(i) => BigInt::from(*i),
+        Big(b) => (**b).clone(),
+        _ => unreachable!(),
+    }
+}
+
+fn to_ratio(v: &MalVal) -> BigRational {
+    match v {
+        Int(_) | Big(_) => BigRational::from_integer(to_bigint(v)),
+        Ratio(r) => (**r).clone(),
+        _ => unreachable!(),
+    }
+}
+
+pub fn to_f64(v: &MalVal) -> f64 {
+    match v {
+        Int(i) => *i as f64,
+        Big(b) => b.to_f64().unwrap_or(f64::NAN),
+        Ratio(r) => r.to_f64().unwrap_or(f64::NAN),
+        Float(f) => *f,
+        _ => f64::NAN,
+    }
+}
+
+// 通用的四则运算 int 溢出时提升为大整数
+fn arith(
+    x: &MalVal,
+    y: &MalVal,
+    int_op: fn(i64, i64) -> Option<i64>,
+    big_op: fn(BigInt, BigInt) -> BigInt,
+    ratio_op: fn(BigRational, BigRational) -> BigRational,
+    float_op: fn(f64, f64) -> f64,
+) -> MalRet {
+    let lv = if level(x)? > level(y)? { level(x)? } else { level(y)? };
+    Ok(match (lv, x, y) {
+        (Level::Int, Int(i), Int(j)) => match int_op(*i, *j) {
+            Some(r) => Int(r),
+            None => from_bigint(big_op(BigInt::from(*i), BigInt::from(*j))),
+        },
+        (Level::Big, _, _) => from_bigint(big_op(to_bigint(x), to_bigint(y))),
+        (Level::Ratio, _, _) => from_ratio(ratio_op(to_ratio(x), to_ratio(y))),
+        _ => Float(float_op(to_f64(x), to_f64(y))),
+    })
+}
+
+pub fn add(x: &MalVal, y: &MalVal) -> MalRet {
+    arith(x, y, |i, j| i.checked_add(j), |a, b| a + b, |a, b| a + b, |f, g| f + g)
+}
+
+pub fn sub(x: &MalVal, y: &MalVal) -> MalRet {
+    arith(x, y, |i, j| i.checked_sub(j), |a, b| a - b, |a, b| a - b, |f, g| f - g)
+}
+
+pub fn mul(x: &MalVal, y: &MalVal) -> MalRet {
+    arith(x, y, |i, j| i.checked_mul(j), |a, b| a * b, |a, b| a * b, |f, g| f * g)
+}
+
+fn is_exact_zero(v: &MalVal) -> bool {
+    match v {
+        Int(0) => true,
+        Big(b) => b.is_zero(),
+        Ratio(r) => r.is_zero(),
+        _ => false,
+    }
+}
+
+// 整数相除不能整除时得到精确的分数 精确的数除以 0 返回错误
+// 有浮点数参与时按 IEEE 754 计算 除以 0 得到 ##Inf ##-Inf 或 ##NaN
+pub fn div(x: &MalVal, y: &MalVal) -> MalRet {
+    let lv = if level(x)? > level(y)? { level(x)? } else { level(y)? };
+    if lv != Level::Float && is_exact_zero(y) {
+        return error("divide by zero");
+    }
+    match (lv, x, y) {
+        (Level::Int, Int(i), Int(j)) if i.checked_rem(*j) == Some(0) => match i.checked_div(*j) {
+            Some(r) => Ok(Int(r)),
+            None => Ok(from_bigint(BigInt::from(*i) / BigInt::from(*j))),
+        },
+        (Level::Int, _, _) | (Level::Big, _, _) => {
+            Ok(from_ratio(BigRational::new(to_bigint(x), to_bigint(y))))
+        }
+        (Level::Ratio, _, _) => Ok(from_ratio(to_ratio(x) / to_ratio(y))),
+        _ => Ok(Float(to_f64(x) / to_f64(y))),
+    }
+}
+
+// 整数的除法 取余 取模 只接受整数
+fn int_div(
+    name: &str,
+    x: &MalVal,
+    y: &MalVal,
+    int_op: fn(i64, i64) -> Option<i64>,
+    big_op: fn(&BigInt, &BigInt) -> BigInt,
+) -> MalRet {
+    match (x, y) {
+        (_, Int(0)) => error("divide by zero"),
+        (Int(i), Int(j)) => match int_op(*i, *j) {
+            Some(r) => Ok(Int(r)),
+            None => Ok(from_bigint(big_op(&BigInt::from(*i), &BigInt::from(*j)))),
+        },
+        (Int(_), Big(_)) | (Big(_), Int(_)) | (Big(_), Big(_)) => {
+            Ok(from_bigint(big_op(&to_bigint(x), &to_bigint(y))))
+        }
+        (Int(_), _) | (Big(_), _) => Err(expected(name, "integer", y)),
+        _ => Err(expected(name, "integer", x)),
+    }
+}
+
+pub fn quot(x: &MalVal, y: &MalVal) -> MalRet {
+    int_div("quot", x, y, |i, j| i.checked_div(j), |a, b| a / b)
+}
+
+pub fn rem(x: &MalVal, y: &MalVal) -> MalRet {
+    int_div("rem", x, y, |i, j| i.checked_rem(j), |a, b| a % b)
+}
+
+// 取模 结果的符号和除数一致
+pub fn modulo(x: &MalVal, y: &MalVal) -> MalRet {
+    int_div(
+        "mod",
+        x,
+        y,
+        |i, j| i.checked_rem(j).map(|_| i.mod_floor(&j)),
+        |a, b| a.mod_floor(b),
+    )
+}
+
+// 比较两个数字 出现 NaN 时返回 None
+pub fn cmp(x: &MalVal, y: &MalVal) -> Result<Option<Ordering>, MalErr> {
+    let lv = if level(x)? > level(y)? { level(x)? } else { level(y)? };
+    Ok(match (lv, x, y) {
+        (Level::Int, Int(i), Int(j)) => Some(i.cmp(j)),
+        (Level::Big, _, _) => Some(to_bigint(x).cmp(&to_bigint(y))),
+        (Level::Ratio, _, _) => Some(to_ratio(x).cmp(&to_ratio(y))),
+        _ => to_f64(x).partial_cmp(&to_f64(y)),
+    })
+}
+
+// 向零取整
+pub fn to_integer(v: &MalVal) -> MalRet {
+    match v {
+        Int(_) | Big(_) => Ok(v.clone()),
+        Ratio(r) => Ok(from_bigint(r.to_integer())),
+        Float(f) if f.is_finite() => match f.to_i64() {
+            Some(i) => Ok(Int(i)),
+            None => Ok(from_bigint(BigInt::from_f64(*f).unwrap_or_default())),
+        },
+        Float(_) => error("cannot convert NaN or infinity to int"),
+        _ => Err(expected("int", "number", v)),
+    }
+}
+
+pub fn numerator(v: &MalVal) -> MalRet {
+    match v {
+        Int(_) | Big(_) => Ok(v.clone()),
+        Ratio(r) => Ok(from_bigint(r.numer().clone())),
+        _ => Err(expected("numerator", "rational number", v)),
+    }
+}
+
+pub fn denominator(v: &MalVal) -> MalRet {
+    match v {
+        Int(_) | Big(_) => Ok(Int(1)),
+        Ratio(r) => Ok(from_bigint(r.denom().clone())),
+        _ => Err(expected("denominator", "rational number", v)),
+    }
+}
+
+// 解析十进制整数 超出 i64 的部分用大整数表示
+pub fn parse_integer(s: &str) -> Option<MalVal> {
+    if let Ok(i) = s.parse::<i64>() {
+        return Some(Int(i));
+    }
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(d) => (true, d),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let b = BigInt::parse_bytes(digits.as_bytes(), 10)?;
+    Some(from_bigint(if neg { -b } else { b }))
+}
+
+// 解析分数 例如 1/3 -2/4
+pub fn parse_ratio(numer: &str, denom: &str) -> MalRet {
+    match (parse_integer(numer), parse_integer(denom)) {
+        (Some(n), Some(d)) => {
+            if is_exact_zero(&d) {
+                return error("divide by zero");
+            }
+            Ok(from_ratio(BigRational::new(to_bigint(&n), to_bigint(&d))))
+        }
+        _ => error(&format!("invalid ratio literal: {}/{}", numer, denom)),
+    }
+}
+
@@ -0,0 +1,233 @@
+// 解构绑定和模式匹配
+// let* 和 lambda 的参数可以是向量 [a [b c] & more :as all] 或者 map {:keys [x y] :or {y 0} :as m}
+// (match expr 模式 结果 ...) 依次尝试每个模式 模式后面可以跟 :when 条件
+use crate::format;
+use crate::list;
+use crate::mal::env::{env_local, env_set, Env};
+use crate::mal::lazy;
+use crate::mal::symbol;
+use crate::mal::pmap::PMap;
+use crate::mal::types::MalErr::ErrString;
+use crate::mal::types::MalVal::{Bool, Hash, Keyword, Lazy, List, Nil, Str, Sym, Vector};
+use crate::mal::types::{hash_map, MalErr, MalVal};
+use crate::vec;
+use alloc::rc::Rc;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+fn is_sym(v: &MalVal, name: &str) -> bool {
+    match v {
+        Sym(s) => s == name,
+        _ => false,
+    }
+}
+
+fn is_keyword(v: &MalVal, name: &str) -> bool {
+    match v {
+        Keyword(s) => s == name,
+        _ => false,
+    }
+}
+
+fn truthy(v: &MalVal) -> bool {
+    match v {
+        Nil | Bool(false) => false,
+        _ => true,
+    }
+}
+
+// 把值按模式绑定到 env 中
+pub fn bind(env: &Env, pat: &MalVal, val: MalVal) -> Result<(), MalErr> {
+    match pat {
+        Sym(_) => env_set(env, pat.clone(), val).map(|_| ()),
+        List(p, _) | Vector(p, _) => bind_seq(env, &p.to_vec(), val),
+        Hash(p, _) => bind_map(env, p, val),
+        _ => Err(ErrString(format!("cannot bind {}", pat.pr_str(true)))),
+    }
+}
+
+// [a b & more :as all] 缺少的元素绑定为 nil
+fn bind_seq(env: &Env, pats: &[MalVal], val: MalVal) -> Result<(), MalErr> {
+    match val {
+        List(..) | Vector(..) | Lazy(_) | Nil => (),
+        _ => return Err(ErrString(format!("cannot destructure {} as a sequence", val.pr_str(true)))),
+    }
+    let mut rest = val.clone();
+    let mut i = 0;
+    while i < pats.len() {
+        if is_sym(&pats[i], "&") || is_keyword(&pats[i], "as") {
+            let p = match pats.get(i + 1) {
+                Some(p) => p,
+                None => return Err(ErrString(format!("{} must be followed by a pattern", pats[i].pr_str(true)))),
+            };
+            match &pats[i] {
+                Sym(_) if rest == Nil => bind(env, p, list![])?,
+                Sym(_) => bind(env, p, rest.clone())?,
+                _ => bind(env, p, val.clone())?,
+            }
+            i += 2;
+            continue;
+        }
+        let (x, r) = lazy::uncons(&rest)?.unwrap_or((Nil, Nil));
+        bind(env, &pats[i], x)?;
+        rest = r;
+        i += 1;
+    }
+    Ok(())
+}
+
+// {a :a :keys [b c] :strs [d] :syms [e] :or {b 0} :as m} 没有的 key 绑定为 nil 或 :or 中的默认值
+// 值是序列时当作 key value 交替的参数 例如 (lambda [& {:keys [a]}] a) 用 (f :a 1) 调用
+fn bind_map(env: &Env, pat: &PMap, val: MalVal) -> Result<(), MalErr> {
+    let val = match val {
+        List(..) | Vector(..) | Lazy(_) => match hash_map(lazy::to_vec(&val)?) {
+            Ok(m) => m,
+            Err(_) => return Err(ErrString("keyword arguments must be key value pairs".to_string())),
+        },
+        v => v,
+    };
+    let m = match val {
+        Hash(ref m, _) => m.clone(),
+        Nil => PMap::new(),
+        _ => return Err(ErrString(format!("cannot destructure {} as a map", val.pr_str(true)))),
+    };
+    let defaults = match pat.get(&Keyword("or".to_string())) {
+        Some(Hash(d, _)) => Some(d),
+        Some(_) => return Err(ErrString(":or requires a map".to_string())),
+        None => None,
+    };
+    for (k, v) in pat.iter() {
+        match k {
+            Keyword(kind) if kind == "keys" || kind == "strs" || kind == "syms" => {
+                let names = match v {
+                    List(n, _) | Vector(n, _) => n.to_vec(),
+                    _ => return Err(ErrString(format!(":{} requires a vector of symbols", kind))),
+                };
+                for name in names.iter() {
+                    let key = match name {
+                        Sym(s) if kind == "keys" => Keyword(s.to_string()),
+                        Sym(s) if kind == "strs" => Str(s.to_string()),
+                        Sym(_) => name.clone(),
+                        _ => return Err(ErrString(format!(":{} requires a vector of symbols", kind))),
+                    };
+                    bind_key(env, name, m.get(&key), defaults)?;
+                }
+            }
+            Keyword(kind) if kind == "as" => bind(env, v, val.clone())?,
+            Keyword(kind) if kind == "or" => (),
+            _ => bind_key(env, k, m.get(v), defaults)?,
+        }
+    }
+    Ok(())
+}
+
+// 默认值在绑定时求值 可以引用已经绑定的符号
+fn bind_key(env: &Env, pat: &MalVal, found: Option<&MalVal>, defaults: Option<&PMap>) -> Result<(), MalErr> {
+    let v = match (found, defaults.and_then(|d| d.get(pat))) {
+        (Some(v), _) => v.clone(),
+        (None, Some(d)) => super::eval(d.clone(), env.clone())?,
+        (None, None) => Nil,
+    };
+    bind(env, pat, v)
+}
+
+// (match expr 模式 结果 ...) 返回第一个匹配的结果代码 和绑定了模式中符号的环境
+pub fn match_form(args: &[MalVal], env: &Env) -> Result<(MalVal, Env), MalErr> {
+    if args.is_empty() {
+        return Err(ErrString("match requires an expression".to_string()));
+    }
+    let val = super::eval(args[0].clone(), env.clone())?;
+    let mut i = 1;
+    while i < args.len() {
+        let pat = &args[i];
+        let (guard, body) = match (args.get(i + 1), args.get(i + 2), args.get(i + 3)) {
+            (Some(w), Some(g), Some(b)) if is_keyword(w, "when") => {
+                i += 4;
+                (Some(g), b)
+            }
+            (Some(b), _, _) => {
+                i += 2;
+                (None, b)
+            }
+            _ => return Err(ErrString("match requires a result for every pattern".to_string())),
+        };
+        let mut binds = Vec::new();
+        // 最外层的 :else 和 _ 一样匹配任何值
+        if !is_keyword(pat, "else") && !matches(pat, &val, env, &mut binds)? {
+            continue;
+        }
+        let menv = env_local(env.clone());
+        for (s, v) in binds {
+            env_set(&menv, s, v)?;
+        }
+        match guard {
+            Some(g) if !truthy(&super::eval(g.clone(), menv.clone())?) => continue,
+            _ => return Ok((body.clone(), menv)),
+        }
+    }
+    Err(ErrString(format!("no match for {}", val.pr_str(true))))
+}
+
+// 模式:
+// _ 匹配任何值 符号匹配任何值并绑定
+// 数字 字符串 关键字 nil true false 和 'x 匹配相等的值
+// [a b & more] 匹配元素个数相同的列表 向量或惰性序列
+// {:k p} 匹配含有 :k 并且值匹配 p 的 map
+// (? pred p ...) 匹配 (pred x) 为真 并且匹配后面所有模式的值 例如 (? number? n)
+fn matches(pat: &MalVal, val: &MalVal, env: &Env, binds: &mut Vec<(MalVal, MalVal)>) -> Result<bool, MalErr> {
+    match pat {
+        Sym(s) if s.id() == symbol::UNDERSCORE => Ok(true),
+        Sym(_) => {
+            binds.push((pat.clone(), val.clone()));
+            Ok(true)
+        }
+        Vector(p, _) => match val {
+            List(..) | Vector(..) | Lazy(_) => matches_seq(&p.to_vec(), val, env, binds),
+            _ => Ok(false),
+        },
+        Hash(p, _) => match val {
+            Hash(m, _) => {
+                for (k, sub) in p.iter() {
+                    match m.get(k) {
+                        Some(v) if matches(sub, v, env, binds)? => (),
+                        _ => return Ok(false),
+                    }
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        },
+        List(l, _) if l.len() == 2 && is_sym(&l[0], "quote") => Ok(&l[1] == val),
+        List(l, _) if l.len() >= 2 && is_sym(&l[0], "?") => {
+            let pred = super::eval(l[1].clone(), env.clone())?;
+            if !truthy(&pred.apply(vec![val.clone()])?) {
+                return Ok(false);
+            }
+            for p in l.iter().skip(2) {
+                if !matches(p, val, env, binds)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        List(..) => Err(ErrString(format!("invalid match pattern {}", pat.pr_str(true)))),
+        _ => Ok(pat == val),
+    }
+}
+
+fn matches_seq(pats: &[MalVal], val: &MalVal, env: &Env, binds: &mut Vec<(MalVal, MalVal)>) -> Result<bool, MalErr> {
+    let mut rest = val.clone();
+    for (i, p) in pats.iter().enumerate() {
+        if is_sym(p, "&") {
+            return match pats.get(i + 1) {
+                Some(r) => matches(r, &rest, env, binds),
+                None => Err(ErrString("& must be followed by a pattern".to_string())),
+            };
+        }
+        match lazy::uncons(&rest)? {
+            Some((x, r)) if matches(p, &x, env, binds)? => rest = r,
+            _ => return Ok(false),
+        }
+    }
+    Ok(lazy::uncons(&rest)?.is_none())
+}
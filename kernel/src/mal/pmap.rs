@@ -0,0 +1,372 @@
+// 持久化的 hash map (HAMT) 和用它实现的集合
+// 按 key 的哈希每 5 位分一层 每个节点用位图记录有哪些子节点 修改时只复制从根到目标的路径
+// assoc dissoc 和查找都是 O(log32 n) 哈希完全相同的 key 放在同一个冲突节点中
+use crate::mal::types::MalVal;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+#[derive(Clone)]
+pub struct PMap {
+    root: Option<Rc<HNode>>,
+    len: usize,
+}
+
+enum HNode {
+    Branch(u32, Vec<Entry>), // 位图 和按位图顺序排列的子节点
+    Collision(u64, Vec<(MalVal, MalVal)>),
+}
+
+#[derive(Clone)]
+enum Entry {
+    Leaf(u64, MalVal, MalVal),
+    Node(Rc<HNode>),
+}
+
+// FNV-1a
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+fn hash_of(k: &MalVal) -> u64 {
+    let mut h = Fnv(0xcbf29ce484222325);
+    k.hash(&mut h);
+    h.finish()
+}
+
+fn bit(hash: u64, shift: u32) -> u32 {
+    1 << ((hash >> shift) & MASK)
+}
+
+fn slot(bitmap: u32, bit: u32) -> usize {
+    (bitmap & (bit - 1)).count_ones() as usize
+}
+
+impl PMap {
+    pub fn new() -> PMap {
+        PMap { root: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, k: &MalVal) -> Option<&MalVal> {
+        let hash = hash_of(k);
+        let mut node = self.root.as_ref()?;
+        let mut shift = 0;
+        loop {
+            match &**node {
+                HNode::Branch(bitmap, entries) => {
+                    let b = bit(hash, shift);
+                    if bitmap & b == 0 {
+                        return None;
+                    }
+                    match &entries[slot(*bitmap, b)] {
+                        Entry::Leaf(_, k2, v) => return if k2 == k { Some(v) } else { None },
+                        Entry::Node(n) => node = n,
+                    }
+                    shift += BITS;
+                }
+                HNode::Collision(_, pairs) => {
+                    return pairs.iter().find(|(k2, _)| k2 == k).map(|(_, v)| v);
+                }
+            }
+        }
+    }
+
+    pub fn contains_key(&self, k: &MalVal) -> bool {
+        self.get(k).is_some()
+    }
+
+    pub fn insert(&self, k: MalVal, v: MalVal) -> PMap {
+        let hash = hash_of(&k);
+        let (root, added) = match self.root {
+            Some(ref n) => insert(n, 0, hash, k, v),
+            None => (Rc::new(HNode::Branch(bit(hash, 0), alloc::vec![Entry::Leaf(hash, k, v)])), true),
+        };
+        PMap {
+            root: Some(root),
+            len: self.len + added as usize,
+        }
+    }
+
+    pub fn remove(&self, k: &MalVal) -> PMap {
+        let root = match self.root {
+            Some(ref n) => n,
+            None => return self.clone(),
+        };
+        match remove(root, 0, hash_of(k), k) {
+            None => self.clone(),
+            Some(root) => PMap {
+                root,
+                len: self.len - 1,
+            },
+        }
+    }
+
+    pub fn iter(&self) -> Iter {
+        Iter {
+            stack: self.root.iter().map(|n| (&**n, 0)).collect(),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &MalVal> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &MalVal> {
+        self.iter().map(|(_, v)| v)
+    }
+
+    // 遍历内部共享的节点 和 Seq::walk 一样
+    pub fn walk<'a>(&'a self, enter: &mut dyn FnMut(usize, usize) -> bool, visit: &mut dyn FnMut(&'a MalVal)) {
+        if let Some(ref n) = self.root {
+            walk_node(n, enter, visit);
+        }
+    }
+}
+
+fn insert(node: &Rc<HNode>, shift: u32, hash: u64, k: MalVal, v: MalVal) -> (Rc<HNode>, bool) {
+    match &**node {
+        HNode::Branch(bitmap, entries) => {
+            let b = bit(hash, shift);
+            let i = slot(*bitmap, b);
+            let mut entries = entries.clone();
+            let mut added = true;
+            if bitmap & b == 0 {
+                entries.insert(i, Entry::Leaf(hash, k, v));
+                return (Rc::new(HNode::Branch(bitmap | b, entries)), true);
+            }
+            entries[i] = match &entries[i] {
+                Entry::Leaf(h2, k2, _) if *k2 == k => {
+                    added = false;
+                    Entry::Leaf(*h2, k, v)
+                }
+                Entry::Leaf(h2, k2, v2) => Entry::Node(merge(shift + BITS, *h2, k2.clone(), v2.clone(), hash, k, v)),
+                Entry::Node(n) => {
+                    let (n, a) = insert(n, shift + BITS, hash, k, v);
+                    added = a;
+                    Entry::Node(n)
+                }
+            };
+            (Rc::new(HNode::Branch(*bitmap, entries)), added)
+        }
+        HNode::Collision(h, pairs) => {
+            let mut pairs = pairs.clone();
+            match pairs.iter().position(|(k2, _)| *k2 == k) {
+                Some(i) => {
+                    pairs[i].1 = v;
+                    (Rc::new(HNode::Collision(*h, pairs)), false)
+                }
+                None => {
+                    pairs.push((k, v));
+                    (Rc::new(HNode::Collision(*h, pairs)), true)
+                }
+            }
+        }
+    }
+}
+
+// 两个 key 落在同一个位置 往下一层分开
+fn merge(shift: u32, h1: u64, k1: MalVal, v1: MalVal, h2: u64, k2: MalVal, v2: MalVal) -> Rc<HNode> {
+    if h1 == h2 || shift >= 64 {
+        return Rc::new(HNode::Collision(h1, alloc::vec![(k1, v1), (k2, v2)]));
+    }
+    let (b1, b2) = (bit(h1, shift), bit(h2, shift));
+    if b1 == b2 {
+        let sub = merge(shift + BITS, h1, k1, v1, h2, k2, v2);
+        return Rc::new(HNode::Branch(b1, alloc::vec![Entry::Node(sub)]));
+    }
+    let (a, b) = (Entry::Leaf(h1, k1, v1), Entry::Leaf(h2, k2, v2));
+    let entries = if b1 < b2 { alloc::vec![a, b] } else { alloc::vec![b, a] };
+    Rc::new(HNode::Branch(b1 | b2, entries))
+}
+
+// 没有找到 key 时返回 None 节点删空时返回 Some(None)
+fn remove(node: &Rc<HNode>, shift: u32, hash: u64, k: &MalVal) -> Option<Option<Rc<HNode>>> {
+    match &**node {
+        HNode::Branch(bitmap, entries) => {
+            let b = bit(hash, shift);
+            if bitmap & b == 0 {
+                return None;
+            }
+            let i = slot(*bitmap, b);
+            let child = match &entries[i] {
+                Entry::Leaf(_, k2, _) if k2 == k => None,
+                Entry::Leaf(_, _, _) => return None,
+                Entry::Node(n) => remove(n, shift + BITS, hash, k)?,
+            };
+            let mut entries = entries.clone();
+            let bitmap = match child {
+                Some(n) => {
+                    entries[i] = Entry::Node(n);
+                    *bitmap
+                }
+                None => {
+                    entries.remove(i);
+                    bitmap & !b
+                }
+            };
+            if entries.is_empty() {
+                Some(None)
+            } else {
+                Some(Some(Rc::new(HNode::Branch(bitmap, entries))))
+            }
+        }
+        HNode::Collision(h, pairs) => {
+            let i = pairs.iter().position(|(k2, _)| k2 == k)?;
+            let mut pairs = pairs.clone();
+            pairs.remove(i);
+            if pairs.is_empty() {
+                Some(None)
+            } else {
+                Some(Some(Rc::new(HNode::Collision(*h, pairs))))
+            }
+        }
+    }
+}
+
+fn walk_node<'a>(node: &'a Rc<HNode>, enter: &mut dyn FnMut(usize, usize) -> bool, visit: &mut dyn FnMut(&'a MalVal)) {
+    if !enter(Rc::as_ptr(node) as *const u8 as usize, Rc::strong_count(node)) {
+        return;
+    }
+    match &**node {
+        HNode::Branch(_, entries) => {
+            for e in entries.iter() {
+                match e {
+                    Entry::Leaf(_, k, v) => {
+                        visit(k);
+                        visit(v);
+                    }
+                    Entry::Node(n) => walk_node(n, enter, visit),
+                }
+            }
+        }
+        HNode::Collision(_, pairs) => {
+            for (k, v) in pairs.iter() {
+                visit(k);
+                visit(v);
+            }
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    stack: Vec<(&'a HNode, usize)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a MalVal, &'a MalVal);
+    fn next(&mut self) -> Option<(&'a MalVal, &'a MalVal)> {
+        loop {
+            let (node, i) = self.stack.last_mut()?;
+            let node: &'a HNode = *node;
+            match node {
+                HNode::Branch(_, entries) if *i < entries.len() => {
+                    let j = *i;
+                    *i += 1;
+                    match &entries[j] {
+                        Entry::Leaf(_, k, v) => return Some((k, v)),
+                        Entry::Node(n) => self.stack.push((&**n, 0)),
+                    }
+                }
+                HNode::Collision(_, pairs) if *i < pairs.len() => {
+                    let j = *i;
+                    *i += 1;
+                    let (k, v) = &pairs[j];
+                    return Some((k, v));
+                }
+                _ => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+impl PartialEq for PMap {
+    fn eq(&self, other: &PMap) -> bool {
+        self.len == other.len && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl fmt::Debug for PMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl core::iter::FromIterator<(MalVal, MalVal)> for PMap {
+    fn from_iter<I: IntoIterator<Item = (MalVal, MalVal)>>(iter: I) -> PMap {
+        iter.into_iter().fold(PMap::new(), |m, (k, v)| m.insert(k, v))
+    }
+}
+
+// 持久化的集合 元素保存为 map 的 key 值都是 nil
+#[derive(Clone, PartialEq)]
+pub struct PSet(PMap);
+
+impl PSet {
+    pub fn new() -> PSet {
+        PSet(PMap::new())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, x: &MalVal) -> bool {
+        self.0.contains_key(x)
+    }
+
+    pub fn insert(&self, x: MalVal) -> PSet {
+        PSet(self.0.insert(x, MalVal::Nil))
+    }
+
+    pub fn remove(&self, x: &MalVal) -> PSet {
+        PSet(self.0.remove(x))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MalVal> {
+        self.0.keys()
+    }
+
+    pub fn walk<'a>(&'a self, enter: &mut dyn FnMut(usize, usize) -> bool, visit: &mut dyn FnMut(&'a MalVal)) {
+        self.0.walk(enter, visit)
+    }
+}
+
+impl fmt::Debug for PSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl core::iter::FromIterator<MalVal> for PSet {
+    fn from_iter<I: IntoIterator<Item = MalVal>>(iter: I) -> PSet {
+        iter.into_iter().fold(PSet::new(), |s, x| s.insert(x))
+    }
+}
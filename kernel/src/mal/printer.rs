@@ -1,6 +1,12 @@
 use alloc::string::{String,ToString};
 use crate::mal::types::MalVal;
-use crate::mal::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::mal::types::MalVal::{Atom, Big, Bool, Closure, Cont, Float, Func, Hash, Int, Keyword, Lazy, List, MalFunc, Nil, Ratio, Set, Str, Sym, Vector, Error, Native};
+use crate::mal::exception;
+use crate::mal::lazy;
+use crate::mal::stack;
+use crate::mal::symbol;
+use crate::mal::global::Global;
+use alloc::rc::Rc;
 use crate::format;
 use crate::vec;
 use alloc::vec::Vec;
@@ -11,6 +17,8 @@ fn escape_str(s: &str) -> String {
         .map(|c| match c {
             '"' => "\\\"".to_string(),
             '\n' => "\\n".to_string(),
+            '\t' => "\\t".to_string(),
+            '\r' => "\\r".to_string(),
             '\\' => "\\\\".to_string(),
             _ => c.to_string(),
         })
@@ -18,49 +26,120 @@ fn escape_str(s: &str) -> String {
         .join("")
 }
 
+// *print-length* 是整数 n 时集合只打印前 n 项 后面的用 ... 表示
+pub fn print_length() -> Option<usize> {
+    match super::dynamic(symbol::PRINT_LENGTH) {
+        Some(Int(n)) if n >= 0 => Some(n as usize),
+        _ => None,
+    }
+}
+
+// 打印集合 每 step 个值是一项 map 的一项是 key 和 value
+fn pr_coll<'a>(
+    seq: impl IntoIterator<Item = &'a MalVal>,
+    step: usize,
+    print_readably: bool,
+    start: &str,
+    end: &str,
+) -> String {
+    let limit = print_length().map(|n| n * step);
+    let mut strs = Vec::new();
+    for (i, x) in seq.into_iter().enumerate() {
+        if Some(i) == limit {
+            strs.push("...".to_string());
+            break;
+        }
+        strs.push(x.pr_str(print_readably));
+    }
+    format!("{}{}{}", start, strs.join(" "), end)
+}
+
+// 正在打印的原子 原子中直接或间接地包含自己时打印成 (atom ...)
+static PRINTING: Global<Vec<usize>> = Global::new(Vec::new());
+
 impl MalVal {
+    // 嵌套的深度和求值一起计算 太深的部分打印成 ... 不会耗尽栈
     pub fn pr_str(&self, print_readably: bool) -> String {
+        if !stack::active() {
+            return stack::run(|| self.pr_str(print_readably));
+        }
+        if stack::enter().is_err() {
+            return String::from("...");
+        }
+        let s = self.pr_nested(print_readably);
+        stack::leave();
+        s
+    }
+
+    fn pr_nested(&self, print_readably: bool) -> String {
         match self {
             Nil => String::from("nil"),
             Bool(true) => String::from("true"),
             Bool(false) => String::from("false"),
             Int(i) => format!("{}", i),
-            //Float(f)    => format!("{}", f),
+            // 无穷大和 NaN 打印成可以读回的 ##Inf ##-Inf ##NaN
+            Float(f) if f.is_nan() => String::from("##NaN"),
+            Float(f) if f.is_infinite() => String::from(if *f > 0.0 { "##Inf" } else { "##-Inf" }),
+            // 用 Debug 格式保证总带有小数点或指数 可以被 reader 读回
+            Float(f) => format!("{:?}", f),
+            Big(b) => format!("{}", b),
+            Ratio(r) => format!("{}", r),
             Str(s) => {
-                if s.starts_with("\u{29e}") {
-                    format!(":{}", &s[2..])
-                } else if print_readably {
+                if print_readably {
                     format!("\"{}\"", escape_str(s))
                 } else {
                     s.clone()
                 }
             }
-            Sym(s) => s.clone(),
-            List(l, _) => pr_seq(&**l, print_readably, "(", ")", " "),
-            Vector(l, _) => pr_seq(&**l, print_readably, "[", "]", " "),
+            Sym(s) => s.to_string(),
+            Keyword(s) => format!(":{}", s),
+            List(l, _) => pr_coll(l.iter(), 1, print_readably, "(", ")"),
+            Vector(l, _) => pr_coll(l.iter(), 1, print_readably, "[", "]"),
             Hash(hm, _) => {
                 let l: Vec<MalVal> = hm
                     .iter()
-                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
+                    .flat_map(|(k, v)| vec![k.clone(), v.clone()])
                     .collect();
-                pr_seq(&l, print_readably, "{", "}", " ")
+                pr_coll(&l, 2, print_readably, "{", "}")
             }
+            Set(s, _) => pr_coll(s.iter(), 1, print_readably, "#{", "}"),
+            Lazy(l) => pr_coll(&lazy::elements(l, print_length()), 1, print_readably, "(", ")"),
             Func(f, _) => format!("#<fn {:?}>", f),
+            Native(n, _) => format!("#<native fn {}>", n.name),
             MalFunc {
                 ast: a, params: p, ..
-            } => format!("(lambda {} {})", p.pr_str(true), a.pr_str(true)),
-            Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
+            } => match (&**p, &**a) {
+                // 有多个参数个数的函数
+                (Nil, List(clauses, _)) => pr_seq(clauses.iter(), true, "(lambda ", ")", " "),
+                _ => format!("(lambda {} {})", p.pr_str(true), a.pr_str(true)),
+            },
+            Atom(a) => {
+                let addr = Rc::as_ptr(a) as usize;
+                if PRINTING.borrow().contains(&addr) {
+                    return String::from("(atom ...)");
+                }
+                PRINTING.borrow_mut().push(addr);
+                let s = format!("(atom {})", a.borrow().pr_str(true));
+                PRINTING.borrow_mut().pop();
+                s
+            }
+            Cont(id) => format!("#<continuation {}>", id),
+            Closure(c, _) => match &c.proto.name {
+                Some(name) => format!("#<compiled fn {}>", name),
+                None => String::from("#<compiled fn>"),
+            },
+            Error(e) => exception::pr(e, print_readably),
         }
     }
 }
 
-pub fn pr_seq(
-    seq: &Vec<MalVal>,
+pub fn pr_seq<'a>(
+    seq: impl IntoIterator<Item = &'a MalVal>,
     print_readably: bool,
     start: &str,
     end: &str,
     join: &str,
 ) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| x.pr_str(print_readably)).collect();
+    let strs: Vec<String> = seq.into_iter().map(|x| x.pr_str(print_readably)).collect();
     format!("{}{}{}", start, strs.join(join), end)
 }
\ No newline at end of file
@@ -1,43 +1,84 @@
 use crate::format;
 use crate::list;
 use crate::mal::reader::State::{Comment, Others, Start, StateStr, StateSym};
+use crate::mal::number::{parse_integer, parse_ratio};
 use crate::mal::types::error;
 use crate::mal::types::hash_map;
 use crate::mal::types::MalErr;
 use crate::mal::types::MalErr::ErrString;
 use crate::mal::types::MalRet;
 use crate::mal::types::MalVal;
-use crate::mal::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
+use crate::mal::seq::Seq;
+use crate::mal::stack;
+use crate::mal::symbol::{self, Symbol};
+use crate::mal::types::MalVal::{Bool, Float, Int, Keyword, List, Nil, Set, Str, Sym, Vector};
 use crate::vec;
 use crate::vector;
 use alloc::rc::Rc;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use hashbrown::HashMap;
+
+// 带有位置信息的 token
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    col: usize,
+}
+
+impl Token {
+    fn new(text: String, (line, col): (usize, usize)) -> Token {
+        Token { text, line, col }
+    }
+}
 
 #[derive(Debug, Clone)]
 struct Reader {
-    tokens: Vec<String>,
+    tokens: Vec<Token>,
     pos: usize,
+    file: Option<String>, // 代码来自的文件 从 repl 输入时为空
 }
 
 impl Reader {
     // 阅读下一项
     fn next(&mut self) -> Result<String, MalErr> {
+        let token = self.peek()?;
         self.pos = self.pos + 1;
-        Ok(self
-            .tokens
-            .get(self.pos - 1)
-            .ok_or(ErrString(String::from("underflow")))?
-            .to_string())
+        Ok(token)
     }
     // 看一眼下一项
     fn peek(&self) -> Result<String, MalErr> {
-        Ok(self
-            .tokens
-            .get(self.pos)
-            .ok_or(ErrString(String::from("underflow")))?
-            .to_string())
+        match self.tokens.get(self.pos) {
+            Some(t) => Ok(t.text.to_string()),
+            None => Err(self.error_at(self.tokens.len() - 1, "underflow")),
+        }
+    }
+    // 第 idx 个 token 的位置 例如 entry.jmal:3:5
+    fn location(&self, idx: usize) -> String {
+        let t = &self.tokens[idx];
+        match &self.file {
+            Some(f) => format!("{}:{}:{}", f, t.line, t.col),
+            None => format!("{}:{}", t.line, t.col),
+        }
+    }
+    // 在第 idx 个 token 的位置上报错
+    fn error_at(&self, idx: usize, msg: &str) -> MalErr {
+        ErrString(msg.to_string()).at(self.location(idx))
+    }
+    // 第 idx 个 token 的位置 作为列表的元数据 {:file :line :column}
+    fn pos_meta(&self, idx: usize) -> MalVal {
+        let t = &self.tokens[idx];
+        let mut kvs = vec![
+            Keyword("line".to_string()),
+            Int(t.line as i64),
+            Keyword("column".to_string()),
+            Int(t.col as i64),
+        ];
+        if let Some(f) = &self.file {
+            kvs.push(Keyword("file".to_string()));
+            kvs.push(Str(f.to_string()));
+        }
+        hash_map(kvs).unwrap_or(Nil)
     }
 }
 
@@ -52,27 +93,45 @@ enum State {
 }
 
 // token化
-fn tokenize(str: &str) -> Vec<String> {
+fn tokenize(str: &str) -> Vec<Token> {
     let mut res = Vec::new();
+    // 当前字符的位置 和 当前 token 开始的位置
+    let (mut line, mut col) = (1, 1);
+    let mut start = (1, 1);
     let mut code = String::from(str).chars().rev().collect::<String>();
     let mut state: State = Start;
+    // 字符串中前一个字符是没有被转义的反斜杠 "a\\" 中的第二个反斜杠已经被转义 不会转义后面的引号
+    let mut in_escape = false;
     loop {
         let pre_state = state.clone();
         match code.pop() {
             Some(t) => {
+                let escaped = in_escape;
+                in_escape = match pre_state {
+                    StateStr(_) => t == '\\' && !escaped,
+                    _ => false,
+                };
+                let here = (line, col);
+                if t == '\n' {
+                    line += 1;
+                    col = 1;
+                } else {
+                    col += 1;
+                }
                 match t {
                     '`' | '\'' | '~' | '^' | '@' | '[' | ']' | '(' | ')' | '{' | '}' => {
                         match pre_state {
                             Start => {
                                 state = StateSym(t.to_string());
+                                start = here;
                             }
                             StateSym(s) => {
                                 if s == "~" && t == '@' {
-                                    res.push(String::from("~@"));
+                                    res.push(Token::new(String::from("~@"), start));
                                     state = Start;
                                 } else {
-                                    res.push(s);
-                                    res.push(t.to_string());
+                                    res.push(Token::new(s, start));
+                                    res.push(Token::new(t.to_string(), here));
                                     state = Start;
                                 }
                             }
@@ -81,9 +140,15 @@ fn tokenize(str: &str) -> Vec<String> {
                                 tmp.push(t);
                                 state = Comment(tmp);
                             }
+                            // #{ 是集合的开始
+                            Others(s) if s == "#" && t == '{' => {
+                                res.push(Token::new(String::from("#{"), start));
+                                state = Start;
+                            }
                             Others(s) => {
-                                res.push(s);
+                                res.push(Token::new(s, start));
                                 state = StateSym(t.to_string());
+                                start = here;
                             }
                             StateStr(s) => {
                                 let mut tmp = s.clone();
@@ -98,7 +163,7 @@ fn tokenize(str: &str) -> Vec<String> {
                                 // do nothing
                             }
                             StateSym(s) => {
-                                res.push(s);
+                                res.push(Token::new(s, start));
                                 state = Start;
                             }
                             Comment(s) => {
@@ -107,7 +172,7 @@ fn tokenize(str: &str) -> Vec<String> {
                                 state = Comment(tmp);
                             }
                             Others(s) => {
-                                res.push(s);
+                                res.push(Token::new(s, start));
                                 state = Start;
                             }
                             StateStr(s) => {
@@ -123,7 +188,7 @@ fn tokenize(str: &str) -> Vec<String> {
                                 // do nothing
                             }
                             StateSym(s) => {
-                                res.push(s);
+                                res.push(Token::new(s, start));
                                 state = Start;
                             }
                             Comment(s) => {
@@ -132,7 +197,7 @@ fn tokenize(str: &str) -> Vec<String> {
                                 state = Start;
                             }
                             Others(s) => {
-                                res.push(s);
+                                res.push(Token::new(s, start));
                                 state = Start;
                             }
                             StateStr(s) => {
@@ -147,7 +212,7 @@ fn tokenize(str: &str) -> Vec<String> {
                             state = Comment(String::from(t.to_string()));
                         }
                         StateSym(s) => {
-                            res.push(s);
+                            res.push(Token::new(s, start));
                             state = Comment(String::from(t.to_string()));
                         }
                         Comment(s) => {
@@ -156,7 +221,7 @@ fn tokenize(str: &str) -> Vec<String> {
                             state = Comment(tmp);
                         }
                         Others(s) => {
-                            res.push(s);
+                            res.push(Token::new(s, start));
                             state = Comment(String::from(t.to_string()));
                         }
                         StateStr(s) => {
@@ -168,10 +233,12 @@ fn tokenize(str: &str) -> Vec<String> {
                     '\"' => match pre_state {
                         Start => {
                             state = StateStr(t.to_string());
+                            start = here;
                         }
                         StateSym(s) => {
-                            res.push(s);
+                            res.push(Token::new(s, start));
                             state = StateStr(t.to_string());
+                            start = here;
                         }
                         Comment(s) => {
                             let mut tmp = s.clone();
@@ -186,10 +253,10 @@ fn tokenize(str: &str) -> Vec<String> {
                         StateStr(s) => {
                             let mut tmp = s.clone();
                             tmp.push(t);
-                            if s.ends_with("\\") {
+                            if escaped {
                                 state = StateStr(tmp);
                             } else {
-                                res.push(tmp);
+                                res.push(Token::new(tmp, start));
                                 state = Start;
                             }
                         }
@@ -199,10 +266,12 @@ fn tokenize(str: &str) -> Vec<String> {
                         match pre_state {
                             Start => {
                                 state = Others(t.to_string());
+                                start = here;
                             }
                             StateSym(s) => {
-                                res.push(s);
+                                res.push(Token::new(s, start));
                                 state = Others(t.to_string());
+                                start = here;
                             }
                             Comment(s) => {
                                 let mut tmp = s.clone();
@@ -232,26 +301,27 @@ fn tokenize(str: &str) -> Vec<String> {
     match state {
         Start => {}
         StateSym(s) => {
-            res.push(s);
+            res.push(Token::new(s, start));
         }
         Comment(s) => {
             // res.push(s);
         }
         Others(s) => {
-            res.push(s);
+            res.push(Token::new(s, start));
         }
-        StateStr(mut s) => {
-            if !s.ends_with("\"") {
-                s.push('\"');
-            }
-            res.push(s);
+        // 没有结束的字符串 由 read_atom 报错
+        StateStr(s) => {
+            res.push(Token::new(s, start));
         }
     }
     res
 }
 
 // 判断一个字符串都是数字
-fn is_numbers(s: &String) -> bool {
+fn is_numbers(s: &str) -> bool {
+    if s.len() == 0 {
+        return false;
+    }
     for r in s.chars() {
         if !r.is_ascii_digit() {
             return false;
@@ -260,25 +330,113 @@ fn is_numbers(s: &String) -> bool {
     true
 }
 
+// 判断是否是浮点数的写法 例如 1.5 .5 1. -2e3 1.5E-3
+fn is_float(s: &str) -> bool {
+    let (mantissa, exp) = match s.find(|c| c == 'e' || c == 'E') {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let mut digits = 0;
+    let mut dots = 0;
+    for c in mantissa.chars() {
+        match c {
+            '0'..='9' => digits += 1,
+            '.' => dots += 1,
+            _ => return false,
+        }
+    }
+    if digits == 0 || dots > 1 {
+        return false;
+    }
+    match exp {
+        Some(e) => {
+            let e = e.strip_prefix(|c| c == '+' || c == '-').unwrap_or(e);
+            is_numbers(e)
+        }
+        None => dots == 1,
+    }
+}
+
+// 识别数字字面量 不是数字时返回 None
+fn read_number(token: &str) -> Option<MalRet> {
+    let body = token
+        .strip_prefix(|c| c == '+' || c == '-')
+        .unwrap_or(token);
+    if is_numbers(body) {
+        // 超出 i64 范围的整数读成大整数
+        Some(Ok(parse_integer(token)?))
+    } else if is_float(body) {
+        Some(match token.parse::<f64>() {
+            Ok(f) => Ok(Float(f)),
+            Err(_) => error(&format!("invalid float literal: {}", token)),
+        })
+    } else {
+        // 分数 例如 1/3 -2/4
+        let i = body.find('/')?;
+        if is_numbers(&body[..i]) && is_numbers(&body[i + 1..]) {
+            let i = token.len() - body.len() + i;
+            Some(parse_ratio(&token[..i], &token[i + 1..]))
+        } else {
+            None
+        }
+    }
+}
+
+// 处理字符串中的转义字符 \n \t \r \" \\ 其他的反斜杠原样保留
+fn unescape(s: &str) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('"') => out.push('"'),
+            Some('\\') => out.push('\\'),
+            Some(c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+// 字符串以没有被转义的引号结束 引号前面的反斜杠是偶数个
+fn is_closed_str(token: &str) -> bool {
+    if token.len() < 2 || !token.ends_with('"') {
+        return false;
+    }
+    let slashes = token[1..token.len() - 1].chars().rev().take_while(|c| *c == '\\').count();
+    slashes % 2 == 0
+}
+
 fn read_atom(rdr: &mut Reader) -> MalRet {
     let token = rdr.next()?;
     match &token[..] {
         "nil" => Ok(Nil),
         "false" => Ok(Bool(false)),
         "true" => Ok(Bool(true)),
+        "##Inf" => Ok(Float(f64::INFINITY)),
+        "##-Inf" => Ok(Float(f64::NEG_INFINITY)),
+        "##NaN" => Ok(Float(f64::NAN)),
         _ => {
-            if is_numbers(&token) {
-                Ok(Int(token.parse().unwrap()))
-            // fixme 这里要失败字符串 再rust中使用的时候 必须加速这个\" 就很难受
-            // TODO 而且上方也没有正常的识别出来
-            } else if token.starts_with('\"') && token.ends_with('\"') {
-                // fixme 这里要转义字符对转义字符进行判断
-                Ok(Str(token[1..token.len() - 1].to_string()))
+            if let Some(num) = read_number(&token) {
+                num
+            } else if token.starts_with('\"') {
+                if !is_closed_str(&token) {
+                    return error("expected '\"', got EOF");
+                }
+                Ok(Str(unescape(&token[1..token.len() - 1])))
             } else if token.starts_with(":") {
-                Ok(Str(format!("\u{29e}{}", &token[1..])))
-            // Ok(Str(String::from("\u{29e}")+&token[1..token.len()]))
+                Ok(Keyword(token[1..].to_string()))
             } else {
-                Ok(Sym(token.to_string()))
+                Ok(Sym(Symbol::new(&token)))
             }
         }
     }
@@ -287,11 +445,15 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
 // 读符合 并且识别两个括号
 fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
     let mut seq: Vec<MalVal> = vec![];
-    rdr.next()?;
+    let start = rdr.pos;
+    let open = rdr.next()?;
     loop {
         let token = match rdr.peek() {
             Ok(t) => t,
-            Err(_) => return error(&format!("expected '{}', got EOF", end)),
+            Err(_) => {
+                let msg = format!("expected '{}', got EOF", end);
+                return Err(rdr.error_at(start, &msg));
+            }
         };
         if token == end {
             break;
@@ -299,60 +461,80 @@ fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
         seq.push(read_form(rdr)?)
     }
     let _ = rdr.next();
-    match end {
-        ")" => Ok(list!(seq)),
-        "]" => Ok(vector!(seq)),
-        "}" => hash_map(seq),
+    match &open[..] {
+        "(" => Ok(List(Seq::list(seq), Rc::new(rdr.pos_meta(start)))),
+        "[" => Ok(vector!(seq)),
+        "{" => hash_map(seq).map_err(|e| e.at(rdr.location(start))),
+        "#{" => Ok(Set(seq.into_iter().collect(), Rc::new(Nil))),
         _ => error("read_seq unknown end value"),
     }
 }
 
+// 嵌套的深度和求值一起计算 太深时报错 不会耗尽栈
 fn read_form(rdr: &mut Reader) -> MalRet {
+    if stack::enter().is_err() {
+        return Err(rdr.error_at(rdr.pos, "too deeply nested"));
+    }
+    let res = read_nested(rdr);
+    stack::leave();
+    res
+}
+
+fn read_nested(rdr: &mut Reader) -> MalRet {
     let token = rdr.peek()?;
     match &token[..] {
         "'" => {
             let _ = rdr.next();
-            Ok(list![Sym("quote".to_string()), read_form(rdr)?])
+            Ok(list![Sym(Symbol::known(symbol::QUOTE)), read_form(rdr)?])
         }
         "`" => {
             let _ = rdr.next();
-            Ok(list![Sym("quasiquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym(Symbol::known(symbol::QUASIQUOTE)), read_form(rdr)?])
         }
         "~" => {
             let _ = rdr.next();
-            Ok(list![Sym("unquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym(Symbol::known(symbol::UNQUOTE)), read_form(rdr)?])
         }
         "~@" => {
             let _ = rdr.next();
-            Ok(list![Sym("splice-unquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym(Symbol::known(symbol::SPLICE_UNQUOTE)), read_form(rdr)?])
         }
         "^" => {
             let _ = rdr.next();
             let meta = read_form(rdr)?;
-            Ok(list![Sym("with-meta".to_string()), read_form(rdr)?, meta])
+            Ok(list![Sym(Symbol::new("with-meta")), read_form(rdr)?, meta])
         }
         "@" => {
             let _ = rdr.next();
-            Ok(list![Sym("deref".to_string()), read_form(rdr)?])
+            Ok(list![Sym(Symbol::new("deref")), read_form(rdr)?])
         }
-        ")" => error("unexpected ')'"),
+        ")" | "]" | "}" => Err(rdr.error_at(rdr.pos, &format!("unexpected '{}'", token))),
         "(" => read_seq(rdr, ")"),
-        "]" => error("unexpected ']'"),
         "[" => read_seq(rdr, "]"),
-        "}" => error("unexpected '}'"),
-        "{" => read_seq(rdr, "}"),
-        _ => read_atom(rdr),
+        "{" | "#{" => read_seq(rdr, "}"),
+        _ => {
+            let start = rdr.pos;
+            read_atom(rdr).map_err(|e| e.at(rdr.location(start)))
+        }
     }
 }
 
 pub fn read_str(str: String) -> MalRet {
+    read_str_in(str, None)
+}
+
+// 读取来自某个文件的代码 读出来的列表会带上文件名和行列号
+pub fn read_str_in(str: String, file: Option<String>) -> MalRet {
+    if !stack::active() {
+        return stack::run(move || read_str_in(str, file));
+    }
     let tokens = tokenize(&str);
-    // println!("tokens: {:?}", tokens);
     if tokens.len() == 0 {
         return error("no input");
     }
     read_form(&mut Reader {
         pos: 0,
         tokens: tokens,
+        file: file,
     })
 }
@@ -0,0 +1,423 @@
+// 持久化(结构共享)的序列 List 和 Vector 都用它保存元素
+// 修改总是返回新的序列 原来的序列保持不变 没有改变的部分在新旧序列之间共享
+// 列表用单链表保存 cons 和 rest 都是 O(1)
+// 向量用 32 叉的前缀树加上尾部的缓冲保存 按下标读取 修改和在末尾添加都是 O(log32 n)
+// 向量的 rest 只是增加起始位置 也是 O(1)
+use crate::mal::types::MalVal;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::Index;
+
+#[derive(Clone)]
+pub enum Seq {
+    Cons(ConsList),
+    Trie(PVec),
+}
+
+impl Seq {
+    // 用链表保存的序列 适合 List
+    pub fn list(v: Vec<MalVal>) -> Seq {
+        Seq::Cons(ConsList::from_vec(v))
+    }
+
+    // 用前缀树保存的序列 适合 Vector
+    pub fn vector(v: Vec<MalVal>) -> Seq {
+        Seq::Trie(PVec::from_vec(v))
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Seq::Cons(l) => l.len,
+            Seq::Trie(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> Option<&MalVal> {
+        match self {
+            Seq::Cons(l) => l.iter().nth(i),
+            Seq::Trie(v) => v.get(i),
+        }
+    }
+
+    pub fn first(&self) -> Option<&MalVal> {
+        self.get(0)
+    }
+
+    pub fn last(&self) -> Option<&MalVal> {
+        match self.len() {
+            0 => None,
+            n => self.get(n - 1),
+        }
+    }
+
+    pub fn iter(&self) -> Iter {
+        match self {
+            Seq::Cons(l) => Iter::Cons(l.head.as_deref()),
+            Seq::Trie(v) => Iter::Trie(v, 0),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<MalVal> {
+        self.iter().cloned().collect()
+    }
+
+    // 去掉前 n 个元素
+    pub fn skip(&self, n: usize) -> Seq {
+        match self {
+            Seq::Cons(l) => {
+                let mut l = l.clone();
+                for _ in 0..n.min(l.len) {
+                    l = l.rest();
+                }
+                Seq::Cons(l)
+            }
+            Seq::Trie(v) => Seq::Trie(v.skip(n)),
+        }
+    }
+
+    pub fn rest(&self) -> Seq {
+        self.skip(1)
+    }
+
+    // 在开头添加一个元素 得到的总是链表
+    pub fn cons(&self, x: MalVal) -> Seq {
+        match self {
+            Seq::Cons(l) => Seq::Cons(l.cons(x)),
+            Seq::Trie(_) => Seq::Cons(ConsList::from_vec(self.to_vec()).cons(x)),
+        }
+    }
+
+    // 在末尾添加一个元素 得到的总是向量
+    pub fn push(&self, x: MalVal) -> Seq {
+        Seq::Trie(self.trie().push(x))
+    }
+
+    // 替换第 i 个元素 i 等于长度时在末尾添加
+    pub fn set(&self, i: usize, x: MalVal) -> Option<Seq> {
+        match i {
+            i if i < self.len() => Some(Seq::Trie(self.trie().set(i, x))),
+            i if i == self.len() => Some(self.push(x)),
+            _ => None,
+        }
+    }
+
+    // 保证修改时是 O(log n) 链表需要先转换成向量
+    fn trie(&self) -> PVec {
+        match self {
+            Seq::Trie(v) => v.clone(),
+            Seq::Cons(l) => PVec::from_vec(l.iter().cloned().collect()),
+        }
+    }
+
+    // 遍历内部共享的节点 enter 收到节点的地址和强引用计数 返回 false 时不进入这个节点
+    pub fn walk<'a>(&'a self, enter: &mut dyn FnMut(usize, usize) -> bool, visit: &mut dyn FnMut(&'a MalVal)) {
+        match self {
+            Seq::Cons(l) => {
+                let mut node = l.head.as_ref();
+                while let Some(n) = node {
+                    if !enter(Rc::as_ptr(n) as usize, Rc::strong_count(n)) {
+                        break;
+                    }
+                    visit(&n.val);
+                    node = n.next.as_ref();
+                }
+            }
+            Seq::Trie(v) => v.walk(enter, visit),
+        }
+    }
+}
+
+impl Index<usize> for Seq {
+    type Output = MalVal;
+    fn index(&self, i: usize) -> &MalVal {
+        self.get(i).expect("index out of range")
+    }
+}
+
+impl PartialEq for Seq {
+    fn eq(&self, other: &Seq) -> bool {
+        self.len() == other.len() && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl Hash for Seq {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for x in self.iter() {
+            x.hash(state);
+        }
+    }
+}
+
+impl fmt::Debug for Seq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+pub enum Iter<'a> {
+    Cons(Option<&'a Node>),
+    Trie(&'a PVec, usize),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a MalVal;
+    fn next(&mut self) -> Option<&'a MalVal> {
+        match self {
+            Iter::Cons(node) => {
+                let n = (*node)?;
+                *node = n.next.as_deref();
+                Some(&n.val)
+            }
+            Iter::Trie(v, i) => {
+                let x = v.get(*i)?;
+                *i += 1;
+                Some(x)
+            }
+        }
+    }
+}
+
+// 单链表
+#[derive(Clone)]
+pub struct ConsList {
+    head: Option<Rc<Node>>,
+    len: usize,
+}
+
+pub struct Node {
+    val: MalVal,
+    next: Option<Rc<Node>>,
+}
+
+// 很长的链表递归释放会耗尽栈 改成循环释放
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut next = self.next.take();
+        while let Some(n) = next {
+            match Rc::try_unwrap(n) {
+                Ok(mut n) => next = n.next.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl ConsList {
+    fn from_vec(v: Vec<MalVal>) -> ConsList {
+        let len = v.len();
+        let mut head = None;
+        for val in v.into_iter().rev() {
+            head = Some(Rc::new(Node { val, next: head }));
+        }
+        ConsList { head, len }
+    }
+
+    fn iter(&self) -> Iter {
+        Iter::Cons(self.head.as_deref())
+    }
+
+    fn cons(&self, val: MalVal) -> ConsList {
+        ConsList {
+            head: Some(Rc::new(Node {
+                val,
+                next: self.head.clone(),
+            })),
+            len: self.len + 1,
+        }
+    }
+
+    fn rest(&self) -> ConsList {
+        match self.head {
+            Some(ref n) => ConsList {
+                head: n.next.clone(),
+                len: self.len - 1,
+            },
+            None => self.clone(),
+        }
+    }
+}
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+// 32 叉前缀树 最后不满 32 个的元素放在 tail 中
+#[derive(Clone)]
+pub struct PVec {
+    count: usize, // 包括 start 之前已经去掉的元素
+    start: usize,
+    shift: usize,
+    root: Rc<TrieNode>,
+    tail: Rc<Vec<MalVal>>,
+}
+
+enum TrieNode {
+    Branch(Vec<Rc<TrieNode>>),
+    Leaf(Vec<MalVal>),
+}
+
+impl TrieNode {
+    fn children(&self) -> &Vec<Rc<TrieNode>> {
+        match self {
+            TrieNode::Branch(c) => c,
+            TrieNode::Leaf(_) => unreachable!(),
+        }
+    }
+}
+
+impl PVec {
+    fn new() -> PVec {
+        PVec {
+            count: 0,
+            start: 0,
+            shift: BITS,
+            root: Rc::new(TrieNode::Branch(Vec::new())),
+            tail: Rc::new(Vec::new()),
+        }
+    }
+
+    fn from_vec(v: Vec<MalVal>) -> PVec {
+        v.into_iter().fold(PVec::new(), |acc, x| acc.push(x))
+    }
+
+    fn len(&self) -> usize {
+        self.count - self.start
+    }
+
+    fn tail_offset(&self) -> usize {
+        if self.count < WIDTH {
+            0
+        } else {
+            ((self.count - 1) >> BITS) << BITS
+        }
+    }
+
+    fn get(&self, i: usize) -> Option<&MalVal> {
+        let i = i.checked_add(self.start)?;
+        if i >= self.count {
+            return None;
+        }
+        if i >= self.tail_offset() {
+            return self.tail.get(i - self.tail_offset());
+        }
+        let mut node = &self.root;
+        let mut level = self.shift;
+        while level > 0 {
+            node = &node.children()[(i >> level) & MASK];
+            level -= BITS;
+        }
+        match &**node {
+            TrieNode::Leaf(vals) => vals.get(i & MASK),
+            TrieNode::Branch(_) => None,
+        }
+    }
+
+    fn skip(&self, n: usize) -> PVec {
+        let mut v = self.clone();
+        v.start = (v.start + n).min(v.count);
+        v
+    }
+
+    fn push(&self, x: MalVal) -> PVec {
+        let mut v = self.clone();
+        if self.count - self.tail_offset() < WIDTH {
+            let mut tail = (*self.tail).clone();
+            tail.push(x);
+            v.tail = Rc::new(tail);
+        } else {
+            // tail 满了 放进树中
+            let leaf = Rc::new(TrieNode::Leaf((*self.tail).clone()));
+            if (self.count >> BITS) > (1 << self.shift) {
+                v.root = Rc::new(TrieNode::Branch(alloc::vec![
+                    self.root.clone(),
+                    new_path(self.shift, leaf)
+                ]));
+                v.shift += BITS;
+            } else {
+                v.root = self.push_tail(self.shift, &self.root, leaf);
+            }
+            v.tail = Rc::new(alloc::vec![x]);
+        }
+        v.count += 1;
+        v
+    }
+
+    fn push_tail(&self, level: usize, parent: &Rc<TrieNode>, leaf: Rc<TrieNode>) -> Rc<TrieNode> {
+        let mut children = parent.children().clone();
+        let sub = ((self.count - 1) >> level) & MASK;
+        let child = if level == BITS {
+            leaf
+        } else if sub < children.len() {
+            self.push_tail(level - BITS, &children[sub], leaf)
+        } else {
+            new_path(level - BITS, leaf)
+        };
+        if sub < children.len() {
+            children[sub] = child;
+        } else {
+            children.push(child);
+        }
+        Rc::new(TrieNode::Branch(children))
+    }
+
+    fn set(&self, i: usize, x: MalVal) -> PVec {
+        let i = i + self.start;
+        let mut v = self.clone();
+        if i >= self.tail_offset() {
+            let mut tail = (*self.tail).clone();
+            tail[i - self.tail_offset()] = x;
+            v.tail = Rc::new(tail);
+        } else {
+            v.root = set_in(self.shift, &self.root, i, x);
+        }
+        v
+    }
+
+    fn walk<'a>(&'a self, enter: &mut dyn FnMut(usize, usize) -> bool, visit: &mut dyn FnMut(&'a MalVal)) {
+        walk_node(&self.root, enter, visit);
+        if enter(Rc::as_ptr(&self.tail) as *const u8 as usize, Rc::strong_count(&self.tail)) {
+            self.tail.iter().for_each(|x| visit(x));
+        }
+    }
+}
+
+fn new_path(level: usize, leaf: Rc<TrieNode>) -> Rc<TrieNode> {
+    if level == 0 {
+        leaf
+    } else {
+        Rc::new(TrieNode::Branch(alloc::vec![new_path(level - BITS, leaf)]))
+    }
+}
+
+fn set_in(level: usize, node: &Rc<TrieNode>, i: usize, x: MalVal) -> Rc<TrieNode> {
+    match &**node {
+        TrieNode::Leaf(vals) => {
+            let mut vals = vals.clone();
+            vals[i & MASK] = x;
+            Rc::new(TrieNode::Leaf(vals))
+        }
+        TrieNode::Branch(children) => {
+            let mut children = children.clone();
+            let sub = (i >> level) & MASK;
+            children[sub] = set_in(level - BITS, &children[sub], i, x);
+            Rc::new(TrieNode::Branch(children))
+        }
+    }
+}
+
+fn walk_node<'a>(node: &'a Rc<TrieNode>, enter: &mut dyn FnMut(usize, usize) -> bool, visit: &mut dyn FnMut(&'a MalVal)) {
+    if !enter(Rc::as_ptr(node) as *const u8 as usize, Rc::strong_count(node)) {
+        return;
+    }
+    match &**node {
+        TrieNode::Branch(children) => children.iter().for_each(|c| walk_node(c, enter, visit)),
+        TrieNode::Leaf(vals) => vals.iter().for_each(|x| visit(x)),
+    }
+}
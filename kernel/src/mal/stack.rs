@@ -0,0 +1,103 @@
+// 求值用的栈
+// eval apply 和宏展开都在 rust 的栈上递归 内核栈很小而且没有保护页 溢出时会悄悄破坏内存
+// 所以最外层的求值切换到一块单独映射的大栈上进行 并且限制求值的深度
+// 超过限制时返回可以被 try* 捕获的 stack overflow 错误 大栈下面有不映射的保护页 限制失效时也只会触发缺页异常
+use crate::mal::types::MalErr::ErrString;
+use crate::mal::types::MalErr;
+use crate::mal::global::Global;
+use crate::memory::paging::alloc_stack;
+use alloc::string::ToString;
+
+// 求值栈的大小
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+// 在求值栈上允许的求值深度 每一层最多使用几 KB 的栈
+const MAX_DEPTH: usize = 10000;
+// 分配不到求值栈时 只能在内核栈上进行很浅的求值
+const FALLBACK_DEPTH: usize = 50;
+
+struct State {
+    top: Option<usize>, // 求值栈的栈顶 第一次求值时分配
+    active: bool,       // 是否已经在求值中
+    depth: usize,
+    limit: usize,
+}
+
+static EVAL_STACK: Global<State> = Global::new(State {
+    top: None,
+    active: false,
+    depth: 0,
+    limit: FALLBACK_DEPTH,
+});
+
+// 是否已经在求值中 嵌套的求值不需要再切换栈
+pub fn active() -> bool {
+    EVAL_STACK.borrow().active
+}
+
+// 在求值栈上执行最外层的求值 打印嵌套很深的结果也需要在这个栈上进行
+pub fn run<R>(f: impl FnOnce() -> R) -> R {
+    let top = {
+        let mut s = EVAL_STACK.borrow_mut();
+        if s.top.is_none() {
+            s.top = alloc_stack(STACK_SIZE);
+        }
+        s.active = true;
+        s.depth = 0;
+        s.limit = if s.top.is_some() { MAX_DEPTH } else { FALLBACK_DEPTH };
+        s.top
+    };
+    let mut f = Some(f);
+    let mut ret = None;
+    match top {
+        Some(top) => {
+            let mut call = || ret = f.take().map(|f| f());
+            unsafe { switch(top, &mut call) }
+        }
+        None => ret = f.take().map(|f| f()),
+    }
+    EVAL_STACK.borrow_mut().active = false;
+    ret.unwrap()
+}
+
+// 进入一层求值 超过深度限制时报错
+pub fn enter() -> Result<(), MalErr> {
+    let mut s = EVAL_STACK.borrow_mut();
+    if s.depth >= s.limit {
+        return Err(ErrString("stack overflow".to_string()));
+    }
+    s.depth += 1;
+    Ok(())
+}
+
+// 离开一层求值
+pub fn leave() {
+    EVAL_STACK.borrow_mut().depth -= 1;
+}
+
+// 把 rsp 切换到 top 调用 f 返回后切换回原来的栈
+// r12 由被调用的函数保存 用它记住原来的 rsp
+unsafe fn switch(top: usize, f: &mut dyn FnMut()) {
+    let mut f = f;
+    asm!(
+        "mov r12, rsp",
+        "mov rsp, {top}",
+        "call {entry}",
+        "mov rsp, r12",
+        top = in(reg) top,
+        entry = in(reg) entry as usize,
+        inout("rdi") &mut f as *mut &mut dyn FnMut() => _,
+        out("r12") _,
+        lateout("rax") _,
+        lateout("rcx") _,
+        lateout("rdx") _,
+        lateout("rsi") _,
+        lateout("r8") _,
+        lateout("r9") _,
+        lateout("r10") _,
+        lateout("r11") _,
+    );
+}
+
+extern "sysv64" fn entry(f: *mut &mut dyn FnMut()) {
+    unsafe { (*f)() }
+}
@@ -0,0 +1,129 @@
+// 字符串库
+// 下标和长度都按 unicode 字符计算 而不是字节
+use crate::format;
+use crate::mal::number::from_bigint;
+use crate::mal::convert::expected;
+use crate::mal::types::MalErr::ErrString;
+use crate::mal::types::MalVal::{Big, Float, Int, List, Nil, Str, Vector};
+use crate::mal::types::{error, MalErr, MalRet, MalVal};
+use crate::vector;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use num_bigint::BigInt;
+
+// 第 i 个字符的字节位置 i 等于字符数时返回字符串的长度
+fn byte_offset(s: &str, i: usize) -> Option<usize> {
+    s.char_indices().map(|(b, _)| b).chain(Some(s.len())).nth(i)
+}
+
+fn char_index(s: &str, byte: usize) -> MalVal {
+    Int(s[..byte].chars().count() as i64)
+}
+
+// (subs s start) (subs s start end)
+pub fn subs(s: &str, start: usize, end: Option<usize>) -> MalRet {
+    let end = end.unwrap_or_else(|| s.chars().count());
+    match (byte_offset(s, start), byte_offset(s, end)) {
+        (Some(b), Some(e)) if start <= end => Ok(Str(s[b..e].to_string())),
+        _ => error("subs: index out of range"),
+    }
+}
+
+// (split s sep) 得到向量 sep 为空字符串时拆成单个字符
+pub fn split(s: &str, sep: &str) -> MalRet {
+    let parts: Vec<MalVal> = if sep.is_empty() {
+        s.chars().map(|c| Str(c.to_string())).collect()
+    } else {
+        s.split(sep).map(|p| Str(p.to_string())).collect()
+    };
+    Ok(vector!(parts))
+}
+
+// (join coll) (join sep coll) 元素按 str 的方式转成字符串
+pub fn join(x: &MalVal, coll: Option<&MalVal>) -> MalRet {
+    let (sep, coll) = match (x, coll) {
+        (_, None) => ("", x),
+        (Str(sep), Some(coll)) => (sep.as_str(), coll),
+        (_, Some(_)) => return Err(expected("join", "string", x)),
+    };
+    match coll {
+        List(v, _) | Vector(v, _) => {
+            let parts: Vec<String> = v.iter().map(|x| x.pr_str(false)).collect();
+            Ok(Str(parts.join(sep)))
+        }
+        Nil => Ok(Str(String::new())),
+        _ => Err(expected("join", "list or vector", coll)),
+    }
+}
+
+// (index-of s sub) (index-of s sub from) 找不到时返回 nil
+pub fn index_of(s: &str, sub: &str, from: Option<usize>) -> MalRet {
+    let from = match byte_offset(s, from.unwrap_or(0)) {
+        Some(b) => b,
+        None => return Ok(Nil),
+    };
+    match s[from..].find(sub) {
+        Some(b) => Ok(char_index(s, from + b)),
+        None => Ok(Nil),
+    }
+}
+
+// (char-at s i) 第 i 个字符 结果是只有一个字符的字符串
+pub fn char_at(s: &str, i: usize) -> MalRet {
+    match s.chars().nth(i) {
+        Some(c) => Ok(Str(c.to_string())),
+        None => error("char-at: index out of range"),
+    }
+}
+
+// (replace s match replacement) 替换所有出现的 match
+pub fn replace(s: &str, from: &str, to: &str) -> MalRet {
+    if from.is_empty() {
+        return error("replace: match must not be empty");
+    }
+    Ok(Str(s.replace(from, to)))
+}
+
+fn radix(r: Option<i64>, name: &str) -> Result<u32, MalErr> {
+    match r {
+        None => Ok(10),
+        Some(r) if r >= 2 && r <= 36 => Ok(r as u32),
+        _ => Err(ErrString(format!("{}: radix must be between 2 and 36", name))),
+    }
+}
+
+// (parse-int s) (parse-int s radix) 不是合法的整数时返回 nil
+pub fn parse_int(s: &str, r: Option<i64>) -> MalRet {
+    let r = radix(r, "parse-int")?;
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(d) => (true, d),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(r)) {
+        return Ok(Nil);
+    }
+    match BigInt::parse_bytes(digits.as_bytes(), r) {
+        Some(b) if neg => Ok(from_bigint(-b)),
+        Some(b) => Ok(from_bigint(b)),
+        None => Ok(Nil),
+    }
+}
+
+// (parse-float s) 不是合法的数字时返回 nil
+pub fn parse_float(s: &str) -> MalVal {
+    match s.parse::<f64>() {
+        Ok(f) => Float(f),
+        Err(_) => Nil,
+    }
+}
+
+// (int->string n) (int->string n radix) 字母用小写
+pub fn int_to_string(n: &MalVal, r: Option<i64>) -> MalRet {
+    let r = radix(r, "int->string")?;
+    match n {
+        Int(n) => Ok(Str(BigInt::from(*n).to_str_radix(r))),
+        Big(b) => Ok(Str(b.to_str_radix(r))),
+        _ => Err(expected("int->string", "integer", n)),
+    }
+}
@@ -0,0 +1,234 @@
+// 符号表
+// 同名的符号共享一个编号和名字 比较和哈希只使用编号 不再比较字符串
+// gensym 会不断产生新的符号 符号表变大时清理没有被引用的符号 编号可以重新使用
+use crate::mal::global::Global;
+use alloc::rc::{Rc, Weak};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::cmp::Ordering;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::Deref;
+use hashbrown::HashMap;
+
+#[derive(Clone)]
+pub struct Symbol {
+    id: u32,
+    // 上次在局部环境中找到这个符号的槽位 见 env::Slots::get
+    slot: Cell<u32>,
+    name: Rc<str>,
+}
+
+// 预先登记的符号 编号就是在 KNOWN 中的位置 一直留在符号表中不会被清理
+// 求值器按编号分派特殊形式 内核按编号读取动态变量 不用再查符号表
+pub const DEF: u32 = 0;
+pub const DEF_DYNAMIC: u32 = 1;
+pub const BINDING: u32 = 2;
+pub const LET: u32 = 3;
+pub const LAMBDA: u32 = 4;
+pub const IF: u32 = 5;
+pub const DO: u32 = 6;
+pub const QUOTE: u32 = 7;
+pub const QUASIQUOTE: u32 = 8;
+pub const UNQUOTE: u32 = 9;
+pub const SPLICE_UNQUOTE: u32 = 10;
+pub const EVAL: u32 = 11;
+pub const MATCH: u32 = 12;
+pub const NS: u32 = 13;
+pub const REQUIRE: u32 = 14;
+pub const IMPORT: u32 = 15;
+pub const RELOAD: u32 = 16;
+pub const TRY: u32 = 17;
+pub const CATCH: u32 = 18;
+pub const DEFMACRO: u32 = 19;
+pub const MACROEXPAND: u32 = 20;
+pub const CONCAT: u32 = 21;
+pub const CONS: u32 = 22;
+pub const AMP: u32 = 23;
+pub const AMP_OPTIONAL: u32 = 24;
+pub const UNDERSCORE: u32 = 25;
+pub const OUT: u32 = 26;
+pub const PRINT_LENGTH: u32 = 27;
+pub const CURRENT_DIR: u32 = 28;
+pub const TRACE: u32 = 29;
+pub const NS_NAME: u32 = 30;
+pub const EXPORTS: u32 = 31;
+pub const ALIASES: u32 = 32;
+
+const KNOWN: [&str; 33] = [
+    "def!", "def-dynamic", "binding", "let*", "lambda", "if", "do", "quote", "quasiquote",
+    "unquote", "splice-unquote", "eval", "match", "ns", "require", "import", "reload", "try*",
+    "catch*", "defmacro!", "macroexpand", "concat", "cons", "&", "&optional", "_", "*out*",
+    "*print-length*", "*current-dir*", "*trace*", "*ns*", "*exports*", "*aliases*",
+];
+
+// 还没有记下槽位
+const NO_SLOT: u32 = u32::MAX;
+
+struct Table {
+    names: Option<HashMap<String, (u32, Weak<str>)>>,
+    known: Vec<Rc<str>>, // 预先登记的符号的名字 保持引用所以不会被清理
+    free: Vec<u32>,      // 可以重新使用的编号
+    next: u32,
+    live: usize, // 上次清理后剩下的符号数
+}
+
+static SYMBOLS: Global<Table> = Global::new(Table {
+    names: None,
+    known: Vec::new(),
+    free: Vec::new(),
+    next: 0,
+    live: 0,
+});
+
+impl Table {
+    // 第一次使用时登记 KNOWN 中的符号
+    fn names(&mut self) -> &mut HashMap<String, (u32, Weak<str>)> {
+        if self.names.is_none() {
+            let mut names = HashMap::new();
+            for (id, name) in KNOWN.iter().enumerate() {
+                let rc: Rc<str> = Rc::from(*name);
+                names.insert(String::from(*name), (id as u32, Rc::downgrade(&rc)));
+                self.known.push(rc);
+            }
+            self.next = KNOWN.len() as u32;
+            self.names = Some(names);
+        }
+        self.names.as_mut().unwrap()
+    }
+}
+
+// 符号表小于这个大小时不清理
+const SWEEP_MIN: usize = 1024;
+
+impl Symbol {
+    // 登记一个名字 已经有同名的符号时使用同一个编号
+    pub fn new(name: &str) -> Symbol {
+        let mut t = SYMBOLS.borrow_mut();
+        t.names();
+        let t = &mut *t;
+        let names = t.names.as_mut().unwrap();
+        if let Some((id, w)) = names.get(name) {
+            if let Some(rc) = w.upgrade() {
+                return Symbol::with(*id, rc);
+            }
+        }
+        if names.len() >= core::cmp::max(SWEEP_MIN, t.live * 2) {
+            let free = &mut t.free;
+            names.retain(|_, (id, w)| {
+                if w.strong_count() > 0 {
+                    return true;
+                }
+                free.push(*id);
+                false
+            });
+            t.live = names.len();
+        }
+        let id = match names.remove(name) {
+            Some((id, _)) => id,
+            None => match t.free.pop() {
+                Some(id) => id,
+                None => {
+                    t.next += 1;
+                    t.next - 1
+                }
+            },
+        };
+        let rc: Rc<str> = Rc::from(name);
+        names.insert(String::from(name), (id, Rc::downgrade(&rc)));
+        Symbol::with(id, rc)
+    }
+
+    // 预先登记的符号 例如 Symbol::known(symbol::LAMBDA)
+    pub fn known(id: u32) -> Symbol {
+        let mut t = SYMBOLS.borrow_mut();
+        t.names();
+        Symbol::with(id, t.known[id as usize].clone())
+    }
+
+    fn with(id: u32, name: Rc<str>) -> Symbol {
+        Symbol { id, slot: Cell::new(NO_SLOT), name }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    // 上次找到这个符号的槽位
+    pub fn slot(&self) -> Option<usize> {
+        match self.slot.get() {
+            NO_SLOT => None,
+            i => Some(i as usize),
+        }
+    }
+
+    pub fn set_slot(&self, i: usize) {
+        self.slot.set(i as u32);
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Symbol {}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        &*self.name == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Symbol {
+    fn eq(&self, other: &&'a str) -> bool {
+        &*self.name == *other
+    }
+}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+// 排序按名字
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Symbol) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Symbol) -> Ordering {
+        match self.id == other.id {
+            true => Ordering::Equal,
+            false => self.name.cmp(&other.name),
+        }
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", &*self.name)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", &*self.name)
+    }
+}
@@ -0,0 +1,46 @@
+// lisp 层面的调用栈
+// 每次函数调用都会把调用处的代码压入栈中 出错时把当时的调用栈记录到错误里
+use crate::format;
+use crate::mal::types::MalVal;
+use crate::mal::types::MalVal::{List, Sym};
+use crate::mal::global::Global;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+static CALL_STACK: Global<Vec<MalVal>> = Global::new(Vec::new());
+
+// 当前调用栈的深度
+pub fn depth() -> usize {
+    CALL_STACK.borrow().len()
+}
+
+// 压入一次调用 form 是调用处的代码 例如 (f 1 2)
+pub fn push(form: MalVal) {
+    CALL_STACK.borrow_mut().push(form);
+}
+
+// 回退到指定的深度 函数返回 或者尾调用替换掉当前帧时使用
+pub fn truncate(depth: usize) {
+    CALL_STACK.borrow_mut().truncate(depth);
+}
+
+// 一帧的描述 例如 say-hello (hello.jmal:3:1)
+fn describe(form: &MalVal) -> String {
+    let name = match form {
+        List(l, _) if l.len() > 0 => match &l[0] {
+            Sym(s) => s.to_string(),
+            List(_, _) => "<lambda>".to_string(),
+            f => f.pr_str(true),
+        },
+        _ => "<unknown>".to_string(),
+    };
+    match form.location() {
+        Some(loc) => format!("{} ({})", name, loc),
+        None => name,
+    }
+}
+
+// 记录下当前的调用栈 最里层的调用在最前面
+pub fn snapshot() -> Vec<String> {
+    CALL_STACK.borrow().iter().rev().map(describe).collect()
+}
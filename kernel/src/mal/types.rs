@@ -1,32 +1,55 @@
+use alloc::boxed::Box;
 use alloc::rc::Rc;
 use alloc::string::{String,ToString};
-use crate::mal::types::MalErr::{ErrString,ErrMalVal};
+use crate::mal::types::MalErr::{ErrString,ErrMalVal,ErrAt,ErrTrace,ErrCont};
 use alloc::vec::Vec;
 use core::cell::RefCell;
-use hashbrown::HashMap;
-use crate::mal::types::MalVal::{Hash,Str,Nil,Func,Bool,Int,Sym,List,Vector,MalFunc,Atom};
-use crate::mal::env::{Env,env_bind};
+use core::hash::{Hash as StdHash, Hasher};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use crate::mal::types::MalVal::{Hash,Set,Str,Nil,Func,Bool,Int,Float,Big,Ratio,Sym,Keyword,List,Vector,Lazy,MalFunc,Atom,Cont,Closure as ClosureVal,Error,Native};
+use crate::mal::env::{self,Env,env_bind};
+use crate::mal::cont::throw;
+use crate::mal::vm::{self, Closure};
+use crate::mal::seq::Seq;
+use crate::mal::pmap::{PMap, PSet};
+use crate::mal::gc::{self, Tracked};
+use crate::mal::lazy::{self, LazySeq};
+use crate::mal::symbol::Symbol;
+use crate::mal::exception::{self, ErrorInfo};
+use crate::mal::convert::expected;
 
 #[derive(Debug,Clone)]
 pub enum MalVal{
     Nil,
     Bool(bool), //布尔类型
     Int(i64),   // int类型
+    Float(f64), // 浮点类型
+    Big(Rc<BigInt>), // 超出 i64 范围的大整数
+    Ratio(Rc<BigRational>), // 分数
     Str(String), // 字符串类型
-    Sym(String),
-    List(Rc<Vec<MalVal>>, Rc<MalVal>),  // 列表类型
-    Vector(Rc<Vec<MalVal>>, Rc<MalVal>), // 向量类型
-    Hash(Rc<HashMap<String,MalVal>>,Rc<MalVal>), // hashMap 类型
+    Sym(Symbol),
+    Keyword(String), // 关键字 :a 只保存冒号后面的名字
+    List(Seq, Rc<MalVal>),  // 列表类型
+    Vector(Seq, Rc<MalVal>), // 向量类型
+    Hash(PMap,Rc<MalVal>), // hashMap 类型 key 可以是任意的 MalVal
+    Set(PSet,Rc<MalVal>), // 集合类型
+    Lazy(Rc<LazySeq>), // 惰性序列 元素在使用时才求值
     Func(fn(MalArgs) -> MalRet,Rc<MalVal>), //函数 相当于 lambda (x)-> M
+    Native(Rc<NativeFn>, Rc<MalVal>), // 可以捕获状态的 Rust 函数 例如绑定了某个设备或 inode 的函数
     MalFunc {
         eval: fn(ast: MalVal, env: Env) -> MalRet,
         ast: Rc<MalVal>, // 函数 抽象语法树
         env: Env,    // repl 环境
         params: Rc<MalVal>,  // 参数值  TODO： 其实可以单值然后用柯里化
+                             // 有多个参数个数的函数为 nil 这时 ast 是 ((参数 函数体) ...)
         is_macro: bool,    // 是否是宏
         meta: Rc<MalVal>,   // 元数据
     },
-    Atom(Rc<RefCell<MalVal>>) //原子
+    Atom(Rc<RefCell<MalVal>>), //原子
+    Cont(usize), // call/cc 得到的 continuation 保存的是它的编号
+    Closure(Rc<Closure>, Rc<MalVal>), // 编译成字节码的函数 由虚拟机执行
+    Error(Rc<ErrorInfo>), // 结构化的错误 由 ex-info 或带类别的内部错误产生
 }
 
 // Mal 报错结构
@@ -34,6 +57,9 @@ pub enum MalVal{
 pub enum MalErr {
     ErrString(String),
     ErrMalVal(MalVal),
+    ErrAt(Box<MalErr>, String), // 附带出错代码的位置 例如 entry.jmal:3:5
+    ErrTrace(Box<MalErr>, Vec<String>), // 附带出错时 lisp 的调用栈 最里层的调用在最前面
+    ErrCont(usize, MalVal), // 调用 continuation 跳回编号对应的 call/cc 并不是真正的错误
 }
 
 // Mal 入参
@@ -44,22 +70,22 @@ pub type MalRet = Result<MalVal, MalErr>;
 #[macro_export]
 macro_rules! list {
     ($seq:expr) => {{
-      List(Rc::new($seq),Rc::new(Nil))
+      List($crate::mal::seq::Seq::list($seq),Rc::new(Nil))
     }};
     [$($args:expr),*] => {{
       let v: Vec<MalVal> = vec![$($args),*];
-      List(Rc::new(v),Rc::new(Nil))
+      List($crate::mal::seq::Seq::list(v),Rc::new(Nil))
     }}
 }
 
 #[macro_export]
 macro_rules! vector {
     ($seq:expr) => {{
-      Vector(Rc::new($seq),Rc::new(Nil))
+      Vector($crate::mal::seq::Seq::vector($seq),Rc::new(Nil))
     }};
     [$($args:expr),*] => {{
       let v: Vec<MalVal> = vec![$($args),*];
-      Vector(Rc::new(v),Rc::new(Nil))
+      Vector($crate::mal::seq::Seq::vector(v),Rc::new(Nil))
     }}
 }
 
@@ -86,43 +112,94 @@ pub fn error(s: &str) -> MalRet {
     Err(ErrString(s.to_string()))
 }
 
+// 参数个数不对的错误 例如 f expects 1 to 2 args, got 3
+pub fn arity_error(name: &str, min: usize, max: Option<usize>, got: usize) -> MalErr {
+    let expected = match max {
+        Some(max) if max == min => format!("{}", min),
+        Some(max) => format!("{} to {}", min, max),
+        None => format!("at least {}", min),
+    };
+    exception::raise("arity", format!("{} expects {} args, got {}", name, expected, got))
+}
+
+// 打印错误时最多显示的调用帧
+const MAX_TRACE_FRAMES: usize = 20;
+
 //格式化错误输出
 pub fn format_error(e: MalErr) -> String {
     match e {
         ErrString(s) => s.clone(),
+        ErrMalVal(Error(e)) => exception::describe(&e),
         ErrMalVal(mv) => mv.pr_str(true),
+        ErrAt(e, loc) => format!("{} (at {})", format_error(*e), loc),
+        ErrTrace(e, trace) => {
+            let mut s = format_error(*e);
+            // 栈溢出时调用栈很长 只显示最里面的几层
+            for frame in trace.iter().take(MAX_TRACE_FRAMES) {
+                s.push_str(&format!("\n    at {}", frame));
+            }
+            if trace.len() > MAX_TRACE_FRAMES {
+                s.push_str(&format!("\n    ... {} more", trace.len() - MAX_TRACE_FRAMES));
+            }
+            s
+        }
+        ErrCont(_, _) => String::from("continuation called outside of its call/cc"),
+    }
+}
+
+impl MalErr {
+    // 给错误附上出错的位置 已经带有位置的错误保持最里层的位置不变
+    pub fn at(self, loc: String) -> MalErr {
+        match self {
+            ErrAt(_, _) | ErrCont(_, _) => self,
+            ErrTrace(e, trace) => ErrTrace(Box::new(e.at(loc)), trace),
+            e => ErrAt(Box::new(e), loc),
+        }
+    }
+
+    // 给错误附上调用栈 只记录最早(最里层)得到的调用栈
+    pub fn with_trace(self, trace: fn() -> Vec<String>) -> MalErr {
+        match self {
+            ErrTrace(_, _) | ErrCont(_, _) => self,
+            e => match trace() {
+                t if t.len() == 0 => e,
+                t => ErrTrace(Box::new(e), t),
+            },
+        }
+    }
+
+    // 出错时的调用栈
+    pub fn trace(&self) -> Vec<String> {
+        match self {
+            ErrTrace(_, trace) => trace.clone(),
+            ErrAt(e, _) => e.trace(),
+            _ => Vec::new(),
+        }
+    }
+
+    // 去掉位置和调用栈 得到原始的错误
+    pub fn inner(&self) -> &MalErr {
+        match self {
+            ErrAt(e, _) | ErrTrace(e, _) => e.inner(),
+            e => e,
+        }
     }
 }
 
 // 把参数 变成hashmap
-pub fn _assoc(mut hm: HashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
+pub fn _assoc(mut hm: PMap, kvs: MalArgs) -> MalRet {
     if kvs.len() % 2 != 0 {
         return error("odd number of elements");
     }
-    let mut itre = kvs.iter();
-    loop{
-        let k = itre.next();
-        match k {
-            Some(Str(s))=>{
-                match itre.next() {
-                    Some(v) => {
-                        hm.insert(s.to_string(), v.clone());
-                    },
-                    // 这里应该永远也不会发生
-                    None => return error("key to value,vlaue is not a MalVal"),
-                }
-            },
-            None => break,
-            _ => return error("key is not string"),
-        }
+    for kv in kvs.chunks(2) {
+        hm = hm.insert(kv[0].clone(), kv[1].clone());
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(Hash(hm, Rc::new(Nil)))
 }
 
 // 创建hashmap
 pub fn hash_map(kvs: MalArgs) -> MalRet {
-    let hm: HashMap<String, MalVal> = HashMap::new();
-    _assoc(hm, kvs)
+    _assoc(PMap::new(), kvs)
 }
 
 // 创建一个函数
@@ -130,9 +207,38 @@ pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
     Func(f, Rc::new(Nil))
 }
 
+// 带有名字和参数个数的 Rust 闭包
+pub struct NativeFn {
+    pub name: String,
+    pub min: usize,
+    pub max: Option<usize>, // None 表示参数个数没有上限
+    pub f: Rc<dyn Fn(MalArgs) -> MalRet>,
+}
+
+impl core::fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "NativeFn({})", self.name)
+    }
+}
+
+// 创建一个可以捕获状态的函数 调用时先检查参数个数
+pub fn native(name: &str, min: usize, max: Option<usize>, f: impl Fn(MalArgs) -> MalRet + 'static) -> MalVal {
+    Native(
+        Rc::new(NativeFn {
+            name: name.to_string(),
+            min,
+            max,
+            f: Rc::new(f),
+        }),
+        Rc::new(Nil),
+    )
+}
+
 // 创造一个原子
 pub fn atom(mv:&MalVal) ->MalVal {
-    Atom(Rc::new(RefCell::new(mv.clone())))
+    let a = Rc::new(RefCell::new(mv.clone()));
+    gc::track(Tracked::Atom(Rc::downgrade(&a)));
+    Atom(a)
 }
 
 // 实现比较方法 判断两个 MalVal 是否相等
@@ -142,30 +248,78 @@ impl PartialEq for MalVal {
             (Nil, Nil) => true,
             (Bool(ref a), Bool(ref b)) => a == b,
             (Int(ref a), Int(ref b)) => a == b,
+            (Float(ref a), Float(ref b)) => a == b,
+            (Big(ref a), Big(ref b)) => a == b,
+            (Ratio(ref a), Ratio(ref b)) => a == b,
             (Str(ref a), Str(ref b)) => a == b,
             (Sym(ref a), Sym(ref b)) => a == b,
+            (Keyword(ref a), Keyword(ref b)) => a == b,
             (List(ref a, _), List(ref b, _))
             | (Vector(ref a, _), Vector(ref b, _))
             | (List(ref a, _), Vector(ref b, _))
             | (Vector(ref a, _), List(ref b, _)) => a == b,
             (Hash(ref a, _), Hash(ref b, _)) => a == b,
+            (Set(ref a, _), Set(ref b, _)) => a == b,
+            // 惰性序列和列表 向量按元素比较 需要求出全部元素
+            (Lazy(_), List(..)) | (Lazy(_), Vector(..)) | (Lazy(_), Lazy(_)) | (List(..), Lazy(_)) | (Vector(..), Lazy(_)) => {
+                match (lazy::to_vec(self), lazy::to_vec(other)) {
+                    (Ok(a), Ok(b)) => a == b,
+                    _ => false,
+                }
+            }
             (MalFunc { .. }, MalFunc { .. }) => false, // 两个函数永远也不能相同！
+            (Cont(ref a), Cont(ref b)) => a == b,
+            (Error(ref a), Error(ref b)) => Rc::ptr_eq(a, b),
+            (Native(ref a, _), Native(ref b, _)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
 }
 
-// 删除hash map 中指定的key, 并且返回 删除后的map,不改变原来的值
-pub fn _dissoc(mut hm: HashMap<String, MalVal>, ks: MalArgs) -> MalRet {
-    for k in ks.iter() {
-        match k {
-            Str(ref s) => {
-                hm.remove(s);
+impl Eq for MalVal {}
+
+// 实现哈希 使任意的 MalVal 都可以作为 hash map 的 key 相等的两个值哈希也必须相等
+impl StdHash for MalVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            // 列表 向量和惰性序列的元素相同时是相等的 所以不哈希类别 用同样的方式哈希元素
+            List(v, _) | Vector(v, _) => v.hash(state),
+            Lazy(_) => {
+                if let Ok(v) = lazy::to_vec(self) {
+                    Seq::list(v).hash(state);
+                }
+            }
+            _ => {
+                // 其他类别之间永远不相等 先哈希类别
+                core::mem::discriminant(self).hash(state);
+                match self {
+                    Bool(b) => b.hash(state),
+                    Int(i) => i.hash(state),
+                    // 0.0 和 -0.0 是相等的
+                    Float(f) if *f == 0.0 => 0u64.hash(state),
+                    Float(f) => f.to_bits().hash(state),
+                    Big(b) => b.hash(state),
+                    Ratio(r) => r.hash(state),
+                    Str(s) | Keyword(s) => s.hash(state),
+                    Sym(s) => s.hash(state),
+                    // hash map 的遍历顺序不固定 只使用长度
+                    Hash(hm, _) => hm.len().hash(state),
+                    Set(s, _) => s.len().hash(state),
+                    Cont(id) => id.hash(state),
+                    // nil 只有类别 函数 原子和错误值只按类别哈希
+                    _ => {}
+                }
             }
-            _ => return error("key is not string"),
         }
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+}
+
+// 删除hash map 中指定的key, 并且返回 删除后的map,不改变原来的值
+pub fn _dissoc(mut hm: PMap, ks: MalArgs) -> MalRet {
+    for k in ks.iter() {
+        hm = hm.remove(k);
+    }
+    Ok(Hash(hm, Rc::new(Nil)))
 }
 
 impl MalVal {
@@ -173,53 +327,57 @@ impl MalVal {
     pub fn apply(&self, args: MalArgs) -> MalRet {
         match *self {
             Func(f, _) => f(args),
-            MalFunc {
-                eval,
-                ref ast,
-                ref env,
-                ref params,
-                ..
-            } => {
-                let a = &**ast;
-                let p = &**params;
+            Native(ref n, _) => {
+                if args.len() < n.min || n.max.map_or(false, |max| args.len() > max) {
+                    return Err(arity_error(&n.name, n.min, n.max, args.len()));
+                }
+                (n.f)(args)
+            }
+            MalFunc { eval, .. } => {
                 // 给环境中的变量绑定值 相当于形参绑定实参  然后返回一个子环境 注意这个子环境是可以追溯到母环境的绑定的
-                let fn_env = env_bind(Some(env.clone()), p.clone(), args)?;
-                Ok(eval(a.clone(), fn_env)?)
+                let (body, fn_env) = self.fn_env(args)?;
+                Ok(eval(body, fn_env)?)
             }
-            _ => error("attempt to call non-function"),
-        }
-    }
-
-    // 获取一个原子所对应的值
-    pub fn deref(&self) -> MalRet {
-        match self {
-            Atom(a) => Ok(a.borrow().clone()),
-            _ => error("attempt to deref a non-Atom")
+            Cont(id) => throw(id, args),
+            ClosureVal(ref c, _) => vm::call(c, args),
+            _ => Err(exception::raise("type", "attempt to call non-function".to_string())),
         }
     }
 
-    // 重新绑定原子， 使他指向这个新的ast对象，下面的new
-    pub fn reset_bang(&self, new: &MalVal) -> MalRet {
-        match self {
-            Atom(a) => {
-                *a.borrow_mut() = new.clone();
-                Ok(new.clone())
+    // 调用 lisp 函数时检查参数个数并绑定参数 返回函数体和绑定了参数的环境
+    // 有多个参数个数的函数选出第一个接受这些参数的函数体
+    pub fn fn_env(&self, args: MalArgs) -> Result<(MalVal, Env), MalErr> {
+        let (ast, fenv, params) = match self {
+            MalFunc { ast, env, params, .. } => (ast, env, params),
+            _ => return Err(exception::raise("type", "attempt to call non-function".to_string())),
+        };
+        let (params, body) = match (&**params, &**ast) {
+            (Nil, List(clauses, _)) => clauses
+                .iter()
+                .find_map(|c| match c {
+                    List(c, _) if env::accepts(&c[0], args.len()) => Some((c[0].clone(), c[1].clone())),
+                    _ => None,
+                })
+                .ok_or_else(|| exception::raise("arity", format!("wrong number of args ({}) passed to {}", args.len(), self.fn_name())))?,
+            (p, a) => {
+                if !env::accepts(p, args.len()) {
+                    let (min, max) = env::arity(p);
+                    return Err(arity_error(&self.fn_name(), min, max, args.len()));
+                }
+                (p.clone(), a.clone())
             }
-            _ => error("attempt to reset! a non-Atom"),
-        }
+        };
+        Ok((body, env_bind(Some(fenv.clone()), params, args)?))
     }
 
-    // 对于一个atom 输入一个函数 然后把atom的值加在最开始作为输入使用进行求值 并且更新原子的值
-    pub fn swap_bang(&self, args: &MalArgs) -> MalRet {
-        match self {
-            Atom(a) => {
-                let f = &args[0];
-                let mut fargs = args[1..].to_vec();
-                fargs.insert(0, a.borrow().clone());
-                *a.borrow_mut() = f.apply(fargs)?;
-                Ok(a.borrow().clone())
-            }
-            _ => error("attempt to swap! a non-Atom"),
+    // 报错时使用的函数名 def! 定义的函数记录在元数据里
+    fn fn_name(&self) -> String {
+        match self.meta() {
+            Hash(m, _) => match m.get(&Keyword("name".to_string())) {
+                Some(n) => n.pr_str(false),
+                None => String::from("<lambda>"),
+            },
+            _ => String::from("<lambda>"),
         }
     }
 
@@ -227,25 +385,116 @@ impl MalVal {
     pub fn empty_q(&self) -> MalRet {
         match self {
             List(l,_) | Vector(l,_) => Ok(Bool(l.len()==0)),
+            Set(s,_) => Ok(Bool(s.is_empty())),
+            Lazy(l) => Ok(Bool(lazy::force(l)?.is_none())),
+            Str(s) => Ok(Bool(s.is_empty())),
             Nil => Ok(Bool(true)),
-            _ => error("invalid empty value!"),
+            _ => Err(expected("empty?", "collection or string", self)),
         }
     }
 
     pub fn count(&self) -> MalRet {
         match self{
             List(l, _) | Vector(l, _) => Ok(Int(l.len() as i64)),
+            Set(s, _) => Ok(Int(s.len() as i64)),
+            // 惰性序列需要求出全部元素
+            Lazy(_) => Ok(Int(lazy::to_vec(self)?.len() as i64)),
+            // 字符串的长度按字符计算
+            Str(s) => Ok(Int(s.chars().count() as i64)),
             Nil => Ok(Int(0)),
-            _ => error("invalid type for count"),
+            _ => Err(expected("count", "collection or string", self)),
+        }
+    }
+
+    // 读入代码时记录在元数据里的位置 例如 entry.jmal:3:5
+    pub fn location(&self) -> Option<String> {
+        match self {
+            List(_, meta) => match &**meta {
+                Hash(hm, _) => match (
+                    hm.get(&Keyword("file".to_string())),
+                    hm.get(&Keyword("line".to_string())),
+                    hm.get(&Keyword("column".to_string())),
+                ) {
+                    (Some(Str(f)), Some(Int(l)), Some(Int(c))) => Some(format!("{}:{}:{}", f, l, c)),
+                    (None, Some(Int(l)), Some(Int(c))) => Some(format!("{}:{}", l, c)),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // 元数据 不能带元数据的值返回 nil
+    pub fn meta(&self) -> MalVal {
+        match self {
+            List(_, m) | Vector(_, m) | Hash(_, m) | Set(_, m) | Func(_, m) | Native(_, m) | MalFunc { meta: m, .. } | ClosureVal(_, m) => {
+                (**m).clone()
+            }
+            _ => Nil,
+        }
+    }
+
+    // 返回换上新元数据的值 原来的值不变
+    pub fn with_meta(&self, meta: &MalVal) -> MalRet {
+        let m = match meta {
+            Hash(_, _) | Nil => Rc::new(meta.clone()),
+            _ => return Err(expected("with-meta", "map or nil", meta)),
+        };
+        match self.clone() {
+            List(l, _) => Ok(List(l, m)),
+            Vector(v, _) => Ok(Vector(v, m)),
+            Hash(hm, _) => Ok(Hash(hm, m)),
+            Set(s, _) => Ok(Set(s, m)),
+            Func(f, _) => Ok(Func(f, m)),
+            Native(n, _) => Ok(Native(n, m)),
+            MalFunc {
+                eval,
+                ast,
+                env,
+                params,
+                is_macro,
+                ..
+            } => Ok(MalFunc {
+                eval,
+                ast,
+                env,
+                params,
+                is_macro,
+                meta: m,
+            }),
+            ClosureVal(c, _) => Ok(ClosureVal(c, m)),
+            _ => Err(expected("with-meta", "collection or function", self)),
+        }
+    }
+
+    // 把 kvs 合并到原来的元数据中 不能带元数据的值原样返回
+    pub fn add_meta(&self, kvs: &MalVal) -> MalRet {
+        if !self.has_meta() {
+            return Ok(self.clone());
+        }
+        match (self.meta(), kvs) {
+            (Hash(old, _), Hash(new, _)) => {
+                let merged = new.iter().fold(old, |m, (k, v)| m.insert(k.clone(), v.clone()));
+                self.with_meta(&Hash(merged, Rc::new(Nil)))
+            }
+            _ => self.with_meta(kvs),
+        }
+    }
+
+    fn has_meta(&self) -> bool {
+        match self {
+            List(..) | Vector(..) | Hash(..) | Set(..) | Func(..) | MalFunc { .. } | ClosureVal(..) => true,
+            _ => false,
         }
     }
 
     // 将Str转换成关键字
     pub fn keyword(&self) -> MalRet {
         match self {
-            Str(s) if s.starts_with("\u{29e}") => Ok(Str(s.to_string())),
-            Str(s) => Ok(Str(format!("\u{29e}{}", s))),
-            _ => error("invalid type for keyword"),
+            Keyword(_) => Ok(self.clone()),
+            Str(s) => Ok(Keyword(s.to_string())),
+            _ => Err(expected("keyword", "string or keyword", self)),
         }
     }
 
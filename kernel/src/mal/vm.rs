@@ -0,0 +1,322 @@
+// 运行字节码的虚拟机
+// 每次调用编译后的函数都会创建一个帧 保存参数和局部变量 闭包通过帧的 outer 访问外层函数的变量
+use crate::format;
+use crate::fs::{inode_ext::INodeExt, ROOT_INODE};
+use crate::mal::bytecode::{decode, encode, module_hash, source_hash, Op, Proto};
+use crate::mal::compiler::compile_top;
+use crate::mal::core::core_lib_hash;
+use crate::mal::env::{env_get, env_set, Env};
+use crate::mal::convert::expected;
+use crate::mal::exception;
+use crate::mal::eval;
+use crate::mal::gc::{self, Tracked};
+use crate::mal::module;
+use crate::mal::stack;
+use crate::mal::trace;
+use crate::mal::types::MalErr::ErrCont;
+use crate::mal::types::MalVal::{Bool, Closure as ClosureVal, List, MalFunc, Nil, Set, Str, Vector};
+use crate::mal::types::{arity_error, error, hash_map, MalArgs, MalErr, MalRet, MalVal};
+use crate::{list, vector};
+use alloc::rc::Rc;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+#[derive(Debug)]
+pub struct Frame {
+    pub slots: RefCell<Vec<MalVal>>,
+    pub outer: Option<Rc<Frame>>,
+}
+
+// 编译后的函数 和它创建时所在的帧 以及查找全局符号用的环境
+#[derive(Debug)]
+pub struct Closure {
+    pub proto: Rc<Proto>,
+    pub frame: Option<Rc<Frame>>,
+    pub env: Env,
+}
+
+// try* 的处理位置
+struct Handler {
+    pc: usize,
+    sp: usize,
+    exc: u16,
+    trace: u16,
+}
+
+// 创建调用帧 绑定参数 参数个数不对时报错
+fn new_frame(c: &Closure, mut args: MalArgs) -> Result<Rc<Frame>, MalErr> {
+    let p = &c.proto;
+    let arity = p.arity as usize;
+    if args.len() < arity || !p.rest && args.len() > arity {
+        let name = p.name.clone().unwrap_or("<lambda>".to_string());
+        let max = if p.rest { None } else { Some(arity) };
+        return Err(arity_error(&name, arity, max, args.len()));
+    }
+    let rest = if p.rest { args.split_off(arity) } else { Vec::new() };
+    args.truncate(arity);
+    if p.rest {
+        args.push(list!(rest));
+    }
+    args.resize(p.nslots as usize, Nil);
+    let frame = Rc::new(Frame {
+        slots: RefCell::new(args),
+        outer: c.frame.clone(),
+    });
+    gc::track(Tracked::Frame(Rc::downgrade(&frame)));
+    Ok(frame)
+}
+
+fn root_env(env: &Env) -> Env {
+    let mut root = env.clone();
+    while let Some(ref e) = root.clone().outer {
+        root = e.clone();
+    }
+    root
+}
+
+// 调用编译后的函数
+pub fn call(c: &Rc<Closure>, args: MalArgs) -> MalRet {
+    stack::enter()?;
+    let depth = trace::depth();
+    let ret = run(c, args, depth);
+    trace::truncate(depth);
+    stack::leave();
+    ret
+}
+
+fn run(c: &Rc<Closure>, args: MalArgs, depth: usize) -> MalRet {
+    let mut proto = c.proto.clone();
+    let mut env = c.env.clone();
+    let mut frame = new_frame(c, args)?;
+    let mut pc = 0;
+    let mut stack: Vec<MalVal> = Vec::new();
+    let mut handlers: Vec<Handler> = Vec::new();
+    loop {
+        let op = proto.code[pc];
+        pc += 1;
+        let res: Result<(), MalErr> = match op {
+            Op::Const(i) => {
+                stack.push(proto.consts[i as usize].clone());
+                Ok(())
+            }
+            Op::Nil => {
+                stack.push(Nil);
+                Ok(())
+            }
+            Op::Local(d, s) => {
+                let mut f = &frame;
+                for _ in 0..d {
+                    f = f.outer.as_ref().unwrap();
+                }
+                stack.push(f.slots.borrow()[s as usize].clone());
+                Ok(())
+            }
+            Op::SetLocal(s) => {
+                let v = stack.pop().unwrap();
+                frame.slots.borrow_mut()[s as usize] = v;
+                Ok(())
+            }
+            Op::Global(i) => env_get(&env, &proto.consts[i as usize]).map(|v| stack.push(v)),
+            Op::Def(i) => {
+                let v = stack.last().unwrap().clone();
+                env_set(&env, proto.consts[i as usize].clone(), v).map(|_| ())
+            }
+            Op::Pop => {
+                stack.pop();
+                Ok(())
+            }
+            Op::Jump(to) => {
+                pc = to as usize;
+                Ok(())
+            }
+            Op::JumpIfNot(to) => {
+                match stack.pop().unwrap() {
+                    Bool(false) | Nil => pc = to as usize,
+                    _ => (),
+                }
+                Ok(())
+            }
+            Op::Closure(i) => {
+                let c = Rc::new(Closure {
+                    proto: proto.protos[i as usize].clone(),
+                    frame: Some(frame.clone()),
+                    env: env.clone(),
+                });
+                gc::track(Tracked::Closure(Rc::downgrade(&c)));
+                stack.push(ClosureVal(c, Rc::new(Nil)));
+                Ok(())
+            }
+            Op::Call(n, site) | Op::TailCall(n, site) => {
+                let args = stack.split_off(stack.len() - n as usize);
+                let f = stack.pop().unwrap();
+                let site = &proto.sites[site as usize];
+                match (op, f) {
+                    // 尾调用编译后的函数 替换掉当前的帧
+                    (Op::TailCall(_, _), ClosureVal(c, _)) => match new_frame(&c, args) {
+                        Ok(new) => {
+                            trace::truncate(depth);
+                            trace::push(site.clone());
+                            proto = c.proto.clone();
+                            env = c.env.clone();
+                            frame = new;
+                            pc = 0;
+                            stack.clear();
+                            Ok(())
+                        }
+                        Err(e) => Err(at_site(e, site)),
+                    },
+                    (_, f) => {
+                        let d = trace::depth();
+                        trace::push(site.clone());
+                        let r = match f {
+                            ClosureVal(ref c, _) => call(c, args),
+                            _ => f.apply(args),
+                        };
+                        match r {
+                            Ok(v) => {
+                                trace::truncate(d);
+                                stack.push(v);
+                                Ok(())
+                            }
+                            Err(e) => {
+                                let e = at_site(e, site).with_trace(trace::snapshot);
+                                trace::truncate(d);
+                                Err(e)
+                            }
+                        }
+                    }
+                }
+            }
+            Op::Return => return Ok(stack.pop().unwrap_or(Nil)),
+            Op::Try(to, exc, tr) => {
+                handlers.push(Handler {
+                    pc: to as usize,
+                    sp: stack.len(),
+                    exc,
+                    trace: tr,
+                });
+                Ok(())
+            }
+            Op::EndTry => {
+                handlers.pop();
+                Ok(())
+            }
+            Op::Eval => {
+                let ast = stack.pop().unwrap();
+                eval(ast, root_env(&env)).map(|v| stack.push(v))
+            }
+            Op::Interp(i) => eval(proto.consts[i as usize].clone(), env.clone()).map(|v| stack.push(v)),
+            Op::Vector(n) => {
+                let v = stack.split_off(stack.len() - n as usize);
+                stack.push(vector!(v));
+                Ok(())
+            }
+            Op::Hash(n) => {
+                let kvs = stack.split_off(stack.len() - 2 * n as usize);
+                hash_map(kvs).map(|h| stack.push(h))
+            }
+            Op::Set(n) => {
+                let xs = stack.split_off(stack.len() - n as usize);
+                stack.push(Set(xs.into_iter().collect(), Rc::new(Nil)));
+                Ok(())
+            }
+            Op::Meta(i) => {
+                let v = stack.pop().unwrap();
+                v.add_meta(&proto.consts[i as usize]).map(|v| stack.push(v))
+            }
+        };
+        if let Err(e) = res {
+            let e = e.with_trace(trace::snapshot);
+            match (&e, handlers.pop()) {
+                // continuation 的跳出不会被 catch* 拦截
+                (ErrCont(_, _), _) | (_, None) => return Err(e),
+                (_, Some(h)) => {
+                    let exc = exception::caught(&e);
+                    let frames = e.trace().into_iter().map(|f| Str(f)).collect();
+                    let mut slots = frame.slots.borrow_mut();
+                    slots[h.exc as usize] = exc;
+                    slots[h.trace as usize] = list!(frames);
+                    stack.truncate(h.sp);
+                    pc = h.pc;
+                }
+            }
+        }
+    }
+}
+
+fn at_site(e: MalErr, site: &MalVal) -> MalErr {
+    match site.location() {
+        Some(loc) => e.at(loc),
+        None => e,
+    }
+}
+
+// 依次编译并执行文件中的顶层语句 前面定义的宏会在编译后面的语句时展开
+fn run_forms(forms: &[MalVal], env: &Env) -> Result<Vec<Rc<Proto>>, MalErr> {
+    let mut protos = Vec::new();
+    for form in forms.iter() {
+        let proto = compile_top(form, env)?;
+        run_top(&proto, env)?;
+        protos.push(proto);
+    }
+    Ok(protos)
+}
+
+fn run_top(proto: &Rc<Proto>, env: &Env) -> MalRet {
+    let c = Rc::new(Closure {
+        proto: proto.clone(),
+        frame: None,
+        env: env.clone(),
+    });
+    call(&c, Vec::new())
+}
+
+// 用虚拟机执行一个模块文件 path 是源文件的路径 返回模块的哈希
+// 编译结果缓存在同名的 .jbc 文件中 缓存中记录了编译时 require 的模块和它们的哈希
+// 源文件和这些模块都没有改变时才使用缓存 因为展开的宏可能来自这些模块
+pub fn run_file(path: &str, src: &str, forms: &[MalVal], env: &Env, use_cache: bool) -> Result<u64, MalErr> {
+    let hash = source_hash(src);
+    let cache = format!("{}.jbc", path.trim_end_matches(".jmal"));
+    module::push_deps();
+    let res = run_cached_or_fresh(hash, &cache, forms, env, use_cache);
+    let deps = module::pop_deps();
+    res?;
+    Ok(module_hash(hash, &deps))
+}
+
+fn run_cached_or_fresh(hash: u64, cache: &str, forms: &[MalVal], env: &Env, use_cache: bool) -> Result<(), MalErr> {
+    let core = core_lib_hash();
+    if use_cache {
+        let cached = ROOT_INODE
+            .lookup(cache)
+            .ok()
+            .and_then(|inode| inode.read_as_vec().ok())
+            .and_then(|data| decode(hash, core, &data));
+        if let Some((deps, protos)) = cached {
+            if module::deps_unchanged(&deps, env) {
+                for p in protos.iter() {
+                    run_top(p, env)?;
+                }
+                return Ok(());
+            }
+        }
+    }
+    let protos = run_forms(forms, env)?;
+    // 缓存写入失败不影响执行
+    if let Some(data) = encode(hash, core, &module::current_deps(), &protos) {
+        if ROOT_INODE.write_file(cache, &data).is_err() {
+            debug!("failed to write {}", cache);
+        }
+    }
+    Ok(())
+}
+
+// (disassemble f) 返回编译后的函数的字节码
+pub fn disassemble(f: &MalVal) -> MalRet {
+    match f {
+        ClosureVal(c, _) => Ok(Str(c.proto.disassemble())),
+        MalFunc { .. } => error("disassemble: function is not compiled"),
+        _ => Err(expected("disassemble", "function", f)),
+    }
+}
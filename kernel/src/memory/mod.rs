@@ -11,6 +11,7 @@ pub const KERNEL_OFFSET: usize = 0xffffff00_00000000;
 pub const MEMORY_OFFSET: usize = 0;
 
 pub mod heap;
+pub mod paging;
 
 #[global_allocator]
 static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
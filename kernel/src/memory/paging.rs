@@ -0,0 +1,74 @@
+use super::{alloc_frame, dealloc_frame, PAGE_SIZE};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTable, PageTableFlags, PhysFrame, RecursivePageTable,
+    Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// bootloader 设置的递归页表项 通过它访问第 4 级页表
+const RECURSIVE_L4: usize = 0xffff_ffff_ffff_f000;
+// 带保护页的栈使用的虚拟地址区域 第 4 级页表的第 509 项
+const STACK_REGION: usize = 0xffff_fe80_0000_0000;
+
+// 下一个栈的起始地址 所有的页都映射成功后才向后移动
+static NEXT_STACK: Mutex<usize> = Mutex::new(STACK_REGION);
+
+// 映射时新建页表使用的物理页
+struct KernelFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for KernelFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        alloc_frame().map(|paddr| PhysFrame::containing_address(PhysAddr::new(paddr as u64)))
+    }
+}
+
+// 映射一个 size 字节的栈 返回栈顶 栈的下面留一页不映射作为保护页
+// 栈溢出时碰到保护页产生异常 而不是悄悄覆盖别的内存
+// 中途失败时取消已经映射的页并释放物理页 这段地址留给下一次使用
+pub fn alloc_stack(size: usize) -> Option<usize> {
+    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mut next = NEXT_STACK.lock();
+    let base = *next;
+    let l4 = unsafe { &mut *(RECURSIVE_L4 as *mut PageTable) };
+    let mut table = RecursivePageTable::new(l4).ok()?;
+    // 第 0 页是保护页 不映射
+    for i in 1..=pages {
+        if map_page(&mut table, stack_page(base, i)).is_none() {
+            for j in 1..i {
+                unmap_page(&mut table, stack_page(base, j));
+            }
+            return None;
+        }
+    }
+    *next += (pages + 1) * PAGE_SIZE;
+    Some(base + (pages + 1) * PAGE_SIZE)
+}
+
+fn stack_page(base: usize, i: usize) -> Page<Size4KiB> {
+    Page::containing_address(VirtAddr::new((base + i * PAGE_SIZE) as u64))
+}
+
+// 给一页分配物理页并映射 映射失败时释放物理页
+fn map_page(table: &mut RecursivePageTable<'_>, page: Page<Size4KiB>) -> Option<()> {
+    let paddr = alloc_frame()?;
+    let frame = PhysFrame::containing_address(PhysAddr::new(paddr as u64));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    match unsafe { table.map_to(page, frame, flags, &mut KernelFrames) } {
+        Ok(flush) => {
+            flush.flush();
+            Some(())
+        }
+        Err(_) => {
+            dealloc_frame(paddr);
+            None
+        }
+    }
+}
+
+fn unmap_page(table: &mut RecursivePageTable<'_>, page: Page<Size4KiB>) {
+    if let Ok((frame, flush)) = table.unmap(page) {
+        flush.flush();
+        dealloc_frame(frame.start_address().as_u64() as usize);
+    }
+}
@@ -1,10 +1,14 @@
+use crate::list;
 use crate::mal::core::load_core;
-use crate::mal::env::env_new;
+use crate::mal::env::{env_native, env_new};
 use crate::mal::env::Env;
 use crate::mal::rep;
 use crate::mal::types::format_error;
+use crate::mal::types::MalVal::{List, Nil, Str};
+use alloc::rc::Rc;
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::cell::RefCell;
 use core::fmt::Arguments;
 
 pub fn init_shell() {
@@ -16,13 +20,19 @@ pub fn init_shell() {
 }
 
 pub fn shell(args: Arguments) {
-    let mut history = Vec::new();
+    let history: Rc<RefCell<Vec<Vec<u8>>>> = Rc::new(RefCell::new(Vec::new()));
     let kernel_env: Env = env_new(None);
     load_core(&kernel_env);
+    // (history) 返回输入过的命令
+    let h = history.clone();
+    env_native(&kernel_env, "history", 0, Some(0), move |_| {
+        let lines: Vec<_> = h.borrow().iter().map(|l| Str(String::from_utf8_lossy(l).into_owned())).collect();
+        Ok(list!(lines))
+    });
     print!(93;"\n");
     loop {
         print!(93; "{} [IN]:",args);
-        let cmd = get_line(&mut history);
+        let cmd = get_line(&mut history.borrow_mut());
         if cmd == String::from("") {
             continue;
         }